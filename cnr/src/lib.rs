@@ -114,6 +114,8 @@ extern crate static_assertions;
 mod context;
 mod log;
 mod replica;
+#[cfg(feature = "stats")]
+pub mod stats;
 
 pub use crate::log::{Log, MAX_REPLICAS_PER_LOG};
 pub use replica::{Replica, ReplicaToken, MAX_THREADS_PER_REPLICA};
@@ -121,6 +123,14 @@ pub use replica::{Replica, ReplicaToken, MAX_THREADS_PER_REPLICA};
 use alloc::vec::Vec;
 use core::fmt::Debug;
 
+/// Paths used by the code that `node-replication-derive` generates; not part
+/// of the public API.
+#[doc(hidden)]
+pub mod __derive {
+    pub use alloc::sync::Arc;
+    pub use alloc::vec::Vec;
+}
+
 /// Every data structure must implement [LogMapper](trait.LogMapper.html) trait
 /// for [ReadOperation](trait.Dispatch.html#associatedtype.ReadOperation) and
 /// [WriteOperation](trait.Dispatch.html#associatedtype.WriteOperation).
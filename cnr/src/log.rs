@@ -2,7 +2,7 @@
 // SPDX-License-Identifier: Apache-2.0 OR MIT
 
 use alloc::alloc::{alloc, dealloc, Layout};
-use alloc::prelude::v1::Box;
+use alloc::boxed::Box;
 use alloc::sync::Arc;
 use alloc::vec::Vec;
 
@@ -19,6 +19,8 @@ use crossbeam_utils::CachePadded;
 
 use crate::context::MAX_PENDING_OPS;
 use crate::replica::MAX_THREADS_PER_REPLICA;
+#[cfg(feature = "stats")]
+use crate::stats::{LogCounters, LogStats, ReplicaLag};
 
 /// The default size of the shared log in bytes. If constructed using the
 /// default constructor, the log will be these many bytes in size. Currently
@@ -174,6 +176,15 @@ where
     /// Use this array in GC callback function to notify other replicas to make progress.
     /// Assumes that the callback handler clears the replica-ids which need to do GC.
     dormant_replicas: [AtomicBool; MAX_REPLICAS_PER_LOG],
+
+    /// Array of flags indicating which replica identifiers below `next` have been
+    /// released through `unregister()`. A released replica is ignored during garbage
+    /// collection.
+    lfree: [AtomicBool; MAX_REPLICAS_PER_LOG],
+
+    /// Counters about waiting for garbage collection on this log.
+    #[cfg(feature = "stats")]
+    stats: LogCounters,
 }
 
 impl<'a, T> fmt::Debug for Log<'a, T>
@@ -295,6 +306,9 @@ where
             scanlock: CachePadded::new(AtomicUsize::new(0)),
             notify_replicas: CachePadded::new(AtomicBool::new(true)),
             dormant_replicas: [DORMANT_DEFAULT; MAX_REPLICAS_PER_LOG],
+            lfree: [DORMANT_DEFAULT; MAX_REPLICAS_PER_LOG],
+            #[cfg(feature = "stats")]
+            stats: LogCounters::new(),
         }
     }
 
@@ -339,7 +353,24 @@ where
 
     /// Registers a replica with the log. Returns an identifier that the replica
     /// can use to execute operations on the log.
+    ///
+    /// Prefers the identifiers of replicas released through `unregister()` over
+    /// new ones. Either way, the replica starts out at the beginning of the log.
     pub(crate) fn register(&self) -> Option<usize> {
+        // Reuse the identifier of an unregistered replica first, so that replicas
+        // coming and going don't use up the identifiers.
+        let n = self.next.load(Ordering::SeqCst);
+        let free = (1..n).find(|idx| {
+            self.lfree[idx - 1]
+                .compare_exchange(true, false, Ordering::SeqCst, Ordering::SeqCst)
+                .is_ok()
+        });
+        if let Some(idx) = free {
+            self.lmasks[idx - 1].set(true);
+            self.ltails[idx - 1].store(0, Ordering::SeqCst);
+            return Some(idx);
+        }
+
         // Loop until we either run out of identifiers or we manage to increment `next`.
         loop {
             let n = self.next.load(Ordering::Relaxed);
@@ -361,6 +392,16 @@ where
         }
     }
 
+    /// Releases a replica identifier that was previously handed out by `register()`.
+    ///
+    /// Once released, the replica's local tail is no longer taken into account when
+    /// garbage collecting the log, and the identifier may be handed out again by a
+    /// later call to `register()`.
+    pub(crate) fn unregister(&self, idx: usize) {
+        self.dormant_replicas[idx - 1].store(false, Ordering::Relaxed);
+        self.lfree[idx - 1].store(true, Ordering::SeqCst);
+    }
+
     /// Adds a batch of operations to the shared log.
     ///
     /// # Note
@@ -402,8 +443,12 @@ where
                 let mut is_stuck = false;
                 let cur_local_tail = self.ltails[idx - 1].load(Ordering::Relaxed);
 
-                // Find the smallest local tail across all replicas.
+                // Find the smallest local tail across all registered replicas.
                 for idx in 1..r {
+                    if self.lfree[idx - 1].load(Ordering::Relaxed) {
+                        continue;
+                    }
+
                     let local_tail = self.ltails[idx - 1].load(Ordering::Relaxed);
                     if cur_local_tail > local_tail && cur_local_tail - local_tail > self.size / 3 {
                         self.dormant_replicas[idx - 1].store(true, Ordering::Relaxed);
@@ -428,6 +473,10 @@ where
             // is currently trying to advance the head of the log. Keep refreshing the
             // replica against the log to make sure that it isn't deadlocking GC.
             if tail > head + self.size - GC_FROM_HEAD {
+                #[cfg(feature = "stats")]
+                if waitgc == 1 {
+                    self.stats.append_gc_waits.fetch_add(1, Ordering::Relaxed);
+                }
                 if waitgc % WARN_THRESHOLD == 0 {
                     warn!(
                         "append(ops.len()={}, {}) takes too many iterations ({}) waiting for gc...",
@@ -732,20 +781,24 @@ where
             let global_head = self.head.load(Ordering::Relaxed);
             let f = self.tail.load(Ordering::Relaxed);
 
-            let mut min_local_tail = self.ltails[0].load(Ordering::Relaxed);
-
-            // Find the smallest local tail across all replicas.
-            for idx in 1..r {
-                let cur_local_tail = self.ltails[idx - 1].load(Ordering::Relaxed);
-                if min_local_tail > cur_local_tail {
-                    min_local_tail = cur_local_tail
-                };
-            }
+            // Find the smallest local tail across all registered replicas. If every
+            // replica has been unregistered, then nothing holds back the head.
+            let min_local_tail = (1..r)
+                .filter(|idx| !self.lfree[idx - 1].load(Ordering::Relaxed))
+                .map(|idx| self.ltails[idx - 1].load(Ordering::Relaxed))
+                .min()
+                .unwrap_or(f);
 
             // If we cannot advance the head further, then start
             // from the beginning of this loop again. Before doing so, try consuming
             // any new entries on the log to prevent deadlock.
-            if min_local_tail == global_head {
+            if min_local_tail <= global_head {
+                #[cfg(feature = "stats")]
+                if iteration == 1 {
+                    self.stats
+                        .advance_head_waits
+                        .fetch_add(1, Ordering::Relaxed);
+                }
                 if iteration % WARN_THRESHOLD == 0 {
                     warn!("Spending a long time in `advance_head`, are we starving?");
                 }
@@ -792,6 +845,7 @@ where
         for r in 0..MAX_REPLICAS_PER_LOG {
             self.ltails[r].store(0, Ordering::Relaxed);
             self.lmasks[r].set(true);
+            self.lfree[r].store(false, Ordering::Relaxed);
         }
 
         // Next, free up all log entries. Use pointers to avoid memcpy and speed up
@@ -815,6 +869,35 @@ where
     pub(crate) fn get_ctail(&self) -> usize {
         self.ctail.load(Ordering::Relaxed)
     }
+
+    /// Returns a snapshot of the state of the log and of the replicas registered
+    /// with it, along with the number of times appending to the log had to wait
+    /// for garbage collection.
+    #[cfg(feature = "stats")]
+    pub fn stats(&self) -> LogStats {
+        let tail = self.tail.load(Ordering::Relaxed);
+        let replicas: Vec<ReplicaLag> = (1..self.next.load(Ordering::Relaxed))
+            .filter(|idx| !self.lfree[idx - 1].load(Ordering::Relaxed))
+            .map(|idx| {
+                let ltail = self.ltails[idx - 1].load(Ordering::Relaxed);
+                ReplicaLag {
+                    id: idx,
+                    ltail,
+                    lag: tail.saturating_sub(ltail),
+                }
+            })
+            .collect();
+
+        LogStats {
+            idx: self.idx,
+            head: self.head.load(Ordering::Relaxed),
+            tail,
+            ctail: self.ctail.load(Ordering::Relaxed),
+            replicas,
+            append_gc_waits: self.stats.append_gc_waits.load(Ordering::Relaxed),
+            advance_head_waits: self.stats.advance_head_waits.load(Ordering::Relaxed),
+        }
+    }
 }
 
 impl<'a, T> Default for Log<'a, T>
@@ -961,6 +1044,25 @@ mod tests {
         assert_eq!(l.next.load(Ordering::Relaxed), 2);
     }
 
+    // Tests that the identifier of an unregistered replica is handed out again,
+    // starting out at the beginning of the log.
+    #[test]
+    fn test_log_register_reuse() {
+        let l = Log::<Operation>::new(1024, 1);
+        assert_eq!(l.register(), Some(1));
+        assert_eq!(l.register(), Some(2));
+
+        l.ltails[1].store(7, Ordering::Relaxed);
+        l.lmasks[1].set(false);
+        l.unregister(2);
+        assert_eq!(l.register(), Some(2));
+        assert!(!l.lfree[1].load(Ordering::Relaxed));
+        assert_eq!(l.ltails[1].load(Ordering::Relaxed), 0);
+        assert!(l.lmasks[1].get());
+        assert_eq!(l.register(), Some(3));
+        assert_eq!(l.next.load(Ordering::Relaxed), 4);
+    }
+
     // Tests that we cannot register more than the max replicas with the log.
     #[test]
     fn test_log_register_none() {
@@ -1019,6 +1121,23 @@ mod tests {
         assert_eq!(l.head.load(Ordering::Relaxed), 224);
     }
 
+    // Tests that unregistered replicas do not hold back the head of the log.
+    #[test]
+    fn test_log_advance_head_unregistered() {
+        let l = Log::<Operation>::default();
+
+        l.next.store(4, Ordering::Relaxed);
+        l.ltails[0].store(1023, Ordering::Relaxed);
+        l.ltails[1].store(224, Ordering::Relaxed);
+        l.ltails[2].store(4096, Ordering::Relaxed);
+        l.unregister(2);
+
+        l.advance_head(0, &mut |_o: Operation, _i: usize, _, _, _, _| -> bool {
+            true
+        });
+        assert_eq!(l.head.load(Ordering::Relaxed), 1023);
+    }
+
     // Tests that the head of the log is advanced when we're close to filling up the entire log.
     #[test]
     fn test_log_append_gc() {
@@ -1043,6 +1162,53 @@ mod tests {
         assert_eq!(l.tail.load(Ordering::Relaxed), l.size - GC_FROM_HEAD + 3);
     }
 
+    // Tests that the stats of the log report the lag of every registered replica
+    // and count the times the head could not be advanced right away.
+    #[test]
+    #[cfg(feature = "stats")]
+    fn test_log_stats() {
+        let l = Log::<Operation>::new(1024 * 1024, 3);
+        let o = [
+            (Operation::Read, 1, false),
+            (Operation::Read, 1, false),
+            (Operation::Read, 1, false),
+            (Operation::Read, 1, false),
+        ];
+        let one = l.register().unwrap();
+        let two = l.register().unwrap();
+        l.append(&o, one, |_o: Operation, _i: usize, _, _, _, _| -> bool {
+            true
+        });
+
+        let stats = l.stats();
+        assert_eq!(stats.idx, 3);
+        assert_eq!(stats.head, 0);
+        assert_eq!(stats.tail, 4);
+        assert_eq!(stats.append_gc_waits, 0);
+        assert_eq!(stats.advance_head_waits, 0);
+        assert_eq!(stats.replicas.len(), 2);
+        assert_eq!(stats.replicas[1].id, two);
+        assert_eq!(stats.replicas[1].lag, 4);
+
+        // The head is held back until `one` consumes its own entries.
+        l.unregister(two);
+        l.advance_head(one, &mut |_o: Operation, _i: usize, _, _, _, _| -> bool {
+            true
+        });
+
+        let stats = l.stats();
+        assert_eq!(stats.head, 4);
+        assert_eq!(stats.advance_head_waits, 1);
+        assert_eq!(
+            stats.replicas,
+            [ReplicaLag {
+                id: one,
+                ltail: 4,
+                lag: 0
+            }]
+        );
+    }
+
     // Tests that on log wrap around, the local mask stays
     // the same because entries have not been executed yet.
     #[test]
@@ -14,6 +14,8 @@ use crossbeam_utils::CachePadded;
 
 use super::context::Context;
 use super::log::Log;
+#[cfg(feature = "stats")]
+use super::stats::{ReplicaCounters, ReplicaStats};
 use super::Dispatch;
 use super::LogMapper;
 
@@ -100,6 +102,10 @@ where
     /// A buffer of scan type operations for flat combining. Each entry in buffer
     /// contains the write operation, hash, and is_read(we store scan read ops).
     scan_buffer: CachePadded<RefCell<Vec<OperationState<D>>>>,
+
+    /// Counters about flat combining and reads on this log.
+    #[cfg(feature = "stats")]
+    stats: ReplicaCounters,
 }
 
 impl<'a, D> LogState<'a, D>
@@ -131,10 +137,23 @@ where
             scan_buffer: CachePadded::new(RefCell::new(Vec::with_capacity(
                 MAX_THREADS_PER_REPLICA,
             ))),
+            #[cfg(feature = "stats")]
+            stats: ReplicaCounters::new(),
         }
     }
 }
 
+impl<'a, D> Drop for LogState<'a, D>
+where
+    D: Sized + Dispatch + Sync,
+{
+    /// Releases the replica's identifier on this log, so that the log no longer
+    /// waits for the replica when garbage collecting entries.
+    fn drop(&mut self) {
+        self.slog.unregister(self.idx);
+    }
+}
+
 /// An instance of a replicated data structure. Uses one or more shared logs
 /// to scale operations on the data structure across cores and processors.
 ///
@@ -986,6 +1005,13 @@ where
         }
     }
 
+    /// Returns the counters collected by the replica, one entry for each of
+    /// the logs the replica was created with (in the same order).
+    #[cfg(feature = "stats")]
+    pub fn stats(&self) -> Vec<ReplicaStats> {
+        self.logstate.iter().map(|l| l.stats.snapshot()).collect()
+    }
+
     /// Issues a read-only operation against the replica and returns a response.
     /// Makes sure the replica is synced up against the log before doing so.
     fn read_only(
@@ -1003,6 +1029,16 @@ where
         // We can perform the read only if our replica is synced up against
         // the shared log. If it isn't, then try to combine until it is synced up.
         let ctail = self.logstate[hash_idx].slog.get_ctail();
+        #[cfg(feature = "stats")]
+        if !self.logstate[hash_idx]
+            .slog
+            .is_replica_synced_for_reads(self.logstate[hash_idx].idx, ctail)
+        {
+            self.logstate[hash_idx]
+                .stats
+                .read_sync_waits
+                .fetch_add(1, Ordering::Relaxed);
+        }
         while !self.logstate[hash_idx]
             .slog
             .is_replica_synced_for_reads(self.logstate[hash_idx].idx, ctail)
@@ -1099,6 +1135,11 @@ where
             }
         }
 
+        #[cfg(feature = "stats")]
+        self.logstate[hashidx]
+            .stats
+            .combined(buffer.len() + scan_buffer.len());
+
         // Append all collected operations into the shared log. We pass a closure
         // in here because operations on the log might need to be consumed for GC.
         {
@@ -1248,6 +1289,7 @@ mod test {
     extern crate std;
 
     use super::*;
+    use crate::log::MAX_REPLICAS_PER_LOG;
     use std::vec;
     use std::{thread, time};
 
@@ -1328,6 +1370,41 @@ mod test {
         assert!(repl.register().is_none());
     }
 
+    // Tests that dropping a replica releases its identifier on every log.
+    #[test]
+    fn test_replica_drop_unregisters() {
+        let slog1 = Arc::new(Log::<<Data as Dispatch>::WriteOperation>::new(1024, 1));
+        let slog2 = Arc::new(Log::<<Data as Dispatch>::WriteOperation>::new(1024, 2));
+        let one = Replica::<Data>::new(vec![slog1.clone(), slog2.clone()]);
+        let two = Replica::<Data>::new(vec![slog1.clone(), slog2.clone()]);
+        assert_eq!(two.logstate[0].idx, 2);
+        assert_eq!(two.logstate[1].idx, 2);
+
+        // `two` would hold back garbage collection on both logs.
+        drop(two);
+        let idx = one.register().unwrap();
+        for i in 0..50_000 {
+            assert_eq!(Ok(107), one.execute_mut(OpWr(i), idx));
+        }
+
+        let three = Replica::<Data>::new(vec![slog1, slog2]);
+        assert_eq!(three.logstate[0].idx, 2);
+        assert_eq!(three.logstate[1].idx, 2);
+    }
+
+    // Tests that replicas can be created and dropped more often than there are
+    // identifiers on a log.
+    #[test]
+    fn test_replica_create_drop_churn() {
+        let slog = Arc::new(Log::<<Data as Dispatch>::WriteOperation>::new(1024, 1));
+        let _one = Replica::<Data>::new(vec![slog.clone()]);
+
+        for _i in 0..2 * MAX_REPLICAS_PER_LOG {
+            let repl = Replica::<Data>::new(vec![slog.clone()]);
+            assert_eq!(repl.logstate[0].idx, 2);
+        }
+    }
+
     // Tests that we can successfully allow operations to go pending on this replica.
     #[test]
     fn test_replica_make_pending() {
@@ -1445,6 +1522,36 @@ mod test {
         assert_eq!(Ok(2), repl.execute(OpRd(11), t1));
     }
 
+    // Tests that the stats of a replica count rounds of flat combining, the
+    // operations appended in them, and reads that had to wait on the log.
+    #[test]
+    #[cfg(feature = "stats")]
+    fn test_replica_stats() {
+        let slog = Arc::new(Log::<<Data as Dispatch>::WriteOperation>::default());
+        let repl = Replica::<Data>::new(vec![slog.clone()]);
+        let idx = repl.register().expect("Failed to register with replica.");
+        let _ignore = slog.register().expect("Failed to register with log.");
+
+        for i in 0..3 {
+            assert_eq!(Ok(107), repl.execute_mut(OpWr(i), idx));
+        }
+        assert_eq!(Ok(3), repl.execute(OpRd(11), idx));
+
+        let stats = repl.stats();
+        assert_eq!(stats.len(), 1);
+        assert_eq!(stats[0].combiner_rounds, 3);
+        assert_eq!(stats[0].combined_ops, 3);
+        assert_eq!(stats[0].max_batch, 1);
+        assert_eq!(stats[0].read_sync_waits, 0);
+
+        // Add in operations to the log off the side, not through the replica.
+        let o = [(OpWr(121), 1, false), (OpWr(212), 1, false)];
+        slog.append(&o, 2, |_o: OpWr, _i: usize, _, _, _, _| true);
+        slog.exec(2, &mut |_o: OpWr, _i: usize, _, _, _, _| true);
+        assert_eq!(Ok(5), repl.execute(OpRd(11), idx));
+        assert_eq!(repl.stats()[0].read_sync_waits, 1);
+    }
+
     // Tests if there are log number of combiners and all of
     // them can acquire the combiner lock in parallel.
     #[test]
@@ -0,0 +1,125 @@
+// Copyright © 2019-2020 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Statistics about the shared logs and replicas.
+//!
+//! Enabled by the `stats` feature. `Log::stats()` returns a snapshot of a single
+//! log and `Replica::stats()` one set of counters for each log the replica
+//! uses, since every log has its own combiner on the replica.
+
+use alloc::vec::Vec;
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// A snapshot of the state of a shared log.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct LogStats {
+    /// The identifier of the log.
+    pub idx: usize,
+
+    /// Logical index of the oldest entry on the log that hasn't been garbage collected.
+    pub head: usize,
+
+    /// Logical index of the next entry that will be appended to the log.
+    pub tail: usize,
+
+    /// Logical index up to which operations were executed by at least one replica.
+    pub ctail: usize,
+
+    /// Local tail and lag of every replica registered with the log.
+    pub replicas: Vec<ReplicaLag>,
+
+    /// Number of batches that had to wait for the log to be garbage collected
+    /// before they could be appended.
+    pub append_gc_waits: usize,
+
+    /// Number of times the head of the log could not be advanced right away
+    /// because a replica hadn't executed the oldest entries on the log yet.
+    pub advance_head_waits: usize,
+}
+
+/// Progress of a single replica on a shared log.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ReplicaLag {
+    /// The identifier of the replica on the log.
+    pub id: usize,
+
+    /// Logical index up to which the replica has executed operations.
+    pub ltail: usize,
+
+    /// Number of entries on the log that the replica hasn't executed yet.
+    pub lag: usize,
+}
+
+/// Counters collected by a replica for one of its logs.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ReplicaStats {
+    /// Number of rounds of flat combining performed for the log.
+    pub combiner_rounds: usize,
+
+    /// Number of operations (including scans) appended to the log across all
+    /// rounds; divided by `combiner_rounds`, this is the average batch size.
+    pub combined_ops: usize,
+
+    /// Size of the largest batch appended to the log in a single round.
+    pub max_batch: usize,
+
+    /// Number of read-only operations that had to wait for the replica to catch
+    /// up with the log before they could be executed.
+    pub read_sync_waits: usize,
+}
+
+/// Counters updated by a shared log.
+pub(crate) struct LogCounters {
+    pub(crate) append_gc_waits: AtomicUsize,
+    pub(crate) advance_head_waits: AtomicUsize,
+}
+
+impl LogCounters {
+    pub(crate) fn new() -> LogCounters {
+        LogCounters {
+            append_gc_waits: AtomicUsize::new(0),
+            advance_head_waits: AtomicUsize::new(0),
+        }
+    }
+}
+
+/// Counters updated by a replica for one of its logs.
+pub(crate) struct ReplicaCounters {
+    combiner_rounds: AtomicUsize,
+    combined_ops: AtomicUsize,
+    max_batch: AtomicUsize,
+    pub(crate) read_sync_waits: AtomicUsize,
+}
+
+impl ReplicaCounters {
+    pub(crate) fn new() -> ReplicaCounters {
+        ReplicaCounters {
+            combiner_rounds: AtomicUsize::new(0),
+            combined_ops: AtomicUsize::new(0),
+            max_batch: AtomicUsize::new(0),
+            read_sync_waits: AtomicUsize::new(0),
+        }
+    }
+
+    /// Records a round of flat combining that appended `batch` operations.
+    /// Only ever called while holding the combiner lock of the log.
+    pub(crate) fn combined(&self, batch: usize) {
+        let rounds = self.combiner_rounds.load(Ordering::Relaxed);
+        self.combiner_rounds.store(rounds + 1, Ordering::Relaxed);
+        let ops = self.combined_ops.load(Ordering::Relaxed);
+        self.combined_ops.store(ops + batch, Ordering::Relaxed);
+        if batch > self.max_batch.load(Ordering::Relaxed) {
+            self.max_batch.store(batch, Ordering::Relaxed);
+        }
+    }
+
+    pub(crate) fn snapshot(&self) -> ReplicaStats {
+        ReplicaStats {
+            combiner_rounds: self.combiner_rounds.load(Ordering::Relaxed),
+            combined_ops: self.combined_ops.load(Ordering::Relaxed),
+            max_batch: self.max_batch.load(Ordering::Relaxed),
+            read_sync_waits: self.read_sync_waits.load(Ordering::Relaxed),
+        }
+    }
+}
@@ -0,0 +1,588 @@
+// Copyright © 2019-2020 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Derives the [Dispatch](../node_replication/trait.Dispatch.html) boilerplate
+//! of a replicated data structure from the methods of its inherent impl block.
+//!
+//! Annotating the impl block with `#[replicated]` and its operations with
+//! `#[operation]` generates, for a data structure `Stack`:
+//!
+//! - `StackReadOperation` and `StackWriteOperation`: one variant per `&self`
+//!   respectively `&mut self` method, holding the method's arguments.
+//! - `StackResponse`: one variant per method, holding its return value.
+//! - The `Dispatch` implementation that calls the methods.
+//! - `StackReplica`: a wrapper around an `Arc<Replica<Stack>>` with one method
+//!   per operation, which takes the arguments and a `ReplicaToken` and returns
+//!   what the method returned.
+//!
+//! ```
+//! use node_replication::{Log, Replica};
+//! use node_replication_derive::replicated;
+//! use std::sync::Arc;
+//!
+//! #[derive(Default)]
+//! pub struct Stack {
+//!     storage: Vec<u32>,
+//! }
+//!
+//! #[replicated]
+//! impl Stack {
+//!     #[operation]
+//!     pub fn push(&mut self, value: u32) {
+//!         self.storage.push(value);
+//!     }
+//!
+//!     #[operation]
+//!     pub fn pop(&mut self) -> Option<u32> {
+//!         self.storage.pop()
+//!     }
+//!
+//!     #[operation]
+//!     pub fn peek(&self) -> Option<u32> {
+//!         self.storage.last().copied()
+//!     }
+//! }
+//!
+//! let log = Arc::new(Log::<StackWriteOperation>::default());
+//! let stack = StackReplica::new(Replica::new(&log));
+//! let idx = stack.register().unwrap();
+//!
+//! stack.push(1, idx);
+//! stack.push(2, idx);
+//! assert_eq!(stack.pop(idx), Some(2));
+//! assert_eq!(stack.peek(idx), Some(1));
+//! ```
+//!
+//! `#[replicated(cnr)]` generates the same items for the `cnr` crate, along
+//! with the `LogMapper` implementations of both operation enums; see
+//! [replicated](attr.replicated.html).
+
+extern crate proc_macro;
+
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::{format_ident, quote};
+use syn::{
+    parse_macro_input, parse_quote, Attribute, Error, FnArg, Ident, ImplItem, ImplItemFn, ItemImpl,
+    Meta, Pat, Result, ReturnType, Type, Visibility,
+};
+
+/// Which crate the generated code targets.
+#[derive(Clone, Copy, PartialEq)]
+enum Flavor {
+    Nr,
+    Cnr,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Kind {
+    Read,
+    Write,
+}
+
+/// A method marked with `#[operation]`.
+struct Operation {
+    kind: Kind,
+    method: Ident,
+    variant: Ident,
+    vis: Visibility,
+    docs: Vec<Attribute>,
+    args: Vec<(Ident, Type)>,
+    /// Index into `args` of the argument marked with `#[log_key]` (cnr only).
+    key: Option<usize>,
+    output: Type,
+}
+
+/// Generates the operations, the `Dispatch` implementation and a typed
+/// replica wrapper for the data structure of the annotated impl block.
+///
+/// Methods marked with `#[operation]` become operations; other methods are
+/// left alone. A method's receiver determines its kind: `&self` methods are
+/// reads and `&mut self` methods are writes. Arguments must be owned (they are
+/// stored on the log) and bound to plain identifiers. The data structure must
+/// not be generic.
+///
+/// # cnr
+///
+/// With `#[replicated(cnr)]`, the data structure is concurrent and every
+/// operation takes `&self`, so writes must be marked `#[operation(write)]`.
+/// Additionally, each operation can mark one integer argument with
+/// `#[log_key]`; the operation is mapped to log `key % nlogs`. Writes without
+/// a key go to all logs (and are executed with `execute_mut_scan`), reads
+/// must have one.
+///
+/// ```ignore
+/// #[replicated(cnr)]
+/// impl Map {
+///     #[operation(write)]
+///     pub fn put(&self, #[log_key] key: u64, value: u64) -> Option<u64> { .. }
+///
+///     #[operation]
+///     pub fn get(&self, #[log_key] key: u64) -> Option<u64> { .. }
+/// }
+/// ```
+#[proc_macro_attribute]
+pub fn replicated(args: TokenStream, input: TokenStream) -> TokenStream {
+    let args = TokenStream2::from(args);
+    let mut block = parse_macro_input!(input as ItemImpl);
+
+    match flavor(args).and_then(|flavor| expand(flavor, &mut block)) {
+        Ok(tokens) => tokens.into(),
+        Err(e) => e.to_compile_error().into(),
+    }
+}
+
+fn flavor(args: TokenStream2) -> Result<Flavor> {
+    if args.is_empty() {
+        return Ok(Flavor::Nr);
+    }
+
+    let ident: Ident = syn::parse2(args)?;
+    if ident == "cnr" {
+        Ok(Flavor::Cnr)
+    } else {
+        Err(Error::new_spanned(ident, "expected `cnr` or no argument"))
+    }
+}
+
+fn expand(flavor: Flavor, block: &mut ItemImpl) -> Result<TokenStream2> {
+    if let Some((_, path, _)) = &block.trait_ {
+        return Err(Error::new_spanned(
+            path,
+            "#[replicated] expects an inherent impl block",
+        ));
+    }
+    if !block.generics.params.is_empty() {
+        return Err(Error::new_spanned(
+            &block.generics,
+            "#[replicated] does not support generic data structures",
+        ));
+    }
+    let name = match &*block.self_ty {
+        Type::Path(ty) if ty.qself.is_none() => ty.path.segments.last().unwrap().ident.clone(),
+        ty => return Err(Error::new_spanned(ty, "expected the name of a type")),
+    };
+
+    let mut ops = Vec::new();
+    for item in block.items.iter_mut() {
+        if let ImplItem::Fn(f) = item {
+            if let Some(op) = operation(flavor, f)? {
+                ops.push(op);
+            }
+        }
+    }
+    if ops.is_empty() {
+        return Err(Error::new_spanned(
+            &block.self_ty,
+            "no method is marked with #[operation]",
+        ));
+    }
+
+    let ty = &block.self_ty;
+    let krate = match flavor {
+        Flavor::Nr => quote!(::node_replication),
+        Flavor::Cnr => quote!(::cnr),
+    };
+    let read_enum = format_ident!("{}ReadOperation", name);
+    let write_enum = format_ident!("{}WriteOperation", name);
+    let response = format_ident!("{}Response", name);
+    let wrapper = format_ident!("{}Replica", name);
+    let enum_of = |op: &Operation| match op.kind {
+        Kind::Read => &read_enum,
+        Kind::Write => &write_enum,
+    };
+
+    let variants = |kind: Kind| {
+        let variants = ops.iter().filter(|op| op.kind == kind).map(|op| {
+            let variant = &op.variant;
+            let doc = format!("Invokes `{}::{}`.", name, op.method);
+            let tys = op.args.iter().map(|(_, ty)| ty);
+            if op.args.is_empty() {
+                quote!(#[doc = #doc] #variant)
+            } else {
+                quote!(#[doc = #doc] #variant(#(#tys),*))
+            }
+        });
+        quote!(#(#variants,)*)
+    };
+    let read_variants = variants(Kind::Read);
+    let write_variants = variants(Kind::Write);
+
+    let response_variants = ops.iter().map(|op| {
+        let variant = &op.variant;
+        let output = &op.output;
+        let doc = format!("Returned by `{}::{}`.", name, op.method);
+        quote!(#[doc = #doc] #variant(#output))
+    });
+
+    let arms = |kind: Kind| {
+        let arms = ops.iter().filter(|op| op.kind == kind).map(|op| {
+            let pattern = pattern(enum_of(op), op);
+            let variant = &op.variant;
+            let method = &op.method;
+            let args = op.args.iter().map(|(arg, _)| arg);
+            quote!(#pattern => #response::#variant(self.#method(#(#args),*)))
+        });
+        quote!(#(#arms,)*)
+    };
+    let read_arms = arms(Kind::Read);
+    let write_arms = arms(Kind::Write);
+    let dispatch_mut_receiver = match flavor {
+        Flavor::Nr => quote!(&mut self),
+        Flavor::Cnr => quote!(&self),
+    };
+
+    let log_mappers = match flavor {
+        Flavor::Nr => quote!(),
+        Flavor::Cnr => {
+            let mapper = |kind: Kind, enum_name: &Ident| {
+                let mut arms = ops.iter().filter(|op| op.kind == kind).peekable();
+                // A reference to an empty enum is not considered uninhabited.
+                let scrutinee = match arms.peek() {
+                    Some(_) => quote!(self),
+                    None => quote!(*self),
+                };
+                let arms = arms.map(|op| {
+                    let pattern = pattern(enum_name, op);
+                    match op.key {
+                        Some(key) => {
+                            let key = &op.args[key].0;
+                            quote!(#pattern => logs.push((*#key as usize) % nlogs))
+                        }
+                        None => quote!(#pattern => logs.extend(0..nlogs)),
+                    }
+                });
+                quote! {
+                    impl ::cnr::LogMapper for #enum_name {
+                        #[allow(unused_variables)]
+                        fn hash(&self, nlogs: usize, logs: &mut ::cnr::__derive::Vec<usize>) {
+                            match #scrutinee {
+                                #(#arms,)*
+                            }
+                        }
+                    }
+                }
+            };
+            let read = mapper(Kind::Read, &read_enum);
+            let write = mapper(Kind::Write, &write_enum);
+            quote!(#read #write)
+        }
+    };
+
+    let methods = ops.iter().map(|op| {
+        let Operation {
+            method,
+            variant,
+            vis,
+            docs,
+            args,
+            output,
+            ..
+        } = op;
+        let pattern = pattern(enum_of(op), op);
+        let execute = match (op.kind, op.key) {
+            (Kind::Read, _) => quote!(execute),
+            (Kind::Write, None) if flavor == Flavor::Cnr => quote!(execute_mut_scan),
+            (Kind::Write, _) => quote!(execute_mut),
+        };
+        let params = args.iter().map(|(arg, ty)| quote!(#arg: #ty));
+        quote! {
+            #(#docs)*
+            #vis fn #method(&self, #(#params,)* token: #krate::ReplicaToken) -> #output {
+                match self.replica.#execute(#pattern, token) {
+                    #response::#variant(response) => response,
+                    #[allow(unreachable_patterns)]
+                    _ => ::core::unreachable!("replica returned the response of another operation"),
+                }
+            }
+        }
+    });
+
+    let read_doc = format!(
+        "Read operations of `{}`, generated by `#[replicated]`.",
+        name
+    );
+    let write_doc = format!(
+        "Write operations of `{}`, generated by `#[replicated]`.",
+        name
+    );
+    let response_doc = format!("The value returned by an operation of `{}`.", name);
+    let wrapper_doc = format!(
+        "A replica of `{}` with a method for each of its operations.",
+        name
+    );
+
+    Ok(quote! {
+        #block
+
+        #[doc = #read_doc]
+        #[derive(Debug, Clone, PartialEq)]
+        pub enum #read_enum {
+            #read_variants
+        }
+
+        #[doc = #write_doc]
+        #[derive(Debug, Clone, PartialEq)]
+        pub enum #write_enum {
+            #write_variants
+        }
+
+        #[doc = #response_doc]
+        #[derive(Clone)]
+        pub enum #response {
+            #(#response_variants,)*
+        }
+
+        impl #krate::Dispatch for #ty {
+            type ReadOperation = #read_enum;
+            type WriteOperation = #write_enum;
+            type Response = #response;
+
+            fn dispatch(&self, op: Self::ReadOperation) -> Self::Response {
+                match op {
+                    #read_arms
+                }
+            }
+
+            fn dispatch_mut(#dispatch_mut_receiver, op: Self::WriteOperation) -> Self::Response {
+                match op {
+                    #write_arms
+                }
+            }
+        }
+
+        #log_mappers
+
+        #[doc = #wrapper_doc]
+        #[derive(Clone)]
+        pub struct #wrapper<'a> {
+            replica: #krate::__derive::Arc<#krate::Replica<'a, #ty>>,
+        }
+
+        impl<'a> #wrapper<'a> {
+            /// Wraps `replica`.
+            pub fn new(replica: #krate::__derive::Arc<#krate::Replica<'a, #ty>>) -> Self {
+                Self { replica }
+            }
+
+            /// The wrapped replica.
+            pub fn replica(&self) -> &#krate::__derive::Arc<#krate::Replica<'a, #ty>> {
+                &self.replica
+            }
+
+            /// Registers a thread with the wrapped replica, see `Replica::register`.
+            pub fn register(&self) -> Option<#krate::ReplicaToken> {
+                self.replica.register()
+            }
+
+            #(#methods)*
+        }
+    })
+}
+
+/// Parses `f` if it is marked with `#[operation]`, and strips the attributes
+/// this crate understands from it.
+fn operation(flavor: Flavor, f: &mut ImplItemFn) -> Result<Option<Operation>> {
+    let mut markers = Vec::new();
+    f.attrs.retain(|attr| {
+        let is_marker = attr.path().is_ident("operation");
+        if is_marker {
+            markers.push(attr.clone());
+        }
+        !is_marker
+    });
+    let marker = match markers.len() {
+        0 => return Ok(None),
+        1 => markers.pop().unwrap(),
+        _ => {
+            return Err(Error::new_spanned(
+                &markers[1],
+                "duplicate #[operation] attribute",
+            ))
+        }
+    };
+
+    let explicit = match &marker.meta {
+        Meta::Path(_) => None,
+        Meta::List(_) => {
+            let kind: Ident = marker.parse_args()?;
+            if kind == "read" {
+                Some(Kind::Read)
+            } else if kind == "write" {
+                Some(Kind::Write)
+            } else {
+                return Err(Error::new_spanned(kind, "expected `read` or `write`"));
+            }
+        }
+        Meta::NameValue(_) => {
+            return Err(Error::new_spanned(
+                marker,
+                "expected #[operation], #[operation(read)] or #[operation(write)]",
+            ))
+        }
+    };
+
+    let sig = &mut f.sig;
+    if !sig.generics.params.is_empty() {
+        return Err(Error::new_spanned(
+            &sig.generics,
+            "operations can not be generic",
+        ));
+    }
+    if let Some(asyncness) = &sig.asyncness {
+        return Err(Error::new_spanned(asyncness, "operations can not be async"));
+    }
+
+    let receiver = match sig.receiver() {
+        Some(receiver) if receiver.reference.is_some() && receiver.colon_token.is_none() => {
+            receiver
+        }
+        _ => {
+            return Err(Error::new_spanned(
+                &sig.ident,
+                "operations take `&self` or `&mut self`",
+            ))
+        }
+    };
+    let kind = match (flavor, receiver.mutability.is_some(), explicit) {
+        (Flavor::Nr, false, None | Some(Kind::Read)) => Kind::Read,
+        (Flavor::Nr, true, None | Some(Kind::Write)) => Kind::Write,
+        (Flavor::Nr, false, Some(Kind::Write)) => {
+            return Err(Error::new_spanned(
+                receiver,
+                "write operations take `&mut self`",
+            ))
+        }
+        (Flavor::Nr, true, Some(Kind::Read)) => {
+            return Err(Error::new_spanned(receiver, "read operations take `&self`"))
+        }
+        (Flavor::Cnr, false, kind) => kind.unwrap_or(Kind::Read),
+        (Flavor::Cnr, true, _) => {
+            return Err(Error::new_spanned(
+                receiver,
+                "cnr operations take `&self`, mark writes with #[operation(write)]",
+            ))
+        }
+    };
+
+    let mut args = Vec::new();
+    let mut key = None;
+    for input in sig.inputs.iter_mut() {
+        let arg = match input {
+            FnArg::Receiver(_) => continue,
+            FnArg::Typed(arg) => arg,
+        };
+
+        let mut is_key = false;
+        arg.attrs.retain(|attr| {
+            let is_log_key = attr.path().is_ident("log_key");
+            is_key |= is_log_key;
+            !is_log_key
+        });
+        if is_key {
+            if flavor == Flavor::Nr {
+                return Err(Error::new_spanned(
+                    &arg.pat,
+                    "#[log_key] is only used by #[replicated(cnr)]",
+                ));
+            }
+            if key.is_some() {
+                return Err(Error::new_spanned(
+                    &arg.pat,
+                    "an operation has at most one #[log_key]",
+                ));
+            }
+            key = Some(args.len());
+        }
+
+        let ident = match &*arg.pat {
+            Pat::Ident(pat) if pat.by_ref.is_none() && pat.subpat.is_none() => pat.ident.clone(),
+            pat => {
+                return Err(Error::new_spanned(
+                    pat,
+                    "operation arguments must be plain identifiers",
+                ))
+            }
+        };
+        if ident == "token" {
+            return Err(Error::new_spanned(
+                ident,
+                "`token` is reserved for the replica token of the generated method",
+            ));
+        }
+        if let Type::Reference(ty) = &*arg.ty {
+            return Err(Error::new_spanned(
+                ty,
+                "operation arguments are stored on the log and must be owned",
+            ));
+        }
+        args.push((ident, (*arg.ty).clone()));
+    }
+
+    if flavor == Flavor::Cnr && kind == Kind::Read && key.is_none() {
+        return Err(Error::new_spanned(
+            &sig.ident,
+            "cnr read operations need an argument marked with #[log_key]",
+        ));
+    }
+
+    let output = match &sig.output {
+        ReturnType::Default => parse_quote!(()),
+        ReturnType::Type(_, ty) => (**ty).clone(),
+    };
+
+    Ok(Some(Operation {
+        kind,
+        variant: variant(&sig.ident),
+        method: sig.ident.clone(),
+        vis: f.vis.clone(),
+        docs: f
+            .attrs
+            .iter()
+            .filter(|attr| attr.path().is_ident("doc"))
+            .cloned()
+            .collect(),
+        args,
+        key,
+        output,
+    }))
+}
+
+/// The enum variant of a method: `get_value` becomes `GetValue`.
+fn variant(method: &Ident) -> Ident {
+    let name = method.to_string();
+    let name: String = name
+        .trim_start_matches("r#")
+        .split('_')
+        .filter(|part| !part.is_empty())
+        .map(|part| {
+            let mut chars = part.chars();
+            let first = chars.next().unwrap().to_ascii_uppercase();
+            core::iter::once(first).chain(chars).collect::<String>()
+        })
+        .collect();
+    Ident::new(&name, method.span())
+}
+
+/// The pattern (and constructor) of `op` in the enum `enum_name`.
+fn pattern(enum_name: &Ident, op: &Operation) -> TokenStream2 {
+    let variant = &op.variant;
+    let args = op.args.iter().map(|(arg, _)| arg);
+    if op.args.is_empty() {
+        quote!(#enum_name::#variant)
+    } else {
+        quote!(#enum_name::#variant(#(#args),*))
+    }
+}
+
+#[cfg(doctest)]
+mod test_readme {
+    macro_rules! external_doc_test {
+        ($x:expr) => {
+            #[doc = $x]
+            extern "C" {}
+        };
+    }
+
+    external_doc_test!(include_str!("../README.md"));
+}
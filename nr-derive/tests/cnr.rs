@@ -0,0 +1,76 @@
+// Copyright © 2019-2020 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Tests the code generated by `#[replicated(cnr)]`.
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use cnr::{Log, LogMapper, Replica};
+use node_replication_derive::replicated;
+
+const SLOTS: usize = 16;
+
+/// A fixed-size array of counters, partitioned by slot.
+#[derive(Default)]
+pub struct Counters {
+    slots: [AtomicU64; SLOTS],
+}
+
+#[replicated(cnr)]
+impl Counters {
+    #[operation(write)]
+    pub fn add(&self, #[log_key] slot: usize, value: u64) -> u64 {
+        self.slots[slot].fetch_add(value, Ordering::Relaxed) + value
+    }
+
+    /// Resets all slots, hence goes to all logs.
+    #[operation(write)]
+    pub fn reset(&self) {
+        for slot in self.slots.iter() {
+            slot.store(0, Ordering::Relaxed);
+        }
+    }
+
+    #[operation]
+    pub fn get(&self, #[log_key] slot: usize) -> u64 {
+        self.slots[slot].load(Ordering::Relaxed)
+    }
+}
+
+// Tests that operations with a `#[log_key]` map to one log, and the others to all.
+#[test]
+fn test_log_mapper_uses_key() {
+    let mut logs = Vec::new();
+    CountersWriteOperation::Add(5, 1).hash(4, &mut logs);
+    assert_eq!(logs, vec![1]);
+
+    logs.clear();
+    CountersReadOperation::Get(3).hash(4, &mut logs);
+    assert_eq!(logs, vec![3]);
+
+    logs.clear();
+    CountersWriteOperation::Reset.hash(4, &mut logs);
+    assert_eq!(logs, vec![0, 1, 2, 3]);
+}
+
+// Tests that the wrapper executes operations against a replica with several logs.
+#[test]
+fn test_wrapper_executes_operations() {
+    let logs = (0..4)
+        .map(|_| Arc::new(Log::<CountersWriteOperation>::default()))
+        .collect();
+    let counters = CountersReplica::new(Replica::new(logs));
+    let idx = counters.register().unwrap();
+
+    for slot in 0..SLOTS {
+        assert_eq!(counters.add(slot, slot as u64, idx), slot as u64);
+    }
+    assert_eq!(counters.add(7, 3, idx), 10);
+    assert_eq!(counters.get(7, idx), 10);
+    assert_eq!(counters.get(15, idx), 15);
+
+    counters.reset(idx);
+    for slot in 0..SLOTS {
+        assert_eq!(counters.get(slot, idx), 0);
+    }
+}
@@ -0,0 +1,121 @@
+// Copyright © 2019-2020 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Tests the code generated by `#[replicated]` for node-replication.
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::thread;
+
+use node_replication::{Dispatch, Log, Replica};
+use node_replication_derive::replicated;
+
+#[derive(Default)]
+pub struct Map {
+    storage: HashMap<u64, u64>,
+}
+
+#[replicated]
+impl Map {
+    /// Inserts `value` at `key`.
+    #[operation]
+    pub fn put(&mut self, key: u64, value: u64) -> Option<u64> {
+        self.storage.insert(key, value)
+    }
+
+    #[operation]
+    pub fn clear(&mut self) {
+        self.storage.clear();
+    }
+
+    #[operation]
+    pub fn get(&self, key: u64) -> Option<u64> {
+        self.storage.get(&key).copied()
+    }
+
+    #[operation]
+    pub fn len(&self) -> usize {
+        self.storage.len()
+    }
+
+    #[operation(read)]
+    pub fn sum_of(&self, keys: Vec<u64>) -> u64 {
+        keys.iter().filter_map(|k| self.storage.get(k)).sum()
+    }
+
+    /// Not an operation.
+    pub fn is_empty(&self) -> bool {
+        self.storage.is_empty()
+    }
+}
+
+// Tests that the generated enums and `Dispatch` implementation call the methods.
+#[test]
+fn test_dispatch_calls_methods() {
+    let mut map = Map::default();
+
+    assert!(matches!(
+        map.dispatch_mut(MapWriteOperation::Put(1, 2)),
+        MapResponse::Put(None)
+    ));
+    assert!(matches!(
+        map.dispatch(MapReadOperation::Get(1)),
+        MapResponse::Get(Some(2))
+    ));
+    assert!(matches!(
+        map.dispatch(MapReadOperation::SumOf(vec![1, 3])),
+        MapResponse::SumOf(2)
+    ));
+    assert!(matches!(
+        map.dispatch_mut(MapWriteOperation::Clear),
+        MapResponse::Clear(())
+    ));
+    assert!(map.is_empty());
+    assert_eq!(MapReadOperation::Len, MapReadOperation::Len);
+}
+
+// Tests that the wrapper executes operations against the replica.
+#[test]
+fn test_wrapper_executes_operations() {
+    let log = Arc::new(Log::<MapWriteOperation>::default());
+    let map = MapReplica::new(Replica::new(&log));
+    let idx = map.register().unwrap();
+
+    assert_eq!(map.put(1, 10, idx), None);
+    assert_eq!(map.put(1, 11, idx), Some(10));
+    assert_eq!(map.put(2, 20, idx), None);
+    assert_eq!(map.get(1, idx), Some(11));
+    assert_eq!(map.get(3, idx), None);
+    assert_eq!(map.len(idx), 2);
+    assert_eq!(map.sum_of(vec![1, 2, 3], idx), 31);
+
+    map.clear(idx);
+    assert_eq!(map.len(idx), 0);
+    assert!(map.replica().read_with(idx, |m| m.is_empty()));
+}
+
+// Tests that writes through one wrapper are visible through another replica's wrapper.
+#[test]
+fn test_wrapper_replicates_writes() {
+    let log = Arc::new(Log::<MapWriteOperation>::default());
+    let maps = [
+        MapReplica::new(Replica::new(&log)),
+        MapReplica::new(Replica::new(&log)),
+    ];
+
+    thread::scope(|s| {
+        for (i, map) in maps.iter().enumerate() {
+            s.spawn(move || {
+                let idx = map.register().unwrap();
+                for k in 0..100 {
+                    map.put(2 * k + i as u64, k, idx);
+                }
+            });
+        }
+    });
+
+    for map in maps.iter() {
+        let idx = map.register().unwrap();
+        assert_eq!(map.len(idx), 200);
+        assert_eq!(map.get(199, idx), Some(99));
+    }
+}
@@ -0,0 +1,68 @@
+// Copyright © 2019-2020 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Clocks for operations with a deadline.
+//!
+//! `Replica::execute_mut_deadline` and `Replica::execute_deadline` give up once a
+//! [Clock](trait.Clock.html) passes a deadline. In `no_std` environments, the
+//! clock is supplied by the caller (for example, a wrapper around the TSC or a
+//! timer of the kernel). With the `std` feature, [StdClock](struct.StdClock.html)
+//! is based on `std::time::Instant`; the `_timeout` variants of these methods
+//! use it.
+
+use core::time::Duration;
+
+/// A monotonic clock.
+pub trait Clock {
+    /// Returns the time that passed since some fixed point in the past. Must
+    /// never go backwards.
+    fn now(&self) -> Duration;
+}
+
+/// A clock that measures time since it was created, using `std::time::Instant`.
+#[cfg(feature = "std")]
+#[derive(Clone, Copy, Debug)]
+pub struct StdClock {
+    /// The point in time this clock started at.
+    epoch: std::time::Instant,
+}
+
+#[cfg(feature = "std")]
+impl StdClock {
+    /// Creates a clock that starts at zero.
+    pub fn new() -> StdClock {
+        StdClock {
+            epoch: std::time::Instant::now(),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl Default for StdClock {
+    fn default() -> StdClock {
+        StdClock::new()
+    }
+}
+
+#[cfg(feature = "std")]
+impl Clock for StdClock {
+    fn now(&self) -> Duration {
+        self.epoch.elapsed()
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+
+    // Tests that the std clock starts out at zero and moves forward.
+    #[test]
+    fn test_std_clock() {
+        let c = StdClock::new();
+        let t0 = c.now();
+        assert!(t0 < Duration::from_secs(1));
+
+        std::thread::sleep(Duration::from_millis(2));
+        assert!(c.now() >= t0 + Duration::from_millis(2));
+    }
+}
@@ -4,20 +4,25 @@
 use alloc::vec::Vec;
 use core::cell::Cell;
 use core::default::Default;
+use core::sync::atomic::AtomicBool;
 
 use crossbeam_utils::CachePadded;
 
-/// The maximum number of operations that can be batched inside this context.
-/// NOTE: This constant must be a power of two for index() to work.
+use crate::wait::Parker;
+
+/// The default maximum number of operations that can be batched inside a context.
+/// NOTE: This constant (and any other batch size) must be a power of two for index()
+/// to work.
 #[cfg(not(loom))]
 pub const MAX_PENDING_OPS: usize = 32;
 #[cfg(loom)]
 pub const MAX_PENDING_OPS: usize = 1;
 const_assert!(MAX_PENDING_OPS >= 1 && (MAX_PENDING_OPS & (MAX_PENDING_OPS - 1) == 0));
 
-/// A pending operation is a combination of the its op-code (T),
-/// and the corresponding result (R).
-type PendingOperation<T, R> = Cell<(Option<T>, Option<R>)>;
+/// A pending operation is a combination of the its op-code (T), the corresponding
+/// result (R), the logical index the operation was appended at on the log, and
+/// whether the thread that issued it doesn't want its result.
+type PendingOperation<T, R> = Cell<(Option<T>, Option<R>, usize, bool)>;
 
 /// Contains all state local to a particular thread.
 ///
@@ -32,15 +37,17 @@ type PendingOperation<T, R> = Cell<(Option<T>, Option<R>)>;
 ///
 /// `R` is a type parameter required by the struct. It is the type on the result obtained
 /// when an operation is executed against the replica.
+///
+/// `MAX_PENDING` is the number of operations that can be batched inside the context.
 #[repr(align(64))]
-pub(crate) struct Context<T, R>
+pub(crate) struct Context<T, R, const MAX_PENDING: usize = MAX_PENDING_OPS>
 where
     T: Sized + Clone,
     R: Sized + Clone,
 {
     /// Array that will hold all pending operations to be appended to the shared log as
     /// well as the results obtained on executing them against a replica.
-    batch: [CachePadded<PendingOperation<T, R>>; MAX_PENDING_OPS],
+    batch: [CachePadded<PendingOperation<T, R>>; MAX_PENDING],
 
     /// Logical array index at which new operations will be enqueued into the batch.
     /// This variable is updated by the thread that owns this context, and is read by the
@@ -55,19 +62,33 @@ where
     /// This variable is updated by the combiner, and is read by the thread that owns this context.
     /// We can avoid making it an atomic by assuming we're on x86.
     pub comb: CachePadded<Cell<usize>>,
+
+    /// Parks the thread that owns this context while it waits for responses (if
+    /// its replica's wait strategy parks). Woken up by `enqueue_resps`.
+    pub parker: CachePadded<Parker>,
+
+    /// Whether the thread that owned this context unregistered from the replica.
+    /// The combiner skips free contexts, and the replica hands them out again to
+    /// threads that register.
+    pub free: CachePadded<AtomicBool>,
 }
 
-impl<T, R> Default for Context<T, R>
+impl<T, R, const MAX_PENDING: usize> Default for Context<T, R, MAX_PENDING>
 where
     T: Sized + Clone,
     R: Sized + Clone,
 {
     /// Default constructor for the context.
-    fn default() -> Context<T, R> {
-        let mut batch: [CachePadded<PendingOperation<T, R>>; MAX_PENDING_OPS] =
+    fn default() -> Context<T, R, MAX_PENDING> {
+        assert!(
+            MAX_PENDING >= 1 && MAX_PENDING.is_power_of_two(),
+            "The batch size of a context must be a power of two."
+        );
+
+        let mut batch: [CachePadded<PendingOperation<T, R>>; MAX_PENDING] =
             unsafe { ::core::mem::MaybeUninit::zeroed().assume_init() };
         for elem in &mut batch[..] {
-            *elem = CachePadded::new(Cell::new((None, None)));
+            *elem = CachePadded::new(Cell::new((None, None, 0, false)));
         }
 
         Context {
@@ -75,11 +96,13 @@ where
             tail: CachePadded::new(Cell::new(Default::default())),
             head: CachePadded::new(Cell::new(Default::default())),
             comb: CachePadded::new(Cell::new(Default::default())),
+            parker: CachePadded::new(Parker::default()),
+            free: CachePadded::new(AtomicBool::new(false)),
         }
     }
 }
 
-impl<T, R> Context<T, R>
+impl<T, R, const MAX_PENDING: usize> Context<T, R, MAX_PENDING>
 where
     T: Sized + Clone,
     R: Sized + Clone,
@@ -89,12 +112,25 @@ where
     /// Returns true if the operation was successfully enqueued. False otherwise.
     #[inline(always)]
     pub(crate) fn enqueue(&self, op: T) -> bool {
+        self.enqueue_with(op, false)
+    }
+
+    /// Same as `enqueue()`, but the combiner drops the result of the operation
+    /// instead of handing it back to the thread.
+    #[inline(always)]
+    pub(crate) fn enqueue_noreply(&self, op: T) -> bool {
+        self.enqueue_with(op, true)
+    }
+
+    #[inline(always)]
+    fn enqueue_with(&self, op: T, noreply: bool) -> bool {
+        self.reclaim();
         let t = self.tail.get();
         let h = self.head.get();
 
         // Check if we have space in the batch to hold this operation. If we don't, then
         // return false to the caller thread.
-        if t - h == MAX_PENDING_OPS {
+        if t - h == MAX_PENDING {
             return false;
         };
 
@@ -102,17 +138,63 @@ where
         // combiner sees this operation. Relying on TSO here to make sure that the tail
         // is updated only after the operation has been written in.
         let e = self.batch[self.index(t)].as_ptr();
-        unsafe { (*e).0 = Some(op) };
+        unsafe {
+            (*e).0 = Some(op);
+            (*e).3 = noreply;
+        }
 
         self.tail.set(t + 1);
         true
     }
 
+    /// Removes the operation enqueued last, unless a combiner already executed it.
+    /// Must only be called while holding the combiner lock of the replica, so that
+    /// no combiner collects the operation at the same time.
+    ///
+    /// Returns true if the operation was removed.
+    #[inline(always)]
+    pub(crate) fn retract(&self) -> bool {
+        let t = self.tail.get();
+        if self.comb.get() == t {
+            return false;
+        }
+
+        let e = self.batch[self.index(t - 1)].as_ptr();
+        unsafe { (*e).0 = None };
+        self.tail.set(t - 1);
+        true
+    }
+
+    /// Enqueues as many of the passed in operations as the batch has space for,
+    /// in order, and makes all of them visible to the combiner at once.
+    ///
+    /// Returns the number of operations that were enqueued.
+    #[inline(always)]
+    pub(crate) fn enqueue_batch(&self, ops: &[T]) -> usize {
+        self.reclaim();
+        let t = self.tail.get();
+        let h = self.head.get();
+        let n = core::cmp::min(MAX_PENDING - (t - h), ops.len());
+
+        for (i, op) in ops.iter().take(n).enumerate() {
+            let e = self.batch[self.index(t + i)].as_ptr();
+            unsafe {
+                (*e).0 = Some(op.clone());
+                (*e).3 = false;
+            }
+        }
+
+        // Same as in enqueue(), relying on TSO to publish the operations before the tail.
+        self.tail.set(t + n);
+        n
+    }
+
     /// Enqueues a batch of responses onto this context. This is invoked by the combiner
     /// after it has executed operations (obtained through a call to ops()) against the
-    /// replica this thread is registered against.
+    /// replica this thread is registered against. Each response comes with the logical
+    /// index its operation was appended at on the shared log.
     #[inline(always)]
-    pub(crate) fn enqueue_resps(&self, responses: &[R]) {
+    pub(crate) fn enqueue_resps(&self, responses: &[(R, usize)]) {
         let h = self.comb.get();
         let n = responses.len();
 
@@ -123,14 +205,16 @@ where
 
         // Starting from `comb`, write all responses into the batch. Assume here that
         // the slice above doesn't cause us to cross the tail of the batch.
-        for (i, response) in responses.iter().enumerate().take(n) {
+        for (i, (response, pos)) in responses.iter().enumerate().take(n) {
             let e = self.batch[self.index(h + i)].as_ptr();
             unsafe {
-                (*e).1 = Some(response.clone());
+                (*e).1 = if (*e).3 { None } else { Some(response.clone()) };
+                (*e).2 = *pos;
             }
         }
 
         self.comb.set(h + n);
+        self.parker.unpark_thread();
     }
 
     /// Adds any pending operations on this context to a passed in buffer. Returns the
@@ -180,6 +264,14 @@ where
     /// Returns a single response if available. Otherwise, returns None.
     #[inline(always)]
     pub(crate) fn res(&self) -> Option<R> {
+        self.res_at().map(|(resp, _pos)| resp)
+    }
+
+    /// Same as `res()`, but also returns the logical index the operation was
+    /// appended at on the shared log.
+    #[inline(always)]
+    pub(crate) fn res_at(&self) -> Option<(R, usize)> {
+        self.reclaim();
         let s = self.head.get();
         let f = self.comb.get();
 
@@ -193,19 +285,40 @@ where
         }
 
         self.head.set(s + 1);
-        unsafe { (*self.batch[self.index(s)].as_ptr()).1.clone() }
+        let e = self.batch[self.index(s)].as_ptr();
+        unsafe { (*e).1.clone().map(|resp| (resp, (*e).2)) }
+    }
+
+    /// Returns true if some operations on this context weren't executed yet.
+    #[inline(always)]
+    pub(crate) fn has_pending(&self) -> bool {
+        self.comb.get() != self.tail.get()
+    }
+
+    /// Frees up the slots of executed operations whose results the thread didn't
+    /// ask for, up to the first result that is still waiting to be collected.
+    #[inline(always)]
+    pub(crate) fn reclaim(&self) {
+        let mut h = self.head.get();
+        let f = self.comb.get();
+
+        while h < f && unsafe { (*self.batch[self.index(h)].as_ptr()).3 } {
+            h += 1;
+        }
+
+        self.head.set(h);
     }
 
     /// Returns the maximum number of operations that will go pending on this context.
     #[inline(always)]
     pub(crate) fn batch_size() -> usize {
-        MAX_PENDING_OPS
+        MAX_PENDING
     }
 
     /// Given a logical address, returns an index into the batch at which it falls.
     #[inline(always)]
     fn index(&self, logical: usize) -> usize {
-        logical & (MAX_PENDING_OPS - 1)
+        logical & (MAX_PENDING - 1)
     }
 }
 
@@ -247,11 +360,47 @@ mod test {
         assert_eq!(c.comb.get(), 0);
     }
 
+    // Tests that retract() removes the last operation only if it wasn't executed.
+    #[test]
+    fn test_context_retract() {
+        let c = Context::<u64, Result<u64, ()>>::default();
+        assert!(!c.retract());
+
+        assert!(c.enqueue(121));
+        assert!(c.enqueue(122));
+        assert!(c.retract());
+        assert_eq!(c.tail.get(), 1);
+        unsafe { assert_eq!((*c.batch[1].as_ptr()).0, None) };
+
+        c.comb.set(1);
+        assert!(!c.retract());
+        assert_eq!(c.tail.get(), 1);
+    }
+
+    // Tests that enqueue_batch() enqueues as many operations as fit in the batch.
+    #[test]
+    fn test_context_enqueue_batch() {
+        let c = Context::<u64, Result<u64, ()>>::default();
+        let ops: vec::Vec<u64> = (0..MAX_PENDING_OPS as u64 + 2).collect();
+
+        c.head.set(1);
+        c.tail.set(3);
+        assert_eq!(c.enqueue_batch(&ops[..2]), 2);
+        assert_eq!(c.tail.get(), 5);
+        assert_eq!(c.batch[3].get().0, Some(0));
+        assert_eq!(c.batch[4].get().0, Some(1));
+
+        assert_eq!(c.enqueue_batch(&ops), MAX_PENDING_OPS - 4);
+        assert_eq!(c.tail.get(), MAX_PENDING_OPS + 1);
+        assert_eq!(c.batch[0].get().0, Some(MAX_PENDING_OPS as u64 - 5));
+        assert_eq!(c.enqueue_batch(&ops), 0);
+    }
+
     // Tests that we can successfully enqueue responses onto the context.
     #[test]
     fn test_context_enqueue_resps() {
         let c = Context::<u64, Result<u64, ()>>::default();
-        let r = [Ok(11), Ok(12), Ok(13), Ok(14)];
+        let r = [(Ok(11), 1), (Ok(12), 2), (Ok(13), 3), (Ok(14), 4)];
 
         c.tail.set(16);
         c.comb.set(12);
@@ -261,10 +410,14 @@ mod test {
         assert_eq!(c.head.get(), 0);
         assert_eq!(c.comb.get(), 16);
 
-        assert_eq!(c.batch[12].get().1, Some(r[0]));
-        assert_eq!(c.batch[13].get().1, Some(r[1]));
-        assert_eq!(c.batch[14].get().1, Some(r[2]));
-        assert_eq!(c.batch[15].get().1, Some(r[3]));
+        assert_eq!(c.batch[12].get().1, Some(r[0].0));
+        assert_eq!(c.batch[12].get().2, r[0].1);
+        assert_eq!(c.batch[13].get().1, Some(r[1].0));
+        assert_eq!(c.batch[13].get().2, r[1].1);
+        assert_eq!(c.batch[14].get().1, Some(r[2].0));
+        assert_eq!(c.batch[14].get().2, r[2].1);
+        assert_eq!(c.batch[15].get().1, Some(r[3].0));
+        assert_eq!(c.batch[15].get().2, r[3].1);
     }
 
     // Tests that attempting to enqueue an empty batch of responses on the context
@@ -339,7 +492,7 @@ mod test {
     #[test]
     fn test_context_res() {
         let c = Context::<u64, Result<u64, ()>>::default();
-        let r = [Ok(11), Ok(12), Ok(13), Ok(14)];
+        let r = [(Ok(11), 1), (Ok(12), 2), (Ok(13), 3), (Ok(14), 4)];
 
         c.tail.set(16);
         c.enqueue_resps(&r);
@@ -347,19 +500,62 @@ mod test {
         assert_eq!(c.tail.get(), 16);
         assert_eq!(c.comb.get(), 4);
 
-        assert_eq!(c.res(), Some(r[0]));
+        assert_eq!(c.res(), Some(r[0].0));
         assert_eq!(c.head.get(), 1);
 
-        assert_eq!(c.res(), Some(r[1]));
+        assert_eq!(c.res(), Some(r[1].0));
         assert_eq!(c.head.get(), 2);
 
-        assert_eq!(c.res(), Some(r[2]));
+        assert_eq!(c.res(), Some(r[2].0));
         assert_eq!(c.head.get(), 3);
 
-        assert_eq!(c.res(), Some(r[3]));
+        assert_eq!(c.res(), Some(r[3].0));
         assert_eq!(c.head.get(), 4);
     }
 
+    // Tests that res_at() returns responses along with their positions on the log.
+    #[test]
+    fn test_context_res_at() {
+        let c = Context::<u64, Result<u64, ()>>::default();
+        let r = [(Ok(11), 7), (Ok(12), 9)];
+
+        c.tail.set(2);
+        c.enqueue_resps(&r);
+
+        assert_eq!(c.res_at(), Some(r[0]));
+        assert_eq!(c.res(), Some(r[1].0));
+        assert_eq!(c.res_at(), None);
+        assert_eq!(c.head.get(), 2);
+    }
+
+    // Tests that responses to operations enqueued without a reply are dropped, and
+    // that their slots are freed up once they were executed.
+    #[test]
+    fn test_context_enqueue_noreply() {
+        let c = Context::<u64, Result<u64, ()>>::default();
+        let r = [(Ok(11), 1), (Ok(12), 2), (Ok(13), 3)];
+
+        assert!(c.enqueue_noreply(1));
+        assert!(c.enqueue(2));
+        assert!(c.enqueue_noreply(3));
+        assert!(c.has_pending());
+        c.enqueue_resps(&r);
+        assert!(!c.has_pending());
+
+        assert_eq!(c.batch[0].get().1, None);
+        assert_eq!(c.batch[2].get().1, None);
+        assert_eq!(c.res_at(), Some(r[1]));
+        assert_eq!(c.res(), None);
+        assert_eq!(c.head.get(), 3);
+
+        for i in 0..MAX_PENDING_OPS as u64 {
+            assert!(c.enqueue_noreply(i));
+        }
+        assert!(!c.enqueue(0));
+        c.comb.set(c.tail.get());
+        assert!(c.enqueue(0));
+    }
+
     // Tests that we cannot retrieve responses when none were enqueued to begin with.
     #[test]
     fn test_context_res_empty() {
@@ -399,4 +595,24 @@ mod test {
         let c = Context::<u64, Result<u64, ()>>::default();
         assert_eq!(c.index(100), 100 % MAX_PENDING_OPS);
     }
+
+    // Tests that a context can be created with a batch size other than the default.
+    #[test]
+    fn test_context_custom_batch_size() {
+        let c = Context::<u64, Result<u64, ()>, 4>::default();
+        assert_eq!(c.batch.len(), 4);
+        assert_eq!(Context::<u64, Result<u64, ()>, 4>::batch_size(), 4);
+        for i in 0..4 {
+            assert!(c.enqueue(i));
+        }
+        assert!(!c.enqueue(4));
+        assert_eq!(c.index(5), 1);
+    }
+
+    // Tests that a batch size that is not a power of two is rejected.
+    #[test]
+    #[should_panic]
+    fn test_context_batch_size_not_power_of_two() {
+        let _c = Context::<u64, Result<u64, ()>, 3>::default();
+    }
 }
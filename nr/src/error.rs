@@ -0,0 +1,51 @@
+// Copyright © 2019-2020 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+use core::fmt;
+
+/// Errors returned by the fallible (`try_`) methods of [Replica](struct.Replica.html).
+///
+/// Their infallible counterparts panic in these cases instead.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Error {
+    /// The shared log has no identifiers left to hand out to another replica.
+    ReplicasExhausted,
+
+    /// The replica has no slots left to hand out to another thread.
+    ThreadsExhausted,
+
+    /// A replica's local tail is not within the shared log anymore; the log was
+    /// corrupted or garbage collected entries the replica hadn't executed yet.
+    LogOutOfRange,
+
+    /// The operation did not complete in time. A write operation was not
+    /// appended to the shared log and won't be.
+    TimedOut,
+
+    /// A write operation did not complete in time, but it is on its way into the
+    /// shared log. The thread must collect the response with
+    /// `Replica::collect_response_deadline` (or `_timeout`) before it issues
+    /// further operations.
+    InFlight,
+
+    /// Writing operations to the segment of a persistent log failed. The
+    /// operations were not executed, and the log doesn't persist any further
+    /// operations; every write fails with this error from then on.
+    SegmentFailed,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::ReplicasExhausted => write!(f, "no replica identifiers left on the shared log"),
+            Error::ThreadsExhausted => write!(f, "no thread slots left on the replica"),
+            Error::LogOutOfRange => write!(f, "local tail not within the shared log"),
+            Error::TimedOut => write!(f, "operation timed out"),
+            Error::InFlight => write!(f, "operation timed out while in flight"),
+            Error::SegmentFailed => write!(f, "failed to write operations to the segment"),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for Error {}
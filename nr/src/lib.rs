@@ -70,7 +70,7 @@
     feature(new_uninit, get_mut_unchecked, negative_impls)
 )]
 
-#[cfg(test)]
+#[cfg(any(test, feature = "std"))]
 extern crate std;
 
 extern crate alloc;
@@ -84,10 +84,24 @@ extern crate log as logging;
 #[macro_use]
 extern crate static_assertions;
 
+pub mod clock;
 mod context;
+mod error;
 mod log;
+#[cfg(feature = "std")]
+pub mod node_replicated;
+mod observer;
+#[cfg(feature = "persistence")]
+pub mod persist;
+pub mod placement;
+pub mod progress;
+#[cfg(feature = "persistence")]
+pub mod record;
 mod replica;
 mod reusable_box;
+#[cfg(feature = "stats")]
+pub mod stats;
+pub mod wait;
 
 #[cfg(not(loom))]
 #[path = "rwlock.rs"]
@@ -96,12 +110,26 @@ pub mod rwlock;
 #[path = "loom_rwlock.rs"]
 pub mod rwlock;
 
-pub use crate::log::{Log, MAX_REPLICAS_PER_LOG};
+pub use crate::log::{LagEvent, Log, MAX_REPLICAS_PER_LOG};
 pub use context::MAX_PENDING_OPS;
+pub use error::Error;
+#[cfg(feature = "std")]
+pub use node_replicated::NodeReplicated;
 pub use replica::{Replica, ReplicaToken, MAX_THREADS_PER_REPLICA};
 pub use reusable_box::ReusableBoxFuture;
 
 use core::fmt::Debug;
+use core::time::Duration;
+
+/// Paths used by the code that `node-replication-derive` generates; not part
+/// of the public API.
+#[doc(hidden)]
+pub mod __derive {
+    #[cfg(not(loom))]
+    pub use alloc::sync::Arc;
+    #[cfg(loom)]
+    pub use loom::sync::Arc;
+}
 
 /// Trait that a data structure must implement to be usable with this library.
 ///
@@ -132,6 +160,70 @@ pub trait Dispatch {
     /// Method on the data structure that allows a write operation to be
     /// executed against it.
     fn dispatch_mut(&mut self, op: Self::WriteOperation) -> Self::Response;
+
+    /// Executes a write operation along with the context it was appended to the
+    /// shared log in. Replicas invoke this method instead of `dispatch_mut`; by
+    /// default, it ignores the context.
+    ///
+    /// The context is the same on every replica, so a data structure that needs
+    /// a sequence number, a timestamp or a seed for an operation can derive it
+    /// from the context rather than reading it from its environment, which
+    /// would let the replicas diverge.
+    fn dispatch_mut_with(
+        &mut self,
+        op: Self::WriteOperation,
+        _ctx: &WriteContext,
+    ) -> Self::Response {
+        self.dispatch_mut(op)
+    }
+
+    /// Merges the write operation `next` into `op`, which was issued just before
+    /// it, and returns an operation that has the effect of both. Returns `None`
+    /// if the two can't be merged; by default, no operations are merged.
+    ///
+    /// Before it appends a batch to the shared log, the combiner of a replica
+    /// merges runs of adjacent operations, so that the log and every replica
+    /// execute a merged operation only once. The merged operation is attributed
+    /// to the thread that issued the first operation of the run (see
+    /// `WriteContext::thread`), and all operations of the run share its position
+    /// on the log.
+    fn coalesce(
+        _op: &Self::WriteOperation,
+        _next: &Self::WriteOperation,
+    ) -> Option<Self::WriteOperation> {
+        None
+    }
+
+    /// Returns the response of the `i`th operation of `ops`, a run of operations
+    /// that `coalesce` merged into one operation whose response was `resp`. By
+    /// default, every operation of the run gets `resp`.
+    fn split_response(
+        _ops: &[Self::WriteOperation],
+        _i: usize,
+        resp: &Self::Response,
+    ) -> Self::Response {
+        resp.clone()
+    }
+}
+
+/// Describes how a write operation was appended to the shared log. Passed to
+/// `Dispatch::dispatch_mut_with`; identical on all replicas.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct WriteContext {
+    /// Logical index of the operation on the log. Unique, and increases with
+    /// every operation appended to the log.
+    pub pos: usize,
+
+    /// Identifier of the replica that appended the operation, see `Replica::id`.
+    pub replica: usize,
+
+    /// Identifier of the thread that issued the operation on `replica`, see
+    /// `ReplicaToken::id`. Zero if the operation was appended directly to the log.
+    pub thread: usize,
+
+    /// The time the operation was appended at, if the log has a clock (see
+    /// `Log::update_clock`). Operations appended in the same batch share it.
+    pub timestamp: Option<Duration>,
 }
 
 #[cfg(doctest)]
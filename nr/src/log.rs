@@ -1,7 +1,10 @@
 // Copyright © 2019-2020 VMware, Inc. All Rights Reserved.
 // SPDX-License-Identifier: Apache-2.0 OR MIT
 
-use alloc::alloc::{alloc, dealloc, Layout};
+use alloc::alloc::Layout;
+use alloc::boxed::Box;
+#[cfg(feature = "stats")]
+use alloc::vec::Vec;
 
 use core::cell::Cell;
 use core::default::Default;
@@ -9,6 +12,7 @@ use core::fmt;
 use core::mem::{align_of, size_of};
 use core::ops::{Drop, FnMut};
 use core::slice::from_raw_parts_mut;
+use core::time::Duration;
 
 #[cfg(not(loom))]
 use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
@@ -17,31 +21,33 @@ pub use loom::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 
 use crossbeam_utils::CachePadded;
 
+use crate::clock::Clock;
 use crate::context::MAX_PENDING_OPS;
+use crate::error::Error;
+use crate::observer::Ring;
+use crate::placement::{Global, Placement};
 use crate::replica::MAX_THREADS_PER_REPLICA;
+#[cfg(feature = "stats")]
+use crate::stats::{LogCounters, LogStats, ReplicaLag};
+use crate::wait::{Spin, WaitStrategy};
+use crate::WriteContext;
 
 /// The default size of the shared log in bytes. If constructed using the
 /// default constructor, the log will be these many bytes in size. Currently
 /// set to 32 MiB based on the ASPLOS 2017 paper.
-const DEFAULT_LOG_BYTES: usize = 32 * 1024 * 1024;
+pub(crate) const DEFAULT_LOG_BYTES: usize = 32 * 1024 * 1024;
 const_assert!(DEFAULT_LOG_BYTES >= 1 && (DEFAULT_LOG_BYTES & (DEFAULT_LOG_BYTES - 1) == 0));
 
-/// The maximum number of replicas that can be registered with the log.
+/// The default maximum number of replicas that can be registered with the log.
 #[cfg(not(loom))]
 pub const MAX_REPLICAS_PER_LOG: usize = 192;
 #[cfg(loom)] // Otherwise uses too much stack space wich crashes in loom...
 pub const MAX_REPLICAS_PER_LOG: usize = 3;
 
-/// Constant required for garbage collection. When the tail and the head are
-/// these many entries apart on the circular buffer, garbage collection will
-/// be performed by one of the replicas registered with the log.
-///
-/// For the GC algorithm to work, we need to ensure that we can support the
-/// largest possible append after deciding to perform GC. This largest possible
-/// append is when every thread within a replica has a full batch of writes
-/// to be appended to the shared log.
-const GC_FROM_HEAD: usize = MAX_PENDING_OPS * MAX_THREADS_PER_REPLICA;
-const_assert!(GC_FROM_HEAD >= 1 && (GC_FROM_HEAD & (GC_FROM_HEAD - 1) == 0));
+const_assert!(
+    MAX_PENDING_OPS * MAX_THREADS_PER_REPLICA >= 1
+        && (MAX_PENDING_OPS * MAX_THREADS_PER_REPLICA).is_power_of_two()
+);
 
 /// Threshold after how many iterations we log a warning for busy spinning loops.
 ///
@@ -49,9 +55,10 @@ const_assert!(GC_FROM_HEAD >= 1 && (GC_FROM_HEAD & (GC_FROM_HEAD - 1) == 0));
 /// Should be a power of two to avoid divisions.
 const WARN_THRESHOLD: usize = 1 << 28;
 
-/// An entry that sits on the log. Each entry consists of three fields: The operation to
-/// be performed when a thread reaches this entry on the log, the replica that appended
-/// this operation, and a flag indicating whether this entry is valid.
+/// An entry that sits on the log. Each entry consists of the operation to be performed
+/// when a thread reaches this entry on the log, the replica and thread that appended
+/// this operation along with the time it was appended at, and a flag indicating whether
+/// this entry is valid.
 ///
 /// `T` is the type on the operation - typically an enum class containing opcodes as well as
 /// arguments. It is required that this type be sized and cloneable.
@@ -65,12 +72,88 @@ where
     operation: Option<T>,
 
     /// Identifies the replica that issued the above operation.
-    replica: usize,
+    replica: u32,
+
+    /// Identifies the thread (on `replica`) that issued the above operation. Zero
+    /// if the operation was appended directly through `Log::append`.
+    thread: u32,
+
+    /// The time the operation was appended at, if the log has a clock. Packed
+    /// with `pack_timestamp` to keep entries small.
+    timestamp: u64,
 
     /// Indicates whether this entry represents a valid operation when on the log.
     alivef: AtomicBool,
 }
 
+/// Stands in for the timestamp of an entry that was appended without a clock.
+const NO_TIMESTAMP: u64 = u64::MAX;
+
+/// Packs the timestamp of an entry into nanoseconds. Timestamps too large to fit
+/// are cut down to about 584 years.
+pub(crate) fn pack_timestamp(timestamp: Option<Duration>) -> u64 {
+    match timestamp {
+        Some(t) => core::cmp::min(t.as_nanos(), (NO_TIMESTAMP - 1) as u128) as u64,
+        None => NO_TIMESTAMP,
+    }
+}
+
+/// Reverses `pack_timestamp`.
+pub(crate) fn unpack_timestamp(timestamp: u64) -> Option<Duration> {
+    if timestamp == NO_TIMESTAMP {
+        None
+    } else {
+        Some(Duration::from_nanos(timestamp))
+    }
+}
+
+/// Returns the thread that issued each operation of a batch, given the number of
+/// operations of every thread of the replica (see `Log::append_with`). Continues
+/// with thread zero once `threads` is exhausted.
+pub(crate) fn issuers(threads: &[usize]) -> impl Iterator<Item = usize> + '_ {
+    threads
+        .iter()
+        .enumerate()
+        .flat_map(|(t, n)| (0..*n).map(move |_i| t + 1))
+        .chain(core::iter::repeat(0))
+}
+
+/// Hands out the identifiers of logs.
+static NEXT_LOG_ID: core::sync::atomic::AtomicUsize = core::sync::atomic::AtomicUsize::new(0);
+
+/// Reported by a [Log](struct.Log.html) when a replica falls behind the other
+/// replicas registered with it, and holds back garbage collection.
+///
+/// The application can react by making sure some thread on the lagging replica
+/// calls `Replica::sync`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LagEvent {
+    /// Identifier of the log, see `Log::id`.
+    pub log: usize,
+
+    /// Identifier of the lagging replica on the log, see `Replica::id`.
+    pub replica: usize,
+
+    /// Number of entries the replica is behind the tail of the log.
+    pub lag: usize,
+}
+
+/// Closure invoked with replicas that lag behind on the log.
+type LagFn = dyn Fn(LagEvent) + Send + Sync;
+
+/// Closure invoked with the logical index, the issuing replica and the operation
+/// of every entry on the log.
+type ObserverFn<'a, T> = dyn Fn(usize, usize, &T) + Send + Sync + 'a;
+
+/// Clock that timestamps the operations appended to the log.
+type ClockFn = dyn Clock + Send + Sync;
+
+/// Closure that writes a batch of operations appended to the log to a segment file,
+/// along with the replica and threads that issued them and their timestamp.
+#[cfg(feature = "persistence")]
+type SegmentFn<'a, T> =
+    dyn Fn(usize, &[T], usize, &[usize], Option<Duration>) -> Result<(), Error> + Send + Sync + 'a;
+
 /// A log of operations that is typically accessed by multiple
 /// [Replica](struct.Replica.html).
 ///
@@ -86,6 +169,12 @@ where
 /// their arguments that will go on the log and would typically be an enum
 /// class.
 ///
+/// The const parameters bound the number of replicas that can register with the
+/// log (`MAX_REPLICAS`), and the number of threads per replica (`MAX_THREADS`) and
+/// pending operations per thread (`MAX_PENDING`) of these replicas. The latter two
+/// determine the largest batch a replica can append at once, and must match the
+/// parameters of the [Replica](struct.Replica.html)s using the log.
+///
 /// This struct is aligned to 64 bytes optimizing cache access.\
 ///
 /// # Note
@@ -93,8 +182,13 @@ where
 /// from `new`. Only in the rare circumstance someone would implement their own
 /// Replica would it be necessary to call any of the Log's methods.
 #[repr(align(64))]
-pub struct Log<'a, T>
-where
+pub struct Log<
+    'a,
+    T,
+    const MAX_REPLICAS: usize = MAX_REPLICAS_PER_LOG,
+    const MAX_THREADS: usize = MAX_THREADS_PER_REPLICA,
+    const MAX_PENDING: usize = MAX_PENDING_OPS,
+> where
     T: Sized + Clone,
 {
     /// Raw pointer to the actual underlying log. Required for dealloc.
@@ -103,6 +197,9 @@ where
     /// Size of the underlying log in bytes. Required for dealloc.
     rawb: usize,
 
+    /// Where the memory of the underlying log came from. Required for dealloc.
+    placement: Box<dyn Placement>,
+
     /// The maximum number of entries that can be held inside the log.
     size: usize,
 
@@ -125,7 +222,7 @@ where
     /// Required for garbage collection; since replicas make progress over the log
     /// independently, we want to make sure that we don't garbage collect operations
     /// that haven't been executed by all replicas.
-    ltails: [CachePadded<AtomicUsize>; MAX_REPLICAS_PER_LOG],
+    ltails: [CachePadded<AtomicUsize>; MAX_REPLICAS],
 
     /// Identifier that will be allocated to the next replica that registers with
     /// this Log. Also required to correctly index into ltails above.
@@ -134,10 +231,45 @@ where
     /// Array consisting of local alive masks for each registered replica. Required
     /// because replicas make independent progress over the log, so we need to
     /// track log wrap-arounds for each of them separately.
-    lmasks: [CachePadded<Cell<bool>>; MAX_REPLICAS_PER_LOG],
+    lmasks: [CachePadded<Cell<bool>>; MAX_REPLICAS],
+
+    /// Array of flags indicating which replica identifiers below `next` have been
+    /// released through `unregister()`. A released replica is ignored during garbage
+    /// collection and its identifier is handed out again by `register()`.
+    lfree: [AtomicBool; MAX_REPLICAS],
+
+    /// Identifier of this log, unique among the logs of a process.
+    id: usize,
+
+    /// Invoked for every replica that lags more than a third of the log behind its
+    /// tail while the log fills up. Invoked for at most one round of replicas
+    /// between two garbage collections; `notify_lag` is reset when the head advances.
+    lag: Option<Box<LagFn>>,
+
+    /// True if `lag` can be invoked again.
+    notify_lag: AtomicBool,
+
+    /// Invoked exactly once, in log order, for every entry that was written to
+    /// the log, with the ring that appending threads copy the entries into. See
+    /// `update_observer`.
+    observer: Option<(Box<ObserverFn<'a, T>>, Ring<T>)>,
+
+    /// Timestamps every batch of operations that is appended to the log.
+    clock: Option<Box<ClockFn>>,
+
+    /// Invoked with the logical index of the first entry and the operations of
+    /// every batch that is appended to the log, before the batch is visible to any
+    /// replica. Writes the batch to a segment file, in log order.
+    #[cfg(feature = "persistence")]
+    segment: Option<Box<SegmentFn<'a, T>>>,
+
+    /// Counters about waiting for garbage collection on this log.
+    #[cfg(feature = "stats")]
+    stats: LogCounters,
 }
 
-impl<'a, T> fmt::Debug for Log<'a, T>
+impl<'a, T, const MAX_REPLICAS: usize, const MAX_THREADS: usize, const MAX_PENDING: usize>
+    fmt::Debug for Log<'a, T, MAX_REPLICAS, MAX_THREADS, MAX_PENDING>
 where
     T: Sized + Clone,
 {
@@ -151,16 +283,37 @@ where
 }
 
 /// The Log is Send. The *mut u8 (`rawp`) is never dereferenced.
-unsafe impl<'a, T> Send for Log<'a, T> where T: Sized + Clone {}
+unsafe impl<'a, T, const MAX_REPLICAS: usize, const MAX_THREADS: usize, const MAX_PENDING: usize>
+    Send for Log<'a, T, MAX_REPLICAS, MAX_THREADS, MAX_PENDING>
+where
+    T: Sized + Clone,
+{
+}
 
 /// The Log is Sync. We know this because: `head` and `tail` are atomic variables, `append()`
 /// reserves entries using a CAS, and exec() does not concurrently mutate entries on the log.
-unsafe impl<'a, T> Sync for Log<'a, T> where T: Sized + Clone {}
+unsafe impl<'a, T, const MAX_REPLICAS: usize, const MAX_THREADS: usize, const MAX_PENDING: usize>
+    Sync for Log<'a, T, MAX_REPLICAS, MAX_THREADS, MAX_PENDING>
+where
+    T: Sized + Clone,
+{
+}
 
-impl<'a, T> Log<'a, T>
+impl<'a, T, const MAX_REPLICAS: usize, const MAX_THREADS: usize, const MAX_PENDING: usize>
+    Log<'a, T, MAX_REPLICAS, MAX_THREADS, MAX_PENDING>
 where
     T: Sized + Clone,
 {
+    /// Constant required for garbage collection. When the tail and the head are
+    /// these many entries apart on the circular buffer, garbage collection will
+    /// be performed by one of the replicas registered with the log.
+    ///
+    /// For the GC algorithm to work, we need to ensure that we can support the
+    /// largest possible append after deciding to perform GC. This largest possible
+    /// append is when every thread within a replica has a full batch of writes
+    /// to be appended to the shared log.
+    pub(crate) const GC_FROM_HEAD: usize = MAX_PENDING * MAX_THREADS;
+
     /// Constructs and returns a log of size `bytes` bytes.
     /// A size between 1-2 MiB usually works well in most cases.
     ///
@@ -183,31 +336,55 @@ where
     ///
     /// This method also allocates memory for the log upfront. No further allocations
     /// will be performed once this method returns.
-    pub fn new<'b>(bytes: usize) -> Log<'b, T> {
+    pub fn new<'b>(bytes: usize) -> Log<'b, T, MAX_REPLICAS, MAX_THREADS, MAX_PENDING> {
+        Log::new_in(bytes, Global)
+    }
+
+    /// Constructs and returns a log of size `bytes` bytes whose memory is allocated
+    /// through `placement` (for example, interleaved across NUMA nodes) instead of
+    /// the global allocator.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use node_replication::placement::Global;
+    /// use node_replication::Log;
+    ///
+    /// let l = Log::<u64>::new_in(1 * 1024 * 1024, Global);
+    /// ```
+    pub fn new_in<'b>(
+        bytes: usize,
+        placement: impl Placement + 'static,
+    ) -> Log<'b, T, MAX_REPLICAS, MAX_THREADS, MAX_PENDING> {
+        assert!(
+            Self::GC_FROM_HEAD >= 1 && Self::GC_FROM_HEAD.is_power_of_two(),
+            "MAX_THREADS * MAX_PENDING must be a power of two."
+        );
+
         // Calculate the number of entries that will go into the log, and retrieve a
         // slice to it from the allocated region of memory.
-        let mut num = bytes / Log::<T>::entry_size();
+        let mut num = bytes / Self::entry_size();
 
         // Make sure the log is large enough to allow for periodic garbage collection.
-        if num < 2 * GC_FROM_HEAD {
-            num = 2 * GC_FROM_HEAD;
+        if num < 2 * Self::GC_FROM_HEAD {
+            num = 2 * Self::GC_FROM_HEAD;
         }
 
         // Round off to the next power of two if required. If we overflow, then set
         // the number of entries to the minimum required for GC. This is unlikely since
         // we'd need a log size > 2^63 entries for this to happen.
         if !num.is_power_of_two() {
-            num = num.checked_next_power_of_two().unwrap_or(2 * GC_FROM_HEAD)
+            num = num
+                .checked_next_power_of_two()
+                .unwrap_or(2 * Self::GC_FROM_HEAD)
         };
 
         // Now that we have the actual number of entries, allocate the log.
-        let b = num * Log::<T>::entry_size();
-        let mem = unsafe {
-            alloc(
-                Layout::from_size_align(b, align_of::<Cell<Entry<T>>>())
-                    .expect("Alignment error while allocating the shared log!"),
-            )
-        };
+        let b = num * Self::entry_size();
+        let mem = placement.alloc(
+            Layout::from_size_align(b, align_of::<Cell<Entry<T>>>())
+                .expect("Alignment error while allocating the shared log!"),
+        );
         if mem.is_null() {
             panic!("Failed to allocate memory for the shared log!");
         }
@@ -220,7 +397,9 @@ where
                     e,
                     Cell::new(Entry {
                         operation: None,
-                        replica: 0usize,
+                        replica: 0,
+                        thread: 0,
+                        timestamp: NO_TIMESTAMP,
                         alivef: AtomicBool::new(false),
                     }),
                 );
@@ -234,37 +413,59 @@ where
         {
             #[allow(clippy::declare_interior_mutable_const)]
             const LTAIL_DEFAULT: CachePadded<AtomicUsize> = CachePadded::new(AtomicUsize::new(0));
+            #[allow(clippy::declare_interior_mutable_const)]
+            const LFREE_DEFAULT: AtomicBool = AtomicBool::new(false);
 
             Log {
                 rawp: mem,
                 rawb: b,
+                placement: Box::new(placement),
                 size: num,
                 slog: raw,
                 head: CachePadded::new(AtomicUsize::new(0usize)),
                 tail: CachePadded::new(AtomicUsize::new(0usize)),
                 ctail: CachePadded::new(AtomicUsize::new(0usize)),
-                ltails: [LTAIL_DEFAULT; MAX_REPLICAS_PER_LOG],
+                ltails: [LTAIL_DEFAULT; MAX_REPLICAS],
                 next: CachePadded::new(AtomicUsize::new(1usize)),
-                lmasks: [LMASK_DEFAULT; MAX_REPLICAS_PER_LOG],
+                lmasks: [LMASK_DEFAULT; MAX_REPLICAS],
+                lfree: [LFREE_DEFAULT; MAX_REPLICAS],
+                id: NEXT_LOG_ID.fetch_add(1, core::sync::atomic::Ordering::Relaxed),
+                lag: None,
+                notify_lag: AtomicBool::new(true),
+                observer: None,
+                clock: None,
+                #[cfg(feature = "persistence")]
+                segment: None,
+                #[cfg(feature = "stats")]
+                stats: LogCounters::new(),
             }
         }
-        // AtomicUsize::new is not const in loom. This code block (including arr
-        // dependency) becomes redundant once
+        // AtomicUsize::new is not const in loom. This code block becomes redundant once
         // https://github.com/tokio-rs/loom/issues/170 is fixed:
         #[cfg(loom)]
         {
-            use arr_macro::arr;
             Log {
                 rawp: mem,
                 rawb: b,
+                placement: Box::new(placement),
                 size: num,
                 slog: raw,
                 head: CachePadded::new(AtomicUsize::new(0usize)),
                 tail: CachePadded::new(AtomicUsize::new(0usize)),
                 ctail: CachePadded::new(AtomicUsize::new(0usize)),
-                ltails: arr![CachePadded::new(AtomicUsize::new(0)); 3], // MAX_REPLICAS_PER_LOG
+                ltails: core::array::from_fn(|_| CachePadded::new(AtomicUsize::new(0))),
                 next: CachePadded::new(AtomicUsize::new(1usize)),
-                lmasks: [LMASK_DEFAULT; MAX_REPLICAS_PER_LOG],
+                lmasks: [LMASK_DEFAULT; MAX_REPLICAS],
+                lfree: core::array::from_fn(|_| AtomicBool::new(false)),
+                id: NEXT_LOG_ID.fetch_add(1, core::sync::atomic::Ordering::Relaxed),
+                lag: None,
+                notify_lag: AtomicBool::new(true),
+                observer: None,
+                clock: None,
+                #[cfg(feature = "persistence")]
+                segment: None,
+                #[cfg(feature = "stats")]
+                stats: LogCounters::new(),
             }
         }
     }
@@ -274,6 +475,189 @@ where
         size_of::<Cell<Entry<T>>>()
     }
 
+    /// Returns the identifier of this log. It is unique among the logs created by
+    /// a process and is reported in [LagEvent](struct.LagEvent.html)s.
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    /// Sets a callback that is invoked with a [LagEvent](struct.LagEvent.html)
+    /// for every registered replica that falls more than a third of the log
+    /// behind its tail. The callback is invoked by a thread appending to the log.
+    /// Once it reported the lagging replicas, it isn't invoked again until the
+    /// log garbage collected entries.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use node_replication::Log;
+    ///
+    /// let mut l = Log::<u64>::new(1024 * 1024);
+    /// l.update_lag_callback(|event| {
+    ///     // Wake up a thread on replica `event.replica`, which then calls `sync`.
+    /// });
+    /// ```
+    pub fn update_lag_callback(&mut self, lag: impl Fn(LagEvent) + Send + Sync + 'static) {
+        self.lag = Some(Box::new(lag));
+    }
+
+    /// Invokes the lag callback for every replica that is more than a third of
+    /// the log behind `tail`, unless it was invoked since the last GC.
+    fn notify_lagging(&self, tail: usize) {
+        let lag = match &self.lag {
+            Some(lag) if self.notify_lag.load(Ordering::Relaxed) => lag,
+            _ => return,
+        };
+
+        let r = self.next.load(Ordering::Relaxed);
+        let behind = |idx: usize| self.behind(idx, tail);
+
+        // Only one thread reports the lagging replicas until the head moves again.
+        if !(1..r).any(|idx| behind(idx) > self.size / 3)
+            || self
+                .notify_lag
+                .compare_exchange(true, false, Ordering::Relaxed, Ordering::Relaxed)
+                .is_err()
+        {
+            return;
+        }
+
+        for idx in 1..r {
+            let behind = behind(idx);
+            if behind > self.size / 3 {
+                lag(LagEvent {
+                    log: self.id,
+                    replica: idx,
+                    lag: behind,
+                });
+            }
+        }
+    }
+
+    /// Returns true if a registered replica is more than a third of the log
+    /// behind its tail.
+    #[cfg(feature = "std")]
+    pub(crate) fn is_lagging(&self) -> bool {
+        let tail = self.tail.load(Ordering::Relaxed);
+        (1..self.next.load(Ordering::Relaxed)).any(|idx| self.behind(idx, tail) > self.size / 3)
+    }
+
+    /// Returns the number of entries the replica `idx` is behind `tail`, or zero
+    /// if `idx` isn't registered.
+    fn behind(&self, idx: usize, tail: usize) -> usize {
+        if self.lfree[idx - 1].load(Ordering::Relaxed) {
+            return 0;
+        }
+        tail.saturating_sub(self.ltails[idx - 1].load(Ordering::Relaxed))
+    }
+
+    /// Sets a callback that observes every operation written to the log, for
+    /// example to keep an audit trail. It is invoked with the logical index of the
+    /// entry, the identifier of the replica that appended it and the operation,
+    /// exactly once per entry and in log order, starting with the next entry
+    /// appended to the log.
+    ///
+    /// Appending threads only copy the entries they write into a ring of at least
+    /// `capacity` entries; the callback runs on the thread that calls
+    /// `drain_observer`. Replicas call it after every round of flat combining, once
+    /// they released the combiner lock, and a thread dedicated to the observer can
+    /// call it as well. Once the ring is full, appends drain it themselves, or wait
+    /// while another thread drains it, so a slow callback slows down appends.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use node_replication::Log;
+    ///
+    /// let mut l = Log::<u64>::new(1024 * 1024);
+    /// l.update_observer(1024, |pos, replica, op| {
+    ///     println!("{}: replica {} wrote {}", pos, replica, op);
+    /// });
+    /// l.drain_observer();
+    /// ```
+    pub fn update_observer(
+        &mut self,
+        capacity: usize,
+        observer: impl Fn(usize, usize, &T) + Send + Sync + 'a,
+    ) {
+        let ring = Ring::new(capacity, self.tail.load(Ordering::SeqCst));
+        self.observer = Some((Box::new(observer), ring));
+    }
+
+    /// Passes the entries that were written to the log since the last call to the
+    /// observer set with `update_observer`. Stops at the first entry that was
+    /// reserved but isn't written yet.
+    ///
+    /// Returns the number of observed entries. Returns zero without waiting if
+    /// another thread is passing entries to the observer at the same time.
+    pub fn drain_observer(&self) -> usize {
+        match &self.observer {
+            Some((observer, ring)) => ring.drain(observer.as_ref()),
+            None => 0,
+        }
+    }
+
+    /// Sets a clock that timestamps every batch of operations when it is appended to
+    /// the log. The timestamp is stored in the log along with the operations, so every
+    /// replica passes the same one to `Dispatch::dispatch_mut_with`, in the
+    /// [WriteContext](struct.WriteContext.html) of an operation.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use core::time::Duration;
+    /// use node_replication::clock::Clock;
+    /// use node_replication::Log;
+    ///
+    /// struct Uptime;
+    ///
+    /// impl Clock for Uptime {
+    ///     fn now(&self) -> Duration {
+    ///         // Read a timer of the platform here.
+    ///         Duration::from_millis(42)
+    ///     }
+    /// }
+    ///
+    /// let mut l = Log::<u64>::new(1024 * 1024);
+    /// l.update_clock(Uptime);
+    /// ```
+    pub fn update_clock(&mut self, clock: impl Clock + Send + Sync + 'static) {
+        self.clock = Some(Box::new(clock));
+    }
+
+    /// Moves a freshly constructed log forward so that the first operation appended
+    /// to it ends up at logical index `start`. Replicas that register afterwards start
+    /// executing operations from `start` as well.
+    ///
+    /// Used to continue a log that was recovered from persistent storage.
+    #[cfg(feature = "persistence")]
+    pub(crate) fn start_at(&mut self, start: usize) {
+        self.head.store(start, Ordering::SeqCst);
+        self.tail.store(start, Ordering::SeqCst);
+        self.ctail.store(start, Ordering::SeqCst);
+        if let Some((_observer, ring)) = &self.observer {
+            ring.reset(start);
+        }
+
+        for r in 0..MAX_REPLICAS {
+            self.ltails[r].store(start, Ordering::Relaxed);
+            self.lmasks[r].set(self.mask_for(start));
+        }
+
+        // Every entry must be dead with respect to the alive mask of the logical
+        // index that will be written to it next.
+        for i in start..start + self.size {
+            let e = self.slog[self.index(i)].as_ptr();
+            unsafe { (*e).alivef.store(!self.mask_for(i), Ordering::Relaxed) };
+        }
+    }
+
+    /// Sets the closure that writes every batch appended to the log to a segment file.
+    #[cfg(feature = "persistence")]
+    pub(crate) fn set_segment(&mut self, segment: Box<SegmentFn<'a, T>>) {
+        self.segment = Some(segment);
+    }
+
     /// Registers a replica with the log. Returns an identifier that the replica
     /// can use to execute operations on the log.
     ///
@@ -298,12 +682,21 @@ where
     /// let idx = l.register().expect("Failed to register with the Log.");
     /// ```
     pub(crate) fn register(&self) -> Option<usize> {
+        // Reuse the identifier of an unregistered replica first, so that replicas
+        // coming and going don't use up the identifiers. It starts out at the
+        // beginning of the log, like a new identifier.
+        if let Some(idx) = self.reclaim() {
+            self.lmasks[idx - 1].set(self.mask_for(0));
+            self.ltails[idx - 1].store(0, Ordering::SeqCst);
+            return Some(idx);
+        }
+
         // Loop until we either run out of identifiers or we manage to increment `next`.
         loop {
             let n = self.next.load(Ordering::Relaxed);
 
             // Check if we've exceeded the maximum number of replicas the log can support.
-            if n >= MAX_REPLICAS_PER_LOG {
+            if n >= MAX_REPLICAS {
                 return None;
             };
 
@@ -319,6 +712,46 @@ where
         }
     }
 
+    /// Registers a replica with the log that starts executing operations from the
+    /// logical index `ltail` instead of the beginning of the log. Like `register`,
+    /// prefers the identifiers of replicas that have been unregistered over new ones.
+    ///
+    /// The caller must ensure that the entry at `ltail` is not garbage collected
+    /// while this method runs, i.e., that some registered replica's local tail
+    /// is at or behind `ltail`, and that the new replica starts out with the state
+    /// of the data structure at `ltail`.
+    pub(crate) fn register_at(&self, ltail: usize) -> Option<usize> {
+        let idx = self.register()?;
+
+        self.lmasks[idx - 1].set(self.mask_for(ltail));
+        self.ltails[idx - 1].store(ltail, Ordering::SeqCst);
+
+        Some(idx)
+    }
+
+    /// Claims the identifier of a replica that has been unregistered, if there is one.
+    ///
+    /// The slot still holds the (stale) local tail of the previous owner. `advance_head`
+    /// never moves the head backwards, so the head stays put until the caller stores a
+    /// local tail that is within the log again.
+    fn reclaim(&self) -> Option<usize> {
+        let n = self.next.load(Ordering::SeqCst);
+        (1..n).find(|idx| {
+            self.lfree[idx - 1]
+                .compare_exchange(true, false, Ordering::SeqCst, Ordering::SeqCst)
+                .is_ok()
+        })
+    }
+
+    /// Releases a replica identifier that was previously handed out by `register()`.
+    ///
+    /// Once released, the replica's local tail is no longer taken into account when
+    /// garbage collecting the log, and the identifier may be handed out again by a
+    /// later call to `register()` or `register_at()`.
+    pub(crate) fn unregister(&self, idx: usize) {
+        self.lfree[idx - 1].store(true, Ordering::SeqCst);
+    }
+
     /// Adds a batch of operations to the shared log.
     ///
     /// # Example
@@ -369,6 +802,39 @@ where
     #[inline(always)]
     #[doc(hidden)]
     pub fn append<F: FnMut(T, usize)>(&self, ops: &[T], idx: usize, mut s: F) {
+        let s = |op: T, ctx: &WriteContext| s(op, ctx.replica);
+        self.append_with(ops, &[], idx, s, &Spin, &|| false)
+            .unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    /// Same as `append`, but waits for garbage collection according to `wait`
+    /// instead of busy spinning, and passes the context of every operation to `s`.
+    ///
+    /// `threads` holds the number of operations in `ops` that were issued by every
+    /// thread of the replica, indexed by thread identifier minus one; the operations
+    /// of a thread follow the ones of the thread before. Operations not covered by
+    /// `threads` are marked as issued by thread zero.
+    ///
+    /// Returns an error if the replica falls off the log while consuming entries to
+    /// make room for the operations. The operations might have been appended anyway.
+    ///
+    /// Fails with `Error::SegmentFailed` if the log persists its operations and the
+    /// batch couldn't be written to the segment. The entries are appended without
+    /// their operations then, and no replica executes them.
+    ///
+    /// `expired` is checked while waiting for garbage collection. If it returns true
+    /// before entries were reserved, this gives up with `Error::TimedOut` and nothing
+    /// is appended. Once the operations are on the log, it stops advancing the head
+    /// instead; the next thread that waits for room on the log takes that over.
+    pub(crate) fn append_with<F: FnMut(T, &WriteContext)>(
+        &self,
+        ops: &[T],
+        threads: &[usize],
+        idx: usize,
+        mut s: F,
+        wait: &dyn WaitStrategy,
+        expired: &dyn Fn() -> bool,
+    ) -> Result<(), Error> {
         let nops = ops.len();
         let mut iteration = 1;
         let mut waitgc = 1;
@@ -389,11 +855,24 @@ where
             let tail = self.tail.load(Ordering::Relaxed);
             let head = self.head.load(Ordering::Relaxed);
 
+            // Once the log is a third full, report replicas that hold back GC.
+            if tail - head > self.size / 3 {
+                self.notify_lagging(tail);
+            }
+
             // If there are fewer than `GC_FROM_HEAD` entries on the log, then just
             // try again. The replica that reserved entry (h + self.size - GC_FROM_HEAD)
             // is currently trying to advance the head of the log. Keep refreshing the
-            // replica against the log to make sure that it isn't deadlocking GC.
-            if tail > head + self.size - GC_FROM_HEAD {
+            // replica against the log to make sure that it isn't deadlocking GC, and
+            // advance the head in case that replica gave up at its deadline.
+            if tail > head + self.size - Self::GC_FROM_HEAD {
+                if expired() {
+                    return Err(Error::TimedOut);
+                }
+                #[cfg(feature = "stats")]
+                if waitgc == 1 {
+                    self.stats.append_gc_waits.fetch_add(1, Ordering::Relaxed);
+                }
                 if waitgc % WARN_THRESHOLD == 0 {
                     warn!(
                         "append(ops.len()={}, {}) takes too many iterations ({}) waiting for gc...",
@@ -402,8 +881,10 @@ where
                         waitgc,
                     );
                 }
+                self.exec_with(idx, &mut s, wait)?;
+                self.try_advance_head();
+                wait.relax(waitgc);
                 waitgc += 1;
-                self.exec(idx, &mut s);
 
                 #[cfg(loom)]
                 loom::thread::yield_now();
@@ -413,7 +894,7 @@ where
             // If on adding in the above entries there would be fewer than `GC_FROM_HEAD`
             // entries left on the log, then we need to advance the head of the log.
             let mut advance = false;
-            if tail + nops > head + self.size - GC_FROM_HEAD {
+            if tail + nops > head + self.size - Self::GC_FROM_HEAD {
                 advance = true
             };
 
@@ -429,7 +910,22 @@ where
                 continue;
             };
 
-            // Successfully reserved entries on the shared log. Add the operations in.
+            // Successfully reserved entries on the shared log. Persist the operations
+            // before any replica can execute them, so that no operation is acknowledged
+            // before it is durable.
+            // An empty batch shares its index with the next one and has nothing to write.
+            let timestamp = self.clock.as_ref().map(|c| c.now());
+            #[cfg(feature = "persistence")]
+            let persisted = match &self.segment {
+                Some(segment) if nops > 0 => segment(tail, ops, idx, threads, timestamp),
+                _ => Ok(()),
+            };
+            #[cfg(not(feature = "persistence"))]
+            let persisted = Ok(());
+
+            // Add the operations in.
+            let timestamp = pack_timestamp(timestamp);
+            let mut issuers = issuers(threads);
             for (i, op) in ops.iter().enumerate().take(nops) {
                 let e = self.slog[self.index(tail + i)].as_ptr();
                 let mut m = self.lmasks[idx - 1].get();
@@ -443,17 +939,37 @@ where
                     m = !m;
                 }
 
-                unsafe { (*e).operation = Some(op.clone()) };
-                unsafe { (*e).replica = idx };
+                let op = if persisted.is_ok() {
+                    Some(op.clone())
+                } else {
+                    None
+                };
+                unsafe { (*e).operation = op };
+                unsafe { (*e).replica = idx as u32 };
+                unsafe { (*e).thread = issuers.next().unwrap_or(0) as u32 };
+                unsafe { (*e).timestamp = timestamp };
                 unsafe { (*e).alivef.store(m, Ordering::Release) };
             }
 
+            // Hand the entries to the observer; it only runs on the append path if
+            // the ring is full.
+            if let Some((observer, ring)) = &self.observer {
+                for (i, op) in ops.iter().enumerate().take(nops) {
+                    let op = if persisted.is_ok() {
+                        Some(op.clone())
+                    } else {
+                        None
+                    };
+                    ring.push(tail + i, idx, op, wait, observer.as_ref());
+                }
+            }
+
             // If needed, advance the head of the log forward to make room on the log.
             if advance {
-                self.advance_head(idx, &mut s);
+                self.advance_head(idx, &mut s, wait, expired)?;
             }
 
-            return;
+            return persisted;
         }
     }
 
@@ -500,8 +1016,23 @@ where
     ///
     /// The passed in closure is expected to take in two arguments: The operation
     /// from the shared log to be executed and the replica that issued it.
-    #[inline(always)]
+    #[cfg(test)]
     pub(crate) fn exec<F: FnMut(T, usize)>(&self, idx: usize, d: &mut F) {
+        let mut d = |op: T, ctx: &WriteContext| d(op, ctx.replica);
+        self.exec_with(idx, &mut d, &Spin)
+            .unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    /// Same as `exec`, but waits for entries that were reserved but not yet
+    /// written according to `wait` instead of busy spinning, and passes the
+    /// context of every operation to `d`. Returns an error instead of panicking
+    /// if the replica's local tail is not within the log.
+    pub(crate) fn exec_with<F: FnMut(T, &WriteContext)>(
+        &self,
+        idx: usize,
+        d: &mut F,
+        wait: &dyn WaitStrategy,
+    ) -> Result<(), Error> {
         // Load the logical log offset from which we must execute operations.
         let ltail = self.ltails[idx - 1].load(Ordering::Relaxed);
 
@@ -509,14 +1040,14 @@ where
         // global tail. If they're equal, then we're done here and can simply return.
         let gtail = self.tail.load(Ordering::Relaxed);
         if ltail == gtail {
-            return;
+            return Ok(());
         }
 
         let h = self.head.load(Ordering::Relaxed);
 
-        // Make sure we're within the shared log. If we aren't, then panic.
+        // Make sure we're within the shared log. If we aren't, then bail out.
         if ltail > gtail || ltail < h {
-            panic!("Local tail not within the shared log!")
+            return Err(Error::LogOutOfRange);
         };
 
         // Execute all operations from the passed in offset to the shared log's tail. Check if
@@ -536,13 +1067,25 @@ where
                         self.lmasks[idx - 1].get()
                     );
                 }
+                wait.relax(iteration);
                 iteration += 1;
 
                 #[cfg(loom)]
                 loom::thread::yield_now();
             }
 
-            unsafe { d((*e).operation.as_ref().unwrap().clone(), (*e).replica) };
+            let ctx = unsafe {
+                WriteContext {
+                    pos: i,
+                    replica: (*e).replica as usize,
+                    thread: (*e).thread as usize,
+                    timestamp: unpack_timestamp((*e).timestamp),
+                }
+            };
+            // Entries of a batch that couldn't be persisted don't hold an operation.
+            if let Some(op) = unsafe { (*e).operation.as_ref() } {
+                d(op.clone(), &ctx);
+            }
 
             // Looks like we're going to wrap around now; flip this replica's local mask.
             if self.index(i) == self.size - 1 {
@@ -555,6 +1098,7 @@ where
         // Also update this replica's local tail.
         self.ctail.fetch_max(gtail, Ordering::Relaxed);
         self.ltails[idx - 1].store(gtail, Ordering::Relaxed);
+        Ok(())
     }
 
     /// Returns a physical index given a logical index into the shared log.
@@ -563,59 +1107,100 @@ where
         logical & (self.size - 1)
     }
 
+    /// Returns the alive mask a replica uses when its local tail is at logical
+    /// index `logical`. The mask starts out as true and flips every time a
+    /// replica wraps around the log.
+    #[inline(always)]
+    fn mask_for(&self, logical: usize) -> bool {
+        (logical / self.size) % 2 == 0
+    }
+
     /// Advances the head of the log forward. If a replica has stopped making progress,
-    /// then this method will never return. Accepts a closure that is passed into exec()
-    /// to ensure that this replica does not deadlock GC.
+    /// then this method only returns once `expired` does; the head isn't advanced
+    /// far enough then. Accepts a closure that is passed into exec() to ensure that
+    /// this replica does not deadlock GC.
     #[inline(always)]
-    fn advance_head<F: FnMut(T, usize)>(&self, rid: usize, mut s: &mut F) {
+    fn advance_head<F: FnMut(T, &WriteContext)>(
+        &self,
+        rid: usize,
+        mut s: &mut F,
+        wait: &dyn WaitStrategy,
+        expired: &dyn Fn() -> bool,
+    ) -> Result<(), Error> {
         // Keep looping until we can advance the head and create some free space
         // on the log. If one of the replicas has stopped making progress, then
-        // this method might never return.
+        // this method might only return at the deadline.
         let mut iteration = 1;
         loop {
-            let r = self.next.load(Ordering::Relaxed);
-            let global_head = self.head.load(Ordering::Relaxed);
             let f = self.tail.load(Ordering::Relaxed);
 
-            let mut min_local_tail = self.ltails[0].load(Ordering::Relaxed);
-
-            // Find the smallest local tail across all replicas.
-            for idx in 1..r {
-                let cur_local_tail = self.ltails[idx - 1].load(Ordering::Relaxed);
-                if min_local_tail > cur_local_tail {
-                    min_local_tail = cur_local_tail
-                };
-            }
-
             // If we cannot advance the head further, then start
             // from the beginning of this loop again. Before doing so, try consuming
-            // any new entries on the log to prevent deadlock.
-            if min_local_tail == global_head {
-                if iteration % WARN_THRESHOLD == 0 {
-                    warn!("Spending a long time in `advance_head`, are we starving?");
+            // any new entries on the log to prevent deadlock. A local tail behind the
+            // head belongs to a replica that is just re-using a released identifier.
+            let min_local_tail = match self.try_advance_head() {
+                Some(min_local_tail) => min_local_tail,
+                None => {
+                    if expired() {
+                        return Ok(());
+                    }
+                    #[cfg(feature = "stats")]
+                    if iteration == 1 {
+                        self.stats
+                            .advance_head_waits
+                            .fetch_add(1, Ordering::Relaxed);
+                    }
+                    if iteration % WARN_THRESHOLD == 0 {
+                        warn!("Spending a long time in `advance_head`, are we starving?");
+                    }
+                    self.exec_with(rid, &mut s, wait)?;
+                    wait.relax(iteration);
+                    iteration += 1;
+
+                    #[cfg(loom)]
+                    loom::thread::yield_now();
+                    continue;
                 }
-                iteration += 1;
-                self.exec(rid, &mut s);
-
-                #[cfg(loom)]
-                loom::thread::yield_now();
-                continue;
-            }
-
-            // There are entries that can be freed up; update the head offset.
-            self.head.store(min_local_tail, Ordering::Relaxed);
+            };
 
             // Make sure that we freed up enough space so that threads waiting for
             // GC in append can make progress. Otherwise, try to make progress again.
             // If we're making progress again, then try consuming entries on the log.
-            if f < min_local_tail + self.size - GC_FROM_HEAD {
-                return;
+            if f < min_local_tail + self.size - Self::GC_FROM_HEAD {
+                return Ok(());
             } else {
-                self.exec(rid, &mut s);
+                self.exec_with(rid, &mut s, wait)?;
             }
         }
     }
 
+    /// Moves the head of the log forward to the smallest local tail across all
+    /// registered replicas, if that frees up any entries. Returns the new head, or
+    /// `None` if nothing could be freed.
+    fn try_advance_head(&self) -> Option<usize> {
+        let r = self.next.load(Ordering::Relaxed);
+        let global_head = self.head.load(Ordering::Relaxed);
+        let f = self.tail.load(Ordering::Relaxed);
+
+        // Find the smallest local tail across all registered replicas. If every
+        // replica has been unregistered, then nothing holds back the head.
+        let min_local_tail = (1..r)
+            .filter(|idx| !self.lfree[idx - 1].load(Ordering::Relaxed))
+            .map(|idx| self.ltails[idx - 1].load(Ordering::Relaxed))
+            .min()
+            .unwrap_or(f);
+
+        if min_local_tail <= global_head {
+            return None;
+        }
+
+        // There are entries that can be freed up; update the head offset. Several
+        // threads might do this at once, so never move the head backwards.
+        self.head.fetch_max(min_local_tail, Ordering::Relaxed);
+        self.notify_lag.store(true, Ordering::Relaxed);
+        Some(min_local_tail)
+    }
+
     /// Resets the log. Required for microbenchmarking the log; with this method, we
     /// can re-use the log across experimental runs without having to re-allocate the
     /// log over and over again.
@@ -632,11 +1217,15 @@ where
         self.head.store(0, Ordering::SeqCst);
         self.tail.store(0, Ordering::SeqCst);
         self.next.store(1, Ordering::SeqCst);
+        if let Some((_observer, ring)) = &self.observer {
+            ring.reset(0);
+        }
 
         // Next, reset replica-local metadata.
-        for r in 0..MAX_REPLICAS_PER_LOG {
+        for r in 0..MAX_REPLICAS {
             self.ltails[r].store(0, Ordering::Relaxed);
             self.lmasks[r].set(true);
+            self.lfree[r].store(false, Ordering::Relaxed);
         }
 
         // Next, free up all log entries. Use pointers to avoid memcpy and speed up
@@ -714,9 +1303,45 @@ where
     pub(crate) fn get_ctail(&self) -> usize {
         self.ctail.load(Ordering::Relaxed)
     }
+
+    /// Returns the logical index up to which the replica `idx` has executed
+    /// operations from the shared log.
+    #[inline(always)]
+    pub(crate) fn get_ltail(&self, idx: usize) -> usize {
+        self.ltails[idx - 1].load(Ordering::Relaxed)
+    }
+
+    /// Returns a snapshot of the state of the log and of the replicas registered
+    /// with it, along with the number of times appending to the log had to wait
+    /// for garbage collection.
+    #[cfg(feature = "stats")]
+    pub fn stats(&self) -> LogStats {
+        let tail = self.tail.load(Ordering::Relaxed);
+        let replicas: Vec<ReplicaLag> = (1..self.next.load(Ordering::Relaxed))
+            .filter(|idx| !self.lfree[idx - 1].load(Ordering::Relaxed))
+            .map(|idx| {
+                let ltail = self.ltails[idx - 1].load(Ordering::Relaxed);
+                ReplicaLag {
+                    id: idx,
+                    ltail,
+                    lag: tail.saturating_sub(ltail),
+                }
+            })
+            .collect();
+
+        LogStats {
+            head: self.head.load(Ordering::Relaxed),
+            tail,
+            ctail: self.ctail.load(Ordering::Relaxed),
+            replicas,
+            append_gc_waits: self.stats.append_gc_waits.load(Ordering::Relaxed),
+            advance_head_waits: self.stats.advance_head_waits.load(Ordering::Relaxed),
+        }
+    }
 }
 
-impl<'a, T> Default for Log<'a, T>
+impl<'a, T, const MAX_REPLICAS: usize, const MAX_THREADS: usize, const MAX_PENDING: usize> Default
+    for Log<'a, T, MAX_REPLICAS, MAX_THREADS, MAX_PENDING>
 where
     T: Sized + Clone,
 {
@@ -726,14 +1351,15 @@ where
     }
 }
 
-impl<'a, T> Drop for Log<'a, T>
+impl<'a, T, const MAX_REPLICAS: usize, const MAX_THREADS: usize, const MAX_PENDING: usize> Drop
+    for Log<'a, T, MAX_REPLICAS, MAX_THREADS, MAX_PENDING>
 where
     T: Sized + Clone,
 {
     /// Destructor for the shared log.
     fn drop(&mut self) {
         unsafe {
-            dealloc(
+            self.placement.dealloc(
                 self.rawp,
                 Layout::from_size_align(self.rawb, align_of::<Cell<Entry<T>>>())
                     .expect("Alignment error while deallocating the shared log!"),
@@ -750,6 +1376,8 @@ mod tests {
     use super::*;
     use std::sync::Arc;
 
+    const GC_FROM_HEAD: usize = Log::<Operation>::GC_FROM_HEAD;
+
     // Define operations along with their arguments that go onto the log.
     #[derive(Clone)] // Traits required by the log interface.
     #[derive(Debug, PartialEq)] // Traits required for testing.
@@ -775,6 +1403,16 @@ mod tests {
         assert_eq!(e.alivef.load(Ordering::Relaxed), false);
     }
 
+    // Tests that timestamps survive being packed into an entry, and that a
+    // missing timestamp stays missing.
+    #[test]
+    fn test_pack_timestamp() {
+        let t = Duration::new(5, 7);
+        assert_eq!(unpack_timestamp(pack_timestamp(Some(t))), Some(t));
+        assert_eq!(unpack_timestamp(pack_timestamp(None)), None);
+        assert!(unpack_timestamp(pack_timestamp(Some(Duration::MAX))).is_some());
+    }
+
     // Test that our entry_size() method returns the correct size.
     #[test]
     fn test_log_entry_size() {
@@ -860,6 +1498,29 @@ mod tests {
         assert_eq!(l.next.load(Ordering::Relaxed), 2);
     }
 
+    // Tests that the identifier of an unregistered replica is handed out again,
+    // and that the new owner starts out at the beginning of the log or at the
+    // given index.
+    #[test]
+    fn test_log_register_reuse() {
+        let l = Log::<Operation>::new(1024);
+        assert_eq!(l.register(), Some(1));
+        assert_eq!(l.register(), Some(2));
+        assert_eq!(l.register(), Some(3));
+
+        l.unregister(2);
+        assert_eq!(l.register_at(l.size + 3), Some(2));
+        assert_eq!(l.ltails[1].load(Ordering::Relaxed), l.size + 3);
+        assert!(!l.lmasks[1].get());
+
+        l.unregister(2);
+        assert_eq!(l.register(), Some(2));
+        assert_eq!(l.ltails[1].load(Ordering::Relaxed), 0);
+        assert!(l.lmasks[1].get());
+        assert_eq!(l.register_at(0), Some(4));
+        assert_eq!(l.next.load(Ordering::Relaxed), 5);
+    }
+
     // Tests that we cannot register more than the max replicas with the log.
     #[test]
     fn test_log_register_none() {
@@ -869,6 +1530,70 @@ mod tests {
         assert_eq!(l.next.load(Ordering::Relaxed), MAX_REPLICAS_PER_LOG);
     }
 
+    // Tests that a log sized for fewer replicas and smaller batches than the
+    // default bounds registrations and garbage collection accordingly.
+    #[test]
+    fn test_log_custom_sizes() {
+        let l = Log::<Operation, 2, 4, 2>::new(1024);
+        assert_eq!(l.ltails.len(), 2);
+        assert_eq!(Log::<Operation, 2, 4, 2>::GC_FROM_HEAD, 8);
+        assert_eq!(l.size, 16);
+
+        assert_eq!(l.register(), Some(1));
+        assert!(l.register().is_none());
+    }
+
+    // Tests that a log rejects batch sizes that break the power of two layout.
+    #[test]
+    #[should_panic]
+    fn test_log_custom_sizes_not_power_of_two() {
+        let _l = Log::<Operation, 2, 3, 2>::new(1024);
+    }
+
+    // Tests that a log allocates and frees its memory through the placement it
+    // was constructed with.
+    #[test]
+    fn test_log_new_in() {
+        use crate::placement::{Global, Placement};
+        use std::sync::atomic::AtomicUsize;
+        use std::sync::Arc;
+
+        struct Counting(Arc<AtomicUsize>, Arc<AtomicUsize>);
+
+        unsafe impl Placement for Counting {
+            fn alloc(&self, layout: Layout) -> *mut u8 {
+                self.0.fetch_add(layout.size(), Ordering::SeqCst);
+                Global.alloc(layout)
+            }
+
+            unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+                self.1.fetch_add(layout.size(), Ordering::SeqCst);
+                Global.dealloc(ptr, layout)
+            }
+        }
+
+        let allocated = Arc::new(AtomicUsize::new(0));
+        let freed = Arc::new(AtomicUsize::new(0));
+        let l = Log::<Operation>::new_in(1024, Counting(allocated.clone(), freed.clone()));
+        assert_eq!(
+            allocated.load(Ordering::SeqCst),
+            l.size * Log::<Operation>::entry_size()
+        );
+        assert_eq!(freed.load(Ordering::SeqCst), 0);
+
+        let idx = l.register().unwrap();
+        l.append(&[Operation::Write(119)], idx, |_o: Operation, _i: usize| {});
+        let mut ops = 0;
+        l.exec(idx, &mut |_o: Operation, _i: usize| ops += 1);
+        assert_eq!(ops, 1);
+
+        drop(l);
+        assert_eq!(
+            freed.load(Ordering::SeqCst),
+            allocated.load(Ordering::SeqCst)
+        );
+    }
+
     // Test that we can correctly append an entry into the log.
     #[test]
     fn test_log_append() {
@@ -905,10 +1630,37 @@ mod tests {
         l.ltails[2].store(4096, Ordering::Relaxed);
         l.ltails[3].store(799, Ordering::Relaxed);
 
-        l.advance_head(0, &mut |_o: Operation, _i: usize| {});
+        l.advance_head(
+            0,
+            &mut |_o: Operation, _c: &WriteContext| {},
+            &Spin,
+            &|| false,
+        )
+        .unwrap();
         assert_eq!(l.head.load(Ordering::Relaxed), 224);
     }
 
+    // Tests that unregistered replicas do not hold back the head of the log.
+    #[test]
+    fn test_log_advance_head_unregistered() {
+        let l = Log::<Operation>::default();
+
+        l.next.store(4, Ordering::Relaxed);
+        l.ltails[0].store(1023, Ordering::Relaxed);
+        l.ltails[1].store(224, Ordering::Relaxed);
+        l.ltails[2].store(4096, Ordering::Relaxed);
+        l.unregister(2);
+
+        l.advance_head(
+            0,
+            &mut |_o: Operation, _c: &WriteContext| {},
+            &Spin,
+            &|| false,
+        )
+        .unwrap();
+        assert_eq!(l.head.load(Ordering::Relaxed), 1023);
+    }
+
     // Tests that the head of the log is advanced when we're close to filling up the entire log.
     #[test]
     fn test_log_append_gc() {
@@ -930,6 +1682,273 @@ mod tests {
         assert_eq!(l.tail.load(Ordering::Relaxed), l.size - GC_FROM_HEAD + 3);
     }
 
+    // Tests that the lag callback reports a replica that holds back GC once,
+    // and that it is armed again after the head advanced.
+    #[test]
+    fn test_log_lag_callback() {
+        use std::sync::Mutex;
+
+        let events = Arc::new(Mutex::new(std::vec::Vec::new()));
+        let mut l = Log::<Operation, 4, 4, 2>::new(1024);
+        assert_eq!(l.size, 16);
+        let e = events.clone();
+        l.update_lag_callback(move |event| e.lock().unwrap().push(event));
+
+        let one = l.register().unwrap();
+        let two = l.register().unwrap();
+        for _i in 0..8 {
+            l.append(&[Operation::Read], one, |_o: Operation, _i: usize| {});
+            l.exec(one, &mut |_o: Operation, _i: usize| {});
+        }
+        assert_eq!(
+            *events.lock().unwrap(),
+            [LagEvent {
+                log: l.id(),
+                replica: two,
+                lag: 6
+            }]
+        );
+        assert!(!l.notify_lag.load(Ordering::Relaxed));
+
+        l.unregister(two);
+        l.append(&[Operation::Read], one, |_o: Operation, _i: usize| {});
+        assert_eq!(l.head.load(Ordering::Relaxed), 8);
+        assert!(l.notify_lag.load(Ordering::Relaxed));
+        assert_eq!(events.lock().unwrap().len(), 1);
+    }
+
+    // Tests that appends give up at their deadline while a stalled replica holds
+    // back GC, and that the next append advances the head in their place.
+    #[test]
+    fn test_log_append_expired() {
+        let l = Log::<Operation, 4, 4, 2>::new(1024);
+        let one = l.register().unwrap();
+        let two = l.register().unwrap();
+        for _i in 0..8 {
+            l.append(&[Operation::Read], one, |_o: Operation, _i: usize| {});
+            l.exec(one, &mut |_o: Operation, _i: usize| {});
+        }
+
+        // The batch is on the log, but the head can't move past `two`.
+        let f = |_o: Operation, _c: &WriteContext| {};
+        let r = l.append_with(&[Operation::Read], &[], one, f, &Spin, &|| true);
+        assert_eq!(r, Ok(()));
+        assert_eq!(l.head.load(Ordering::Relaxed), 0);
+        assert_eq!(l.tail.load(Ordering::Relaxed), 9);
+
+        // There is no room for another batch.
+        let r = l.append_with(&[Operation::Read], &[], one, f, &Spin, &|| true);
+        assert_eq!(r, Err(Error::TimedOut));
+        assert_eq!(l.tail.load(Ordering::Relaxed), 9);
+
+        l.exec(two, &mut |_o: Operation, _i: usize| {});
+        l.append(&[Operation::Read], one, |_o: Operation, _i: usize| {});
+        assert_eq!(l.head.load(Ordering::Relaxed), 9);
+        assert_eq!(l.tail.load(Ordering::Relaxed), 10);
+    }
+
+    // Tests that the context passed to exec holds the position, the issuing
+    // replica and thread, and the timestamp of every entry.
+    #[test]
+    fn test_log_write_context() {
+        struct Fixed;
+        impl Clock for Fixed {
+            fn now(&self) -> Duration {
+                Duration::from_secs(5)
+            }
+        }
+
+        let mut l = Log::<Operation>::default();
+        let one = l.register().unwrap();
+        l.append(&[Operation::Read], one, |_o: Operation, _i: usize| {});
+        l.update_clock(Fixed);
+        let ops = [
+            Operation::Write(1),
+            Operation::Write(2),
+            Operation::Write(3),
+        ];
+        l.append_with(&ops, &[1, 0, 2], one, |_o, _c| {}, &Spin, &|| false)
+            .unwrap();
+
+        let mut seen = std::vec::Vec::new();
+        l.exec(one, &mut |_o: Operation, _i: usize| {});
+        l.ltails[0].store(0, Ordering::Relaxed);
+        l.exec_with(one, &mut |_o, c: &WriteContext| seen.push(*c), &Spin)
+            .unwrap();
+
+        let ctx = |pos, thread, timestamp| WriteContext {
+            pos,
+            replica: one,
+            thread,
+            timestamp,
+        };
+        let five = Some(Duration::from_secs(5));
+        assert_eq!(
+            seen,
+            [
+                ctx(0, 0, None),
+                ctx(1, 1, five),
+                ctx(2, 3, five),
+                ctx(3, 3, five)
+            ]
+        );
+    }
+
+    // Tests that every log gets its own identifier.
+    #[test]
+    fn test_log_id() {
+        let one = Log::<Operation>::new(1024);
+        let two = Log::<Operation>::new(1024);
+        assert_ne!(one.id(), two.id());
+    }
+
+    // Tests that the observer sees every entry exactly once and in order, across
+    // replicas and wrap-arounds of the log, and only when it is drained.
+    #[test]
+    fn test_log_observer() {
+        use std::sync::Mutex;
+
+        let seen = Arc::new(Mutex::new(std::vec::Vec::new()));
+        let mut l = Log::<Operation, 4, 4, 2>::new(1024);
+        let s = seen.clone();
+        l.update_observer(64, move |pos, replica, op: &Operation| {
+            s.lock().unwrap().push((pos, replica, op.clone()))
+        });
+
+        let one = l.register().unwrap();
+        let two = l.register().unwrap();
+        let mut expected = std::vec::Vec::new();
+        for i in 0..40 {
+            let idx = if i % 3 == 0 { two } else { one };
+            l.append(&[Operation::Write(i)], idx, |_o: Operation, _i: usize| {});
+            l.exec(one, &mut |_o: Operation, _i: usize| {});
+            l.exec(two, &mut |_o: Operation, _i: usize| {});
+            expected.push((i as usize, idx, Operation::Write(i)));
+        }
+
+        assert!(seen.lock().unwrap().is_empty());
+        assert_eq!(l.drain_observer(), 40);
+        assert_eq!(*seen.lock().unwrap(), expected);
+        assert_eq!(l.drain_observer(), 0);
+    }
+
+    // Tests that appends to a full observer ring keep going while another thread
+    // drains it.
+    #[test]
+    fn test_log_observer_full() {
+        use std::sync::atomic::AtomicBool;
+        use std::sync::Mutex;
+
+        let seen = Arc::new(Mutex::new(std::vec::Vec::new()));
+        let mut l = Log::<Operation, 4, 4, 2>::new(1024);
+        let s = seen.clone();
+        l.update_observer(4, move |pos, _replica, _op: &Operation| {
+            s.lock().unwrap().push(pos)
+        });
+        let l = Arc::new(l);
+
+        let one = l.register().unwrap();
+        for _i in 0..4 {
+            l.append(&[Operation::Read], one, |_o: Operation, _i: usize| {});
+            l.exec(one, &mut |_o: Operation, _i: usize| {});
+        }
+
+        let done = Arc::new(AtomicBool::new(false));
+        let (log, d) = (l.clone(), done.clone());
+        let drainer = std::thread::spawn(move || {
+            while !d.load(Ordering::Acquire) {
+                log.drain_observer();
+            }
+            log.drain_observer();
+        });
+        for _i in 4..40 {
+            l.append(&[Operation::Read], one, |_o: Operation, _i: usize| {});
+            l.exec(one, &mut |_o: Operation, _i: usize| {});
+        }
+        done.store(true, Ordering::Release);
+        drainer.join().unwrap();
+
+        assert_eq!(
+            *seen.lock().unwrap(),
+            (0..40).collect::<std::vec::Vec<usize>>()
+        );
+    }
+
+    // Tests that appending to a full observer ring that nobody drains passes the
+    // oldest entries to the observer to make room.
+    #[test]
+    fn test_log_observer_full_undrained() {
+        use std::sync::Mutex;
+
+        let seen = Arc::new(Mutex::new(std::vec::Vec::new()));
+        let mut l = Log::<Operation, 4, 4, 2>::new(1024);
+        let s = seen.clone();
+        l.update_observer(4, move |pos, _replica, _op: &Operation| {
+            s.lock().unwrap().push(pos)
+        });
+
+        let one = l.register().unwrap();
+        for _i in 0..40 {
+            l.append(&[Operation::Read], one, |_o: Operation, _i: usize| {});
+            l.exec(one, &mut |_o: Operation, _i: usize| {});
+        }
+
+        assert_eq!(
+            *seen.lock().unwrap(),
+            (0..36).collect::<std::vec::Vec<usize>>()
+        );
+        assert_eq!(l.drain_observer(), 4);
+        assert_eq!(seen.lock().unwrap().len(), 40);
+    }
+
+    // Tests that the stats of the log report the lag of every registered replica
+    // and count the times the head could not be advanced right away.
+    #[test]
+    #[cfg(feature = "stats")]
+    fn test_log_stats() {
+        let l = Log::<Operation>::default();
+        let o = [
+            Operation::Read,
+            Operation::Read,
+            Operation::Read,
+            Operation::Read,
+        ];
+        let one = l.register().unwrap();
+        let two = l.register().unwrap();
+        l.append(&o, one, |_o: Operation, _i: usize| {});
+
+        let stats = l.stats();
+        assert_eq!(stats.head, 0);
+        assert_eq!(stats.tail, 4);
+        assert_eq!(stats.append_gc_waits, 0);
+        assert_eq!(stats.advance_head_waits, 0);
+        assert_eq!(stats.replicas.len(), 2);
+        assert_eq!(stats.replicas[1].id, two);
+        assert_eq!(stats.replicas[1].lag, 4);
+
+        // The head is held back until `one` consumes its own entries.
+        l.unregister(two);
+        l.advance_head(
+            one,
+            &mut |_o: Operation, _c: &WriteContext| {},
+            &Spin,
+            &|| false,
+        )
+        .unwrap();
+
+        let stats = l.stats();
+        assert_eq!(stats.head, 4);
+        assert_eq!(stats.advance_head_waits, 1);
+        assert_eq!(
+            stats.replicas,
+            [ReplicaLag {
+                id: one,
+                ltail: 4,
+                lag: 0
+            }]
+        );
+    }
+
     // Tests that on log wrap around, the local mask stays
     // the same because entries have not been executed yet.
     #[test]
@@ -1082,6 +2101,24 @@ mod tests {
         l.exec(1, &mut f);
     }
 
+    // Tests that exec_with() reports a local tail outside of the log as an error.
+    #[test]
+    fn test_exec_with_out_of_range() {
+        let l = Log::<Operation>::default();
+        l.append(
+            &[Operation::Read, Operation::Read],
+            1,
+            |_o: Operation, _i: usize| {},
+        );
+        l.head.store(8192, Ordering::SeqCst);
+
+        let mut f = |_op: Operation, _c: &WriteContext| {
+            assert!(false);
+        };
+        assert_eq!(l.exec_with(1, &mut f, &Spin), Err(Error::LogOutOfRange));
+        assert_eq!(l.ltails[0].load(Ordering::Relaxed), 0);
+    }
+
     // Tests that operations are cloned when added to the log, and that
     // they are correctly dropped once overwritten.
     #[test]
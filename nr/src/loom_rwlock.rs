@@ -6,25 +6,29 @@
 
 #![cfg(loom)]
 
-pub struct RwLock<T>
+/// Kept for parity with the regular `RwLock`; loom's lock does not need to know
+/// the number of readers upfront.
+pub const MAX_READER_THREADS: usize = 192;
+
+pub struct RwLock<T, const MAX_READERS: usize = MAX_READER_THREADS>
 where
     T: Sized + Sync,
 {
     inner: loom::sync::RwLock<T>,
 }
 
-impl<T> Default for RwLock<T>
+impl<T, const MAX_READERS: usize> Default for RwLock<T, MAX_READERS>
 where
     T: Sized + Default + Sync,
 {
-    fn default() -> RwLock<T> {
+    fn default() -> RwLock<T, MAX_READERS> {
         RwLock {
             inner: loom::sync::RwLock::new(Default::default()),
         }
     }
 }
 
-impl<T> RwLock<T>
+impl<T, const MAX_READERS: usize> RwLock<T, MAX_READERS>
 where
     T: Sized + Sync,
 {
@@ -38,6 +42,14 @@ where
         self.inner.write().unwrap()
     }
 
+    pub fn write_with(
+        &self,
+        _n: usize,
+        _wait: &dyn crate::wait::WaitStrategy,
+    ) -> loom::sync::RwLockWriteGuard<'_, T> {
+        self.inner.write().unwrap()
+    }
+
     pub fn read(&self, _tid: usize) -> loom::sync::RwLockReadGuard<'_, T> {
         self.inner.read().unwrap()
     }
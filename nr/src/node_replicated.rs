@@ -0,0 +1,650 @@
+// Copyright © 2019-2020 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! A replicated data structure with one replica per NUMA node.
+//!
+//! [NodeReplicated](struct.NodeReplicated.html) does the setup every user of
+//! [Replica](../struct.Replica.html) otherwise repeats: it creates the shared
+//! log and one replica per NUMA node of a [Topology](struct.Topology.html),
+//! and registers each thread with the replica of the node it runs on. Threads
+//! don't deal with `ReplicaToken`s; their tokens are kept in thread-local
+//! storage.
+//!
+//! With the `numa` feature, the log is interleaved across the nodes and every
+//! replica is placed on its node (see the `placement` module).
+//!
+//! Replicas of nodes whose threads went idle are synced by the threads writing
+//! on the other nodes (see the `progress` module), so they don't block writes.
+
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::cell::RefCell;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::Mutex;
+
+use crate::context::MAX_PENDING_OPS;
+use crate::log::{Log, DEFAULT_LOG_BYTES, MAX_REPLICAS_PER_LOG};
+#[cfg(all(feature = "numa", target_os = "linux"))]
+use crate::placement::Numa;
+use crate::progress::Progress;
+use crate::replica::{Replica, ReplicaToken, MAX_THREADS_PER_REPLICA};
+use crate::{Dispatch, Error};
+
+/// Where the NUMA nodes of the machine are listed.
+const SYSFS_NODES: &str = "/sys/devices/system/node";
+
+/// The NUMA nodes of a machine and their CPUs.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Topology {
+    /// The identifiers of the nodes, as used by the operating system.
+    ids: Vec<usize>,
+
+    /// The CPUs of every node.
+    cpus: Vec<Vec<usize>>,
+}
+
+impl Topology {
+    /// Discovers the topology of this machine from sysfs. Falls back to a
+    /// single node with all CPUs if that fails, for example on systems other
+    /// than Linux.
+    pub fn detect() -> Topology {
+        Topology::from_sysfs(SYSFS_NODES).unwrap_or_else(|e| {
+            debug!("Can't read the NUMA topology ({}), assuming one node.", e);
+            let cpus = std::thread::available_parallelism().map_or(1, |n| n.get());
+            Topology::new(alloc::vec![(0..cpus).collect()])
+        })
+    }
+
+    /// Reads the topology from a directory laid out like
+    /// `/sys/devices/system/node`. Nodes without CPUs are left out.
+    pub fn from_sysfs<P: AsRef<Path>>(path: P) -> io::Result<Topology> {
+        let mut nodes = Vec::new();
+        for entry in fs::read_dir(path)? {
+            let entry = entry?;
+            let name = entry.file_name();
+            let id = match name
+                .to_str()
+                .and_then(|name| name.strip_prefix("node"))
+                .and_then(|id| id.parse::<usize>().ok())
+            {
+                Some(id) => id,
+                None => continue,
+            };
+
+            let list = fs::read_to_string(entry.path().join("cpulist"))?;
+            let cpus = parse_cpulist(list.trim()).ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    alloc::format!("invalid cpulist of node {}: {:?}", id, list),
+                )
+            })?;
+            if !cpus.is_empty() {
+                nodes.push((id, cpus));
+            }
+        }
+
+        if nodes.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                "no NUMA node with CPUs",
+            ));
+        }
+        nodes.sort_unstable();
+
+        Ok(Topology::with_ids(nodes))
+    }
+
+    /// Creates a topology with a node for every set of CPUs in `nodes`, for
+    /// example to use fewer replicas than the machine has nodes. The nodes are
+    /// numbered from zero; use `with_ids` to place them on particular nodes of
+    /// the machine.
+    pub fn new(nodes: Vec<Vec<usize>>) -> Topology {
+        Topology::with_ids(nodes.into_iter().enumerate().collect())
+    }
+
+    /// Creates a topology from pairs of a node identifier, as used by the
+    /// operating system, and the CPUs of that node.
+    pub fn with_ids(nodes: Vec<(usize, Vec<usize>)>) -> Topology {
+        assert!(!nodes.is_empty(), "Need at least one node.");
+        Topology {
+            ids: nodes.iter().map(|(id, _)| *id).collect(),
+            cpus: nodes.into_iter().map(|(_, cpus)| cpus).collect(),
+        }
+    }
+
+    /// Returns the number of nodes.
+    pub fn nodes(&self) -> usize {
+        self.cpus.len()
+    }
+
+    /// Returns the CPUs of the `node`th node.
+    pub fn cpus(&self, node: usize) -> &[usize] {
+        &self.cpus[node]
+    }
+
+    /// Returns the node `cpu` belongs to.
+    pub fn node_of(&self, cpu: usize) -> Option<usize> {
+        self.cpus.iter().position(|cpus| cpus.contains(&cpu))
+    }
+}
+
+/// Parses a list of CPUs like `0-3,8,10-11`.
+fn parse_cpulist(list: &str) -> Option<Vec<usize>> {
+    let mut cpus = Vec::new();
+    for range in list.split(',').filter(|range| !range.is_empty()) {
+        match range.split_once('-') {
+            Some((first, last)) => {
+                let (first, last): (usize, usize) = (first.parse().ok()?, last.parse().ok()?);
+                if first > last {
+                    return None;
+                }
+                cpus.extend(first..=last);
+            }
+            None => cpus.push(range.parse().ok()?),
+        }
+    }
+    Some(cpus)
+}
+
+/// Returns the CPU the calling thread runs on.
+#[cfg(target_os = "linux")]
+fn current_cpu() -> Option<usize> {
+    extern "C" {
+        fn sched_getcpu() -> i32;
+    }
+
+    let cpu = unsafe { sched_getcpu() };
+    if cpu >= 0 {
+        Some(cpu as usize)
+    } else {
+        None
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn current_cpu() -> Option<usize> {
+    None
+}
+
+/// Tokens of threads that exited or unregistered, with the node of their
+/// replica, waiting to be unregistered by their `NodeReplicated`.
+type Released = Mutex<Vec<(usize, ReplicaToken)>>;
+
+/// The registration of a thread with the replica of a `NodeReplicated`. Hands
+/// the token back when it is dropped, in particular when the thread exits.
+struct Registration {
+    /// Identifier of the log of the `NodeReplicated`.
+    log: usize,
+    node: usize,
+    idx: ReplicaToken,
+    released: Arc<Released>,
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        if let Ok(mut released) = self.released.lock() {
+            released.push((self.node, self.idx));
+        }
+    }
+}
+
+std::thread_local! {
+    /// The registrations of the calling thread for every `NodeReplicated` it
+    /// uses.
+    static TOKENS: RefCell<Vec<Registration>> = const { RefCell::new(Vec::new()) };
+}
+
+/// A data structure replicated on every NUMA node of a
+/// [Topology](struct.Topology.html).
+///
+/// A thread is registered with the replica of the node it runs on the first
+/// time it executes an operation. Threads that run on a CPU which isn't part of
+/// the topology are assigned to the nodes in turn. The registration is kept
+/// until the thread calls `unregister_thread` or exits; the slot of a thread
+/// that exited is handed to the next thread that registers.
+///
+/// # Example
+///
+/// ```
+/// use node_replication::Dispatch;
+/// use node_replication::NodeReplicated;
+///
+/// use std::sync::Arc;
+///
+/// #[derive(Default)]
+/// struct Data {
+///     junk: u64,
+/// }
+///
+/// impl Dispatch for Data {
+///     type ReadOperation = ();
+///     type WriteOperation = u64;
+///     type Response = u64;
+///
+///     fn dispatch(&self, _op: Self::ReadOperation) -> Self::Response {
+///         self.junk
+///     }
+///
+///     fn dispatch_mut(&mut self, op: Self::WriteOperation) -> Self::Response {
+///         self.junk += op;
+///         self.junk
+///     }
+/// }
+///
+/// let data = Arc::new(NodeReplicated::<Data>::new());
+///
+/// let threads: Vec<_> = (0..4)
+///     .map(|_| {
+///         let data = data.clone();
+///         std::thread::spawn(move || {
+///             data.execute_mut(1);
+///         })
+///     })
+///     .collect();
+/// for t in threads {
+///     t.join().unwrap();
+/// }
+///
+/// assert_eq!(data.execute(()), 4);
+/// ```
+pub struct NodeReplicated<
+    'a,
+    D,
+    const MAX_REPLICAS: usize = MAX_REPLICAS_PER_LOG,
+    const MAX_THREADS: usize = MAX_THREADS_PER_REPLICA,
+    const MAX_PENDING: usize = MAX_PENDING_OPS,
+> where
+    D: Sized + Dispatch + Sync,
+{
+    log: Arc<Log<'a, <D as Dispatch>::WriteOperation, MAX_REPLICAS, MAX_THREADS, MAX_PENDING>>,
+
+    /// One replica per node of `topology`.
+    replicas: Vec<Arc<Replica<'a, D, MAX_REPLICAS, MAX_THREADS, MAX_PENDING>>>,
+
+    topology: Topology,
+
+    /// The node the next thread that runs outside of `topology` is assigned to.
+    next: AtomicUsize,
+
+    /// Watches all replicas, and syncs the idle ones once a replica lags behind.
+    progress: Mutex<Progress<'a, D, MAX_REPLICAS, MAX_THREADS, MAX_PENDING>>,
+
+    /// Tokens that threads gave up but that aren't unregistered yet.
+    released: Arc<Released>,
+}
+
+impl<'a, D, const MAX_REPLICAS: usize, const MAX_THREADS: usize, const MAX_PENDING: usize>
+    NodeReplicated<'a, D, MAX_REPLICAS, MAX_THREADS, MAX_PENDING>
+where
+    D: Sized + Default + Dispatch + Sync,
+{
+    /// Replicates a default constructed data structure on every node of this
+    /// machine, see `Topology::detect`.
+    pub fn new() -> Self {
+        NodeReplicated::with_topology(Topology::detect())
+    }
+
+    /// Replicates a default constructed data structure on every node of
+    /// `topology`.
+    pub fn with_topology(topology: Topology) -> Self {
+        NodeReplicated::create(topology, DEFAULT_LOG_BYTES, D::default)
+    }
+}
+
+impl<'a, D, const MAX_REPLICAS: usize, const MAX_THREADS: usize, const MAX_PENDING: usize> Default
+    for NodeReplicated<'a, D, MAX_REPLICAS, MAX_THREADS, MAX_PENDING>
+where
+    D: Sized + Default + Dispatch + Sync,
+{
+    fn default() -> Self {
+        NodeReplicated::new()
+    }
+}
+
+impl<'a, D, const MAX_REPLICAS: usize, const MAX_THREADS: usize, const MAX_PENDING: usize>
+    NodeReplicated<'a, D, MAX_REPLICAS, MAX_THREADS, MAX_PENDING>
+where
+    D: Sized + Clone + Dispatch + Sync,
+{
+    /// Replicates `d` on every node of `topology`.
+    pub fn with_data(topology: Topology, d: D) -> Self {
+        NodeReplicated::create(topology, DEFAULT_LOG_BYTES, || d.clone())
+    }
+}
+
+impl<'a, D, const MAX_REPLICAS: usize, const MAX_THREADS: usize, const MAX_PENDING: usize>
+    NodeReplicated<'a, D, MAX_REPLICAS, MAX_THREADS, MAX_PENDING>
+where
+    D: Sized + Dispatch + Sync,
+{
+    /// Replicates the data structures returned by `data` on every node of
+    /// `topology`, with a log of `bytes` bytes.
+    fn create<F: FnMut() -> D>(topology: Topology, bytes: usize, mut data: F) -> Self {
+        assert!(
+            topology.nodes() <= MAX_REPLICAS,
+            "The log takes at most {} replicas, but there are {} nodes.",
+            MAX_REPLICAS,
+            topology.nodes()
+        );
+
+        #[cfg(all(feature = "numa", target_os = "linux"))]
+        let log = Arc::new(Log::new_in(bytes, Numa::interleave(&topology.ids)));
+        #[cfg(not(all(feature = "numa", target_os = "linux")))]
+        let log = Arc::new(Log::new(bytes));
+
+        #[cfg(all(feature = "numa", target_os = "linux"))]
+        let replica = |id: usize, d: D| Replica::with_data_in(&log, d, Numa::bind(id));
+        #[cfg(not(all(feature = "numa", target_os = "linux")))]
+        let replica = |_id: usize, d: D| Replica::with_data(&log, d);
+
+        let replicas: Vec<_> = topology.ids.iter().map(|&id| replica(id, data())).collect();
+
+        let mut progress = Progress::new();
+        for replica in replicas.iter() {
+            progress.watch(replica).unwrap_or_else(|e| panic!("{}", e));
+        }
+
+        NodeReplicated {
+            log,
+            replicas,
+            topology,
+            next: AtomicUsize::new(0),
+            progress: Mutex::new(progress),
+            released: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    /// Executes a write operation on the replica of the calling thread, and
+    /// returns its response.
+    ///
+    /// # Panics
+    /// If the calling thread isn't registered yet and the replica of its node
+    /// has no slots left, or for the same reasons as `Replica::execute_mut`.
+    pub fn execute_mut(&self, op: <D as Dispatch>::WriteOperation) -> <D as Dispatch>::Response {
+        let (node, idx) = self.token();
+        self.sync_idle();
+        self.replicas[node].execute_mut(op, idx)
+    }
+
+    /// Executes a read-only operation on the replica of the calling thread, and
+    /// returns its response.
+    ///
+    /// # Panics
+    /// If the calling thread isn't registered yet and the replica of its node
+    /// has no slots left, or for the same reasons as `Replica::execute`.
+    pub fn execute(&self, op: <D as Dispatch>::ReadOperation) -> <D as Dispatch>::Response {
+        let (node, idx) = self.token();
+        self.replicas[node].execute(op, idx)
+    }
+
+    /// Registers the calling thread with the replica of `node`, regardless of
+    /// where it runs. Moves the thread if it is registered with another
+    /// replica already.
+    pub fn register_on(&self, node: usize) -> Result<(), Error> {
+        assert!(node < self.replicas.len(), "Node {} doesn't exist.", node);
+        if self.registration() == Some(node) {
+            return Ok(());
+        }
+
+        self.unregister_thread();
+        let idx = self.replicas[node].try_register()?;
+        TOKENS.with(|tokens| {
+            tokens.borrow_mut().push(Registration {
+                log: self.log.id(),
+                node,
+                idx,
+                released: self.released.clone(),
+            })
+        });
+        Ok(())
+    }
+
+    /// Unregisters the calling thread from its replica, so that the replica can
+    /// hand its slot to another thread. Does nothing if the thread isn't
+    /// registered.
+    pub fn unregister_thread(&self) {
+        let id = self.log.id();
+        let registration = TOKENS.with(|tokens| {
+            let mut tokens = tokens.borrow_mut();
+            let i = tokens.iter().position(|r| r.log == id)?;
+            Some(tokens.swap_remove(i))
+        });
+
+        drop(registration);
+        self.release();
+    }
+
+    /// Unregisters the tokens of threads that unregistered or exited.
+    fn release(&self) {
+        let released = core::mem::take(&mut *self.released.lock().unwrap());
+        for (node, idx) in released {
+            self.replicas[node].unregister(idx);
+        }
+    }
+
+    /// Syncs the replicas that didn't make progress since it was last done, if a
+    /// replica lags behind on the log. Otherwise, the writes on the other
+    /// replicas eventually wait for idle replicas to execute their operations.
+    fn sync_idle(&self) {
+        if self.log.is_lagging() {
+            if let Ok(mut progress) = self.progress.try_lock() {
+                progress.poll();
+            }
+        }
+    }
+
+    /// Returns the node of the replica the calling thread is registered with.
+    pub fn registration(&self) -> Option<usize> {
+        self.lookup().map(|(node, _)| node)
+    }
+
+    /// Returns the topology the data structure is replicated on.
+    pub fn topology(&self) -> &Topology {
+        &self.topology
+    }
+
+    /// Returns the replicas, indexed by node.
+    pub fn replicas(&self) -> &[Arc<Replica<'a, D, MAX_REPLICAS, MAX_THREADS, MAX_PENDING>>] {
+        &self.replicas
+    }
+
+    /// Returns the node and token of the calling thread.
+    fn lookup(&self) -> Option<(usize, ReplicaToken)> {
+        let id = self.log.id();
+        TOKENS.with(|tokens| {
+            tokens
+                .borrow()
+                .iter()
+                .find(|r| r.log == id)
+                .map(|r| (r.node, r.idx))
+        })
+    }
+
+    /// Returns the node and token of the calling thread, and registers it with
+    /// the replica of its node first if necessary.
+    fn token(&self) -> (usize, ReplicaToken) {
+        if let Some(token) = self.lookup() {
+            return token;
+        }
+
+        let node = current_cpu()
+            .and_then(|cpu| self.topology.node_of(cpu))
+            .unwrap_or_else(|| self.next.fetch_add(1, Ordering::Relaxed) % self.replicas.len());
+        self.register_on(node).unwrap_or_else(|e| panic!("{}", e));
+        self.lookup().unwrap()
+    }
+}
+
+impl<'a, D, const MAX_REPLICAS: usize, const MAX_THREADS: usize, const MAX_PENDING: usize> Drop
+    for NodeReplicated<'a, D, MAX_REPLICAS, MAX_THREADS, MAX_PENDING>
+where
+    D: Sized + Dispatch + Sync,
+{
+    fn drop(&mut self) {
+        // Registrations of other threads stay behind; logs never reuse an
+        // identifier, and the tokens they release are ignored.
+        let id = self.log.id();
+        let _ = TOKENS.try_with(|tokens| tokens.borrow_mut().retain(|r| r.log != id));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate std;
+
+    use super::*;
+    use std::thread;
+    use std::{format, vec};
+
+    #[derive(Default, Clone)]
+    struct Data {
+        junk: u64,
+    }
+
+    impl Dispatch for Data {
+        type ReadOperation = ();
+        type WriteOperation = u64;
+        type Response = u64;
+
+        fn dispatch(&self, _op: Self::ReadOperation) -> Self::Response {
+            self.junk
+        }
+
+        fn dispatch_mut(&mut self, op: Self::WriteOperation) -> Self::Response {
+            self.junk += op;
+            self.junk
+        }
+    }
+
+    // Tests parsing the cpulists of sysfs.
+    #[test]
+    fn test_parse_cpulist() {
+        assert_eq!(parse_cpulist(""), Some(vec![]));
+        assert_eq!(parse_cpulist("3"), Some(vec![3]));
+        assert_eq!(
+            parse_cpulist("0-3,8,10-11"),
+            Some(vec![0, 1, 2, 3, 8, 10, 11])
+        );
+        assert_eq!(parse_cpulist("3-1"), None);
+        assert_eq!(parse_cpulist("0-a"), None);
+    }
+
+    // Tests reading a topology from a directory laid out like sysfs.
+    #[test]
+    fn test_topology_from_sysfs() {
+        let dir = std::env::temp_dir().join(format!("nr-topology-{}", std::process::id()));
+        for (node, cpus) in [("node2", "4-7\n"), ("node0", "0-3\n"), ("node1", "\n")].iter() {
+            fs::create_dir_all(dir.join(node)).unwrap();
+            fs::write(dir.join(node).join("cpulist"), cpus).unwrap();
+        }
+        fs::create_dir_all(dir.join("power")).unwrap();
+
+        let topology = Topology::from_sysfs(&dir).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(topology.nodes(), 2);
+        assert_eq!(topology.ids, vec![0, 2]);
+        assert_eq!(topology.cpus(1), &[4, 5, 6, 7]);
+        assert_eq!(topology.node_of(5), Some(1));
+        assert_eq!(topology.node_of(8), None);
+        assert!(Topology::from_sysfs(&dir).is_err());
+    }
+
+    // Tests that a topology given by hand keeps the identifiers of its nodes.
+    #[test]
+    fn test_topology_with_ids() {
+        let topology = Topology::with_ids(vec![(3, vec![1, 2]), (1, vec![0])]);
+        assert_eq!(topology.nodes(), 2);
+        assert_eq!(topology.ids, vec![3, 1]);
+        assert_eq!(topology.node_of(0), Some(1));
+
+        let topology = Topology::new(vec![vec![0], vec![1]]);
+        assert_eq!(topology.ids, vec![0, 1]);
+    }
+
+    // Tests that threads are routed to all replicas, and that the replicas
+    // agree with each other.
+    #[test]
+    fn test_node_replicated_execute() {
+        let nr = NodeReplicated::<Data>::with_topology(Topology::new(vec![vec![], vec![]]));
+
+        thread::scope(|s| {
+            for _i in 0..4 {
+                s.spawn(|| {
+                    for _j in 0..100 {
+                        nr.execute_mut(1);
+                    }
+                });
+            }
+        });
+
+        // No CPU is part of the topology, so threads alternate between nodes.
+        assert_eq!(nr.next.load(Ordering::Relaxed), 4);
+        for node in 0..2 {
+            nr.register_on(node).unwrap();
+            assert_eq!(nr.registration(), Some(node));
+            assert_eq!(nr.execute(()), 400);
+        }
+    }
+
+    // Tests that threads unregister from their replica and can move.
+    #[test]
+    fn test_node_replicated_register() {
+        let nr = NodeReplicated::<Data>::with_data(
+            Topology::new(vec![vec![], vec![]]),
+            Data { junk: 7 },
+        );
+        assert_eq!(nr.registration(), None);
+
+        // The first slot of every replica belongs to `progress`.
+        nr.register_on(1).unwrap();
+        assert_eq!(nr.execute_mut(1), 8);
+        nr.register_on(0).unwrap();
+        assert_eq!(nr.execute(()), 8);
+        assert_eq!(nr.replicas()[1].register().map(|idx| idx.id()), Some(2));
+
+        nr.unregister_thread();
+        assert_eq!(nr.registration(), None);
+        assert_eq!(nr.replicas()[0].register().map(|idx| idx.id()), Some(2));
+    }
+
+    // Tests that a single thread keeps writing while the replica of the other
+    // node is idle, even once the log wrapped around several times.
+    #[test]
+    fn test_node_replicated_idle_node() {
+        let nr = NodeReplicated::<Data>::create(
+            Topology::new(vec![vec![], vec![]]),
+            1024 * 1024,
+            Data::default,
+        );
+
+        for _i in 0..100_000 {
+            nr.execute_mut(1);
+        }
+        assert_eq!(nr.registration(), Some(0));
+
+        nr.register_on(1).unwrap();
+        assert_eq!(nr.execute(()), 100_000);
+    }
+
+    // Tests that threads hand their slots back when they exit.
+    #[test]
+    fn test_node_replicated_thread_exit() {
+        let nr = Arc::new(
+            NodeReplicated::<Data, MAX_REPLICAS_PER_LOG, 2>::with_topology(Topology::new(vec![
+                vec![],
+            ])),
+        );
+
+        // One slot is left next to the one of `progress`.
+        for _i in 0..4 {
+            let nr = nr.clone();
+            thread::spawn(move || nr.execute_mut(1)).join().unwrap();
+        }
+        assert_eq!(nr.execute(()), 4);
+    }
+}
@@ -0,0 +1,224 @@
+// Copyright © 2019-2020 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! A bounded ring that hands entries of the log to an observer.
+//!
+//! Appending threads copy every entry they write to the log into the slot of
+//! the ring that belongs to its logical index. The observer takes the entries
+//! out of the ring in log order, on whichever thread calls `Ring::drain`. It only
+//! runs on the append path of a combiner if the ring is full.
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::cell::UnsafeCell;
+
+#[cfg(not(loom))]
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+#[cfg(loom)]
+use loom::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use crossbeam_utils::CachePadded;
+
+use crate::wait::WaitStrategy;
+
+/// Threshold after how many iterations we log a warning for waiting on a full
+/// ring that another thread is draining. Should be a power of two to avoid
+/// divisions.
+const WARN_THRESHOLD: usize = 1 << 28;
+
+/// A slot of the ring.
+struct Slot<T> {
+    /// Logical index of the entry the slot can accept next if it is empty, or
+    /// that index plus one once the entry was copied in.
+    seq: AtomicUsize,
+
+    /// Identifier of the replica that appended the entry and its operation; the
+    /// operation is `None` for an entry that isn't passed to the observer.
+    entry: UnsafeCell<Option<(usize, Option<T>)>>,
+}
+
+/// Bounded ring of log entries waiting to be passed to an observer.
+pub(crate) struct Ring<T> {
+    slots: Box<[Slot<T>]>,
+
+    /// Logical index of the next entry to take out of the ring.
+    cursor: CachePadded<AtomicUsize>,
+
+    /// Held by the thread currently passing entries to the observer.
+    lock: AtomicBool,
+}
+
+// Slots are only accessed by the thread that owns them according to `seq`.
+unsafe impl<T: Send> Send for Ring<T> {}
+unsafe impl<T: Send> Sync for Ring<T> {}
+
+impl<T> Ring<T> {
+    /// Creates a ring of at least `capacity` slots whose first entry is the one at
+    /// logical index `start`.
+    pub(crate) fn new(capacity: usize, start: usize) -> Ring<T> {
+        let capacity = capacity.max(1).next_power_of_two();
+        let slots: Vec<Slot<T>> = (0..capacity)
+            .map(|_i| Slot {
+                seq: AtomicUsize::new(0),
+                entry: UnsafeCell::new(None),
+            })
+            .collect();
+        let ring = Ring {
+            slots: slots.into_boxed_slice(),
+            cursor: CachePadded::new(AtomicUsize::new(start)),
+            lock: AtomicBool::new(false),
+        };
+        ring.reset(start);
+        ring
+    }
+
+    /// Empties the ring and moves it to logical index `start`. Must not race with
+    /// `push` or `drain`.
+    pub(crate) fn reset(&self, start: usize) {
+        let mask = self.slots.len() - 1;
+        for (i, slot) in self.slots.iter().enumerate() {
+            // The first index at or after `start` that maps to this slot.
+            let pos = start + (i.wrapping_sub(start) & mask);
+            slot.seq.store(pos, Ordering::Relaxed);
+            unsafe { *slot.entry.get() = None };
+        }
+        self.cursor.store(start, Ordering::SeqCst);
+    }
+
+    /// Copies the entry at logical index `pos` into the ring. While the ring is
+    /// full, drains it by passing entries to `observer`, or waits with `wait` if
+    /// another thread is draining it, until the entry `capacity` indices before
+    /// is taken out of the ring.
+    ///
+    /// Must be called exactly once for every logical index from `start` onwards.
+    pub(crate) fn push(
+        &self,
+        pos: usize,
+        replica: usize,
+        op: Option<T>,
+        wait: &dyn WaitStrategy,
+        observer: &dyn Fn(usize, usize, &T),
+    ) {
+        let slot = &self.slots[pos & (self.slots.len() - 1)];
+
+        let mut iteration = 1;
+        while slot.seq.load(Ordering::Acquire) != pos {
+            if self.drain(observer) > 0 {
+                continue;
+            }
+
+            if iteration % WARN_THRESHOLD == 0 {
+                warn!("Spending a long time waiting for the observer to drain.");
+            }
+            wait.relax(iteration);
+            iteration += 1;
+
+            #[cfg(loom)]
+            loom::thread::yield_now();
+        }
+
+        unsafe { *slot.entry.get() = Some((replica, op)) };
+        slot.seq.store(pos + 1, Ordering::Release);
+    }
+
+    /// Passes the entries in the ring to `observer` in log order. Stops at the
+    /// first entry that wasn't copied into the ring yet.
+    ///
+    /// Returns the number of entries taken out of the ring. Returns zero without
+    /// waiting if another thread is draining the ring at the same time.
+    pub(crate) fn drain(&self, observer: &dyn Fn(usize, usize, &T)) -> usize {
+        if self
+            .lock
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            return 0;
+        }
+
+        let capacity = self.slots.len();
+        let start = self.cursor.load(Ordering::Relaxed);
+        let mut pos = start;
+        loop {
+            let slot = &self.slots[pos & (capacity - 1)];
+            if slot.seq.load(Ordering::Acquire) != pos + 1 {
+                break;
+            }
+
+            let entry = unsafe { (*slot.entry.get()).take() };
+            slot.seq.store(pos + capacity, Ordering::Release);
+            if let Some((replica, Some(op))) = entry {
+                observer(pos, replica, &op);
+            }
+            pos += 1;
+        }
+
+        self.cursor.store(pos, Ordering::Release);
+        self.lock.store(false, Ordering::Release);
+        pos - start
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wait::Spin;
+    use std::sync::{Arc, Mutex};
+    use std::vec;
+
+    // Tests that entries pushed out of order are passed on in log order, and that
+    // the ring only drains up to the first missing entry.
+    #[test]
+    fn test_ring_order() {
+        let ring = Ring::<u64>::new(3, 5);
+        let seen = Mutex::new(std::vec::Vec::new());
+        let observe =
+            |pos: usize, replica: usize, op: &u64| seen.lock().unwrap().push((pos, replica, *op));
+
+        ring.push(6, 2, Some(60), &Spin, &observe);
+        assert_eq!(ring.drain(&observe), 0);
+        ring.push(5, 1, Some(50), &Spin, &observe);
+        ring.push(7, 1, None, &Spin, &observe);
+        assert_eq!(ring.drain(&observe), 3);
+        assert_eq!(*seen.lock().unwrap(), vec![(5, 1, 50), (6, 2, 60)]);
+    }
+
+    // Tests that pushing into a full ring drains it to make room.
+    #[test]
+    fn test_ring_full() {
+        let ring = Ring::<u64>::new(2, 0);
+        let seen = Mutex::new(std::vec::Vec::new());
+        let observe = |pos: usize, _replica: usize, _op: &u64| seen.lock().unwrap().push(pos);
+
+        ring.push(0, 1, Some(0), &Spin, &observe);
+        ring.push(1, 1, Some(1), &Spin, &observe);
+        assert!(seen.lock().unwrap().is_empty());
+        ring.push(2, 1, Some(2), &Spin, &observe);
+        assert_eq!(*seen.lock().unwrap(), vec![0, 1]);
+        assert_eq!(ring.drain(&observe), 1);
+        assert_eq!(*seen.lock().unwrap(), vec![0, 1, 2]);
+    }
+
+    // Tests that pushing into a full ring waits while another thread drains it.
+    #[test]
+    fn test_ring_full_draining() {
+        let ring = Arc::new(Ring::<u64>::new(2, 0));
+        let ignore = |_pos: usize, _replica: usize, _op: &u64| {};
+        ring.push(0, 1, Some(0), &Spin, &ignore);
+        ring.push(1, 1, Some(1), &Spin, &ignore);
+
+        // Hold the lock as if another thread was draining the ring.
+        ring.lock.store(true, Ordering::Release);
+        let pushed = Arc::new(AtomicBool::new(false));
+        let (r, p) = (ring.clone(), pushed.clone());
+        let pusher = std::thread::spawn(move || {
+            r.push(2, 1, Some(2), &Spin, &ignore);
+            p.store(true, Ordering::Release);
+        });
+        std::thread::sleep(std::time::Duration::from_millis(10));
+        assert!(!pushed.load(Ordering::Acquire));
+
+        ring.lock.store(false, Ordering::Release);
+        pusher.join().unwrap();
+        assert_eq!(ring.cursor.load(Ordering::Relaxed), 2);
+    }
+}
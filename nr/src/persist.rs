@@ -0,0 +1,1052 @@
+// Copyright © 2019-2020 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Durable write-ahead persistence for the shared log.
+//!
+//! A [Log](../struct.Log.html) created with `Log::with_segment` writes every batch
+//! of operations that is appended to it into a segment file. After a restart,
+//! [recover](fn.recover.html) reads the segment back, replays it into fresh
+//! replicas and returns a log that continues appending to the same segment.
+//!
+//! A segment is a sequence of records. Every record starts with a header holding
+//! the logical log index of the operation, the length of the encoded operation,
+//! a checksum and the rest of the operation's
+//! [WriteContext](../struct.WriteContext.html), followed by the operation as
+//! produced by [Persistent::encode](trait.Persistent.html#tymethod.encode). Batches are
+//! written in log order, before any replica can execute their operations. A
+//! record that was only partially written (for example, because the process
+//! crashed) fails the checksum and ends the segment.
+//!
+//! If writing a batch fails, its operations are dropped from the log and the
+//! append fails with `Error::SegmentFailed`, as does every append after it.
+//!
+//! Operations replayed from a segment are passed the same `WriteContext` as when
+//! they were first executed: their position on the log, the replica and thread
+//! that issued them and their timestamp.
+//!
+//! To bound the time it takes to recover, `Replica::checkpoint` writes the state of
+//! a replica together with its position on the log. [restore](fn.restore.html)
+//! starts from such a checkpoint and only replays the operations in the segment
+//! that were appended after it was taken. It also drops the operations before the
+//! checkpoint from the segment, so the segment can't be recovered without it from
+//! then on.
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::convert::TryInto;
+use core::ops::Range;
+use core::time::Duration;
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex};
+
+use crate::log::{issuers, pack_timestamp, unpack_timestamp};
+use crate::{Dispatch, Error, Log, Replica, ReplicaToken, WriteContext};
+
+/// Identifies a checkpoint written by `Replica::checkpoint`.
+const CHECKPOINT_MAGIC: [u8; 8] = *b"NRCKPT01";
+
+/// Size of the header in front of every record in a segment: the logical log
+/// index (8 bytes), the length of the encoded operation (4 bytes), a checksum over
+/// the rest of the record (4 bytes), the issuing replica and thread (4 bytes
+/// each) and the packed timestamp (8 bytes).
+const RECORD_HEADER: usize = 32;
+
+/// Offset of the part of a record that is covered by its checksum.
+const RECORD_CHECKED: usize = 16;
+
+/// Types that can be written to and read back from persistent storage.
+///
+/// Implemented by the `WriteOperation` of a data structure whose shared log
+/// should be persisted.
+pub trait Persistent: Sized {
+    /// Appends an encoded representation of `self` to `buf`.
+    fn encode(&self, buf: &mut Vec<u8>);
+
+    /// Reconstructs a value from the bytes produced by `encode`. Returns `None`
+    /// if `buf` does not hold a valid encoding.
+    fn decode(buf: &[u8]) -> Option<Self>;
+}
+
+macro_rules! persistent_int {
+    ($($t:ty),*) => {
+        $(
+            impl Persistent for $t {
+                fn encode(&self, buf: &mut Vec<u8>) {
+                    buf.extend_from_slice(&self.to_le_bytes());
+                }
+
+                fn decode(buf: &[u8]) -> Option<Self> {
+                    Some(<$t>::from_le_bytes(buf.try_into().ok()?))
+                }
+            }
+        )*
+    };
+}
+
+persistent_int!(u8, u16, u32, u64, usize, i8, i16, i32, i64, isize);
+
+/// Determines when writes to a segment are flushed to stable storage.
+///
+/// Every batch is written to the segment file before any replica executes its
+/// operations, which is enough to survive a crash of the process. The policy
+/// decides how often the file is additionally `fsync`ed to survive a crash of the
+/// machine.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum FsyncPolicy {
+    /// Never fsync the segment; leave it to the operating system.
+    Never,
+
+    /// Fsync the segment after every appended batch. An operation is durable
+    /// before any replica executes it, and so by the time its response is returned.
+    Always,
+
+    /// Fsync the segment after every `n` appended batches.
+    Every(usize),
+}
+
+/// An open segment file that batches appended to a log are written to.
+struct Segment {
+    /// The segment file, opened in append mode.
+    file: File,
+
+    /// When to fsync `file`.
+    policy: FsyncPolicy,
+
+    /// The number of batches written since the last fsync.
+    unsynced: usize,
+
+    /// Staging buffer for the records of a batch, so that every batch is
+    /// written to the file with a single call.
+    buffer: Vec<u8>,
+
+    /// The logical log index of the next batch to write.
+    next: usize,
+
+    /// Set once a write failed; the segment doesn't take any more batches.
+    failed: bool,
+}
+
+impl Segment {
+    /// Opens the segment at `path` for appending, creating it if it doesn't exist.
+    fn open(path: &Path, policy: FsyncPolicy) -> io::Result<Segment> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+
+        Ok(Segment {
+            file,
+            policy,
+            unsynced: 0,
+            buffer: Vec::new(),
+            next: 0,
+            failed: false,
+        })
+    }
+
+    /// Writes a batch of operations starting at logical log index `start`, which
+    /// `replica` appended at `timestamp`. `threads` holds the number of operations
+    /// every thread of the replica issued, as passed to `Log::append_with`.
+    fn write<T: Persistent>(
+        &mut self,
+        start: usize,
+        ops: &[T],
+        replica: usize,
+        threads: &[usize],
+        timestamp: Option<Duration>,
+    ) -> io::Result<()> {
+        self.buffer.clear();
+        let timestamp = pack_timestamp(timestamp);
+        for ((i, op), thread) in ops.iter().enumerate().zip(issuers(threads)) {
+            let header = self.buffer.len();
+            self.buffer.extend_from_slice(&[0u8; RECORD_HEADER]);
+            op.encode(&mut self.buffer);
+
+            let len = (self.buffer.len() - header - RECORD_HEADER) as u32;
+            let h = &mut self.buffer[header..header + RECORD_HEADER];
+            h[0..8].copy_from_slice(&((start + i) as u64).to_le_bytes());
+            h[8..12].copy_from_slice(&len.to_le_bytes());
+            h[16..20].copy_from_slice(&(replica as u32).to_le_bytes());
+            h[20..24].copy_from_slice(&(thread as u32).to_le_bytes());
+            h[24..32].copy_from_slice(&timestamp.to_le_bytes());
+
+            let sum = checksum(&self.buffer[header + RECORD_CHECKED..]);
+            self.buffer[header + 12..header + 16].copy_from_slice(&sum.to_le_bytes());
+        }
+
+        self.file.write_all(&self.buffer)?;
+        self.unsynced += 1;
+
+        let sync = match self.policy {
+            FsyncPolicy::Never => false,
+            FsyncPolicy::Always => true,
+            FsyncPolicy::Every(n) => self.unsynced >= n,
+        };
+        if sync {
+            self.file.sync_data()?;
+            self.unsynced = 0;
+        }
+
+        Ok(())
+    }
+}
+
+/// FNV-1a hash over `bytes`; detects records that were only partially written.
+pub(crate) fn checksum(bytes: &[u8]) -> u32 {
+    bytes.iter().fold(0x811c_9dc5u32, |h, b| {
+        (h ^ *b as u32).wrapping_mul(0x0100_0193)
+    })
+}
+
+/// Reads the contents of the segment at `path` and returns the byte ranges of all
+/// complete records in it, sorted by their logical log index. A missing segment
+/// is treated like an empty one.
+fn read_records(path: &Path, contents: &mut Vec<u8>) -> io::Result<Vec<(usize, Range<usize>)>> {
+    match File::open(path) {
+        Ok(mut file) => {
+            file.read_to_end(contents)?;
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e),
+    }
+
+    let mut records = Vec::new();
+    let mut offset = 0;
+    while contents.len() - offset >= RECORD_HEADER {
+        let header = &contents[offset..offset + RECORD_HEADER];
+        let pos = u64::from_le_bytes(header[0..8].try_into().unwrap()) as usize;
+        let len = u32::from_le_bytes(header[8..12].try_into().unwrap()) as usize;
+        let sum = u32::from_le_bytes(header[12..16].try_into().unwrap());
+
+        let payload = offset + RECORD_HEADER..offset + RECORD_HEADER + len;
+        if payload.end > contents.len()
+            || checksum(&contents[offset + RECORD_CHECKED..payload.end]) != sum
+        {
+            warn!(
+                "Ignoring a partially written record at offset {} in the segment",
+                offset
+            );
+            break;
+        }
+
+        records.push((pos, offset..payload.end));
+        offset = payload.end;
+    }
+
+    records.sort_by_key(|(pos, _r)| *pos);
+    Ok(records)
+}
+
+/// Returns the context the operation of the record at `record` in `contents` was
+/// executed with when it was appended to the log at logical index `pos`.
+fn record_context(pos: usize, contents: &[u8], record: &Range<usize>) -> WriteContext {
+    let header = &contents[record.start..record.start + RECORD_HEADER];
+    WriteContext {
+        pos,
+        replica: u32::from_le_bytes(header[16..20].try_into().unwrap()) as usize,
+        thread: u32::from_le_bytes(header[20..24].try_into().unwrap()) as usize,
+        timestamp: unpack_timestamp(u64::from_le_bytes(header[24..32].try_into().unwrap())),
+    }
+}
+
+/// Cuts a partially written record off the end of the segment at `path`, so that
+/// records appended from now on can be read back. `end` is the offset right
+/// after the last complete record.
+fn truncate_torn(path: &Path, contents: &[u8], end: usize) -> io::Result<()> {
+    if end == contents.len() {
+        return Ok(());
+    }
+
+    let file = OpenOptions::new().write(true).open(path)?;
+    file.set_len(end as u64)?;
+    file.sync_all()
+}
+
+/// Replaces the segment at `path` with one that only holds `records`, the byte
+/// ranges of records in `contents`. The records are written to a new file that is
+/// then renamed over the segment, so that a crash leaves either the old or the new
+/// segment behind.
+fn compact(path: &Path, contents: &[u8], records: &[(usize, Range<usize>)]) -> io::Result<()> {
+    let mut tmp = path.as_os_str().to_os_string();
+    tmp.push(".compact");
+    let tmp = PathBuf::from(tmp);
+
+    let mut file = File::create(&tmp)?;
+    for (_pos, r) in records {
+        file.write_all(&contents[r.clone()])?;
+    }
+    file.sync_all()?;
+    drop(file);
+    fs::rename(&tmp, path)?;
+
+    // Make sure the rename itself survives a crash of the machine.
+    #[cfg(unix)]
+    {
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        File::open(dir)?.sync_all()?;
+    }
+
+    Ok(())
+}
+
+impl<'a, T, const MAX_REPLICAS: usize, const MAX_THREADS: usize, const MAX_PENDING: usize>
+    Log<'a, T, MAX_REPLICAS, MAX_THREADS, MAX_PENDING>
+where
+    T: Sized + Clone + Persistent + 'a,
+{
+    /// Constructs a log of size `bytes` bytes that writes every batch of operations
+    /// appended to it into the segment file at `path`.
+    ///
+    /// Fails with `AlreadyExists` if the segment already holds operations; use
+    /// [recover](persist/fn.recover.html) to continue from an existing segment.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use node_replication::persist::FsyncPolicy;
+    /// use node_replication::Log;
+    ///
+    /// let path = std::env::temp_dir().join("nr-log-with-segment-example");
+    /// # let _ = std::fs::remove_file(&path);
+    /// let l = Log::<u64>::with_segment(1 * 1024 * 1024, &path, FsyncPolicy::Always)
+    ///     .expect("Failed to create the segment");
+    /// # std::fs::remove_file(&path).unwrap();
+    /// ```
+    pub fn with_segment<P: AsRef<Path>>(
+        bytes: usize,
+        path: P,
+        policy: FsyncPolicy,
+    ) -> io::Result<Log<'a, T, MAX_REPLICAS, MAX_THREADS, MAX_PENDING>> {
+        let path = path.as_ref();
+        match fs::metadata(path) {
+            Ok(m) if m.len() > 0 => {
+                return Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    "segment already holds operations",
+                ))
+            }
+            Ok(_) => {}
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+
+        let mut log = Log::new(bytes);
+        log.persist_to(Segment::open(path, policy)?, 0);
+        Ok(log)
+    }
+
+    /// Writes every batch appended to this log into `segment` from now on. `next`
+    /// is the logical index of the next entry that will be appended to the log.
+    fn persist_to(&mut self, mut segment: Segment, next: usize) {
+        segment.next = next;
+        let segment = (Mutex::new(segment), Condvar::new());
+        self.set_segment(Box::new(
+            move |start: usize,
+                  ops: &[T],
+                  replica: usize,
+                  threads: &[usize],
+                  timestamp: Option<Duration>| {
+                // Batches are reserved on the log concurrently; write them in log order,
+                // so that the segment never misses operations that precede a record.
+                let (lock, turn) = &segment;
+                let mut s = turn
+                    .wait_while(lock.lock().unwrap(), |s| s.next != start)
+                    .unwrap();
+
+                let r = if s.failed {
+                    Err(Error::SegmentFailed)
+                } else {
+                    s.write(start, ops, replica, threads, timestamp)
+                        .map_err(|e| {
+                            error!("Failed to write operations to the segment: {}", e);
+                            Error::SegmentFailed
+                        })
+                };
+                s.failed |= r.is_err();
+                s.next = start + ops.len();
+                turn.notify_all();
+                r
+            },
+        ));
+    }
+}
+
+impl<'a, D, const MAX_REPLICAS: usize, const MAX_THREADS: usize, const MAX_PENDING: usize>
+    Replica<'a, D, MAX_REPLICAS, MAX_THREADS, MAX_PENDING>
+where
+    D: Sized + Dispatch + Sync + Persistent,
+{
+    /// Writes a checkpoint of this replica's data structure to `w` and returns the
+    /// logical log index the checkpoint corresponds to. `idx` is the token of the
+    /// calling thread.
+    ///
+    /// The data structure is encoded while holding the combiner lock, after the
+    /// replica has been brought up to date with the shared log. Together with the
+    /// segment of the log, the checkpoint can be used to [restore](persist/fn.restore.html)
+    /// the replicated data structure, which drops the operations that precede the
+    /// checkpoint from the segment.
+    pub fn checkpoint<W: Write>(&self, idx: ReplicaToken, mut w: W) -> io::Result<usize> {
+        let (state, ltail) = self.synced(idx.id(), |data, ltail| {
+            let mut state = Vec::new();
+            data.encode(&mut state);
+            (state, ltail)
+        });
+
+        w.write_all(&CHECKPOINT_MAGIC)?;
+        w.write_all(&(ltail as u64).to_le_bytes())?;
+        w.write_all(&(state.len() as u64).to_le_bytes())?;
+        w.write_all(&checksum(&state).to_le_bytes())?;
+        w.write_all(&state)?;
+        w.flush()?;
+
+        Ok(ltail)
+    }
+}
+
+/// Reads a checkpoint written by `Replica::checkpoint` and returns the logical
+/// log index it was taken at along with the encoded data structure.
+fn read_checkpoint<R: Read>(mut r: R) -> io::Result<(usize, Vec<u8>)> {
+    let invalid = |msg| io::Error::new(io::ErrorKind::InvalidData, msg);
+
+    let mut header = [0u8; 28];
+    r.read_exact(&mut header)?;
+    if header[0..8] != CHECKPOINT_MAGIC {
+        return Err(invalid("not a checkpoint"));
+    }
+
+    let ltail = u64::from_le_bytes(header[8..16].try_into().unwrap()) as usize;
+    let len = u64::from_le_bytes(header[16..24].try_into().unwrap()) as usize;
+    let sum = u32::from_le_bytes(header[24..28].try_into().unwrap());
+
+    let mut state = alloc::vec![0u8; len];
+    r.read_exact(&mut state)?;
+    if checksum(&state) != sum {
+        return Err(invalid("checkpoint is corrupted"));
+    }
+
+    Ok((ltail, state))
+}
+
+/// Recovers a replicated data structure from the segment at `path`.
+///
+/// Creates `replicas` replicas, each starting out from `D::default()` with all the
+/// operations in the segment applied to it, and a log of size `bytes` bytes that
+/// they are registered with. The log continues writing to the segment using the
+/// fsync `policy`. A segment that doesn't exist is treated like an empty one.
+///
+/// Fails with an `InvalidData` error wrapping `Error::LogOutOfRange` if the segment
+/// misses operations, and leaves it untouched then.
+///
+/// # Example
+///
+/// ```
+/// use node_replication::persist::{self, FsyncPolicy};
+/// use node_replication::{Dispatch, Log, Replica};
+///
+/// use std::sync::Arc;
+///
+/// #[derive(Default)]
+/// struct Data {
+///     junk: u64,
+/// }
+///
+/// impl Dispatch for Data {
+///     type ReadOperation = ();
+///     type WriteOperation = u64;
+///     type Response = Option<u64>;
+///
+///     fn dispatch(&self, _op: Self::ReadOperation) -> Self::Response {
+///         Some(self.junk)
+///     }
+///
+///     fn dispatch_mut(&mut self, op: Self::WriteOperation) -> Self::Response {
+///         self.junk = op;
+///         None
+///     }
+/// }
+///
+/// let path = std::env::temp_dir().join("nr-recover-example");
+/// # let _ = std::fs::remove_file(&path);
+/// {
+///     let log = Log::<u64>::with_segment(1024 * 1024, &path, FsyncPolicy::Always).unwrap();
+///     let replica = Replica::<Data>::new(&Arc::new(log));
+///     let idx = replica.register().unwrap();
+///     replica.execute_mut(42, idx);
+/// }
+///
+/// // After a restart, the replicas are rebuilt from the segment.
+/// let (_log, replicas) =
+///     persist::recover::<Data, _>(1024 * 1024, &path, FsyncPolicy::Always, 2).unwrap();
+/// let idx = replicas[1].register().unwrap();
+/// assert_eq!(replicas[1].execute((), idx), Some(42));
+/// # std::fs::remove_file(&path).unwrap();
+/// ```
+#[allow(clippy::type_complexity)]
+pub fn recover<'a, D, P>(
+    bytes: usize,
+    path: P,
+    policy: FsyncPolicy,
+    replicas: usize,
+) -> io::Result<(
+    Arc<Log<'a, <D as Dispatch>::WriteOperation>>,
+    Vec<Arc<Replica<'a, D>>>,
+)>
+where
+    D: Sized + Default + Dispatch + Sync,
+    <D as Dispatch>::WriteOperation: Persistent + 'a,
+    P: AsRef<Path>,
+{
+    rebuild(bytes, path.as_ref(), policy, replicas, None, || {
+        Ok(D::default())
+    })
+}
+
+/// Restores a replicated data structure from a `checkpoint` written by
+/// `Replica::checkpoint` and the segment at `path`.
+///
+/// Like [recover](fn.recover.html), but every replica starts out from the state
+/// in the checkpoint and only the operations in the segment that were appended
+/// after the checkpoint was taken are replayed. Fails if the segment doesn't hold
+/// every operation since, for example because it starts after the checkpoint.
+///
+/// The operations before the checkpoint are dropped from the segment, so that
+/// they aren't read again the next time. The segment is rewritten into a new file
+/// that replaces the old one; from then on, it can only be restored from this
+/// checkpoint or a later one.
+///
+/// # Example
+///
+/// ```
+/// use node_replication::persist::{self, FsyncPolicy, Persistent};
+/// use node_replication::{Dispatch, Log, Replica};
+///
+/// use std::sync::Arc;
+///
+/// #[derive(Default)]
+/// struct Data {
+///     junk: u64,
+/// }
+///
+/// impl Dispatch for Data {
+///     type ReadOperation = ();
+///     type WriteOperation = u64;
+///     type Response = Option<u64>;
+///
+///     fn dispatch(&self, _op: Self::ReadOperation) -> Self::Response {
+///         Some(self.junk)
+///     }
+///
+///     fn dispatch_mut(&mut self, op: Self::WriteOperation) -> Self::Response {
+///         self.junk += op;
+///         None
+///     }
+/// }
+///
+/// impl Persistent for Data {
+///     fn encode(&self, buf: &mut Vec<u8>) {
+///         self.junk.encode(buf);
+///     }
+///
+///     fn decode(buf: &[u8]) -> Option<Self> {
+///         Some(Data { junk: u64::decode(buf)? })
+///     }
+/// }
+///
+/// let path = std::env::temp_dir().join("nr-restore-example");
+/// # let _ = std::fs::remove_file(&path);
+/// let mut checkpoint = Vec::new();
+/// {
+///     let log = Log::<u64>::with_segment(1024 * 1024, &path, FsyncPolicy::Always).unwrap();
+///     let replica = Replica::<Data>::new(&Arc::new(log));
+///     let idx = replica.register().unwrap();
+///     replica.execute_mut(40, idx);
+///     assert_eq!(replica.checkpoint(idx, &mut checkpoint).unwrap(), 1);
+///     replica.execute_mut(2, idx);
+/// }
+///
+/// // After a restart, only the operation after the checkpoint is replayed.
+/// let (_log, replicas) =
+///     persist::restore::<Data, _, _>(1024 * 1024, &checkpoint[..], &path, FsyncPolicy::Always, 1)
+///         .unwrap();
+/// let idx = replicas[0].register().unwrap();
+/// assert_eq!(replicas[0].execute((), idx), Some(42));
+/// # std::fs::remove_file(&path).unwrap();
+/// ```
+#[allow(clippy::type_complexity)]
+pub fn restore<'a, D, R, P>(
+    bytes: usize,
+    checkpoint: R,
+    path: P,
+    policy: FsyncPolicy,
+    replicas: usize,
+) -> io::Result<(
+    Arc<Log<'a, <D as Dispatch>::WriteOperation>>,
+    Vec<Arc<Replica<'a, D>>>,
+)>
+where
+    D: Sized + Dispatch + Sync + Persistent,
+    <D as Dispatch>::WriteOperation: Persistent + 'a,
+    R: Read,
+    P: AsRef<Path>,
+{
+    let (ltail, state) = read_checkpoint(checkpoint)?;
+
+    rebuild(bytes, path.as_ref(), policy, replicas, Some(ltail), || {
+        D::decode(&state).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidData, "failed to decode checkpoint")
+        })
+    })
+}
+
+/// Rebuilds a log and `replicas` replicas from the segment at `path`.
+///
+/// The state of every replica is created by `init` and corresponds to the
+/// logical log index `start`; only the operations from `start` onwards are
+/// replayed on top of it. If `start` is `None`, all operations in the segment
+/// are replayed, starting at the beginning of the log.
+///
+/// The segment must hold the operations from `start` onwards without gaps. The
+/// records before `start` and a partially written record at its end are cut off.
+#[allow(clippy::type_complexity)]
+fn rebuild<'a, D, F>(
+    bytes: usize,
+    path: &Path,
+    policy: FsyncPolicy,
+    replicas: usize,
+    start: Option<usize>,
+    mut init: F,
+) -> io::Result<(
+    Arc<Log<'a, <D as Dispatch>::WriteOperation>>,
+    Vec<Arc<Replica<'a, D>>>,
+)>
+where
+    D: Sized + Dispatch + Sync,
+    <D as Dispatch>::WriteOperation: Persistent + 'a,
+    F: FnMut() -> io::Result<D>,
+{
+    let mut contents = Vec::new();
+    let records = read_records(path, &mut contents)?;
+    let end = records.iter().map(|(_pos, r)| r.end).max().unwrap_or(0);
+
+    // Records before `start` are covered by the checkpoint. Replaying the rest
+    // requires every one of them, starting right at `first`.
+    let first = start.unwrap_or(0);
+    let skip = records.iter().take_while(|(pos, _r)| *pos < first).count();
+    let records = &records[skip..];
+    if let Some(missing) = records
+        .iter()
+        .enumerate()
+        .find(|(i, (pos, _r))| *pos != first + i)
+        .map(|(i, _r)| first + i)
+    {
+        error!("The segment misses the operation at index {}", missing);
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            Error::LogOutOfRange,
+        ));
+    }
+
+    let mut ops = Vec::with_capacity(records.len());
+    for (pos, r) in records {
+        let op = <D as Dispatch>::WriteOperation::decode(&contents[r.start + RECORD_HEADER..r.end])
+            .ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidData, "failed to decode operation")
+            })?;
+        ops.push((op, record_context(*pos, &contents, r)));
+    }
+
+    // Records appended after a partially written one couldn't be read back, and
+    // the ones before `start` won't be replayed again.
+    if skip > 0 {
+        compact(path, &contents, records)?;
+    } else {
+        truncate_torn(path, &contents, end)?;
+    }
+
+    let mut log = Log::new(bytes);
+    log.start_at(first + ops.len());
+    log.persist_to(Segment::open(path, policy)?, first + ops.len());
+    let log = Arc::new(log);
+
+    let mut rs = Vec::with_capacity(replicas);
+    for _i in 0..replicas {
+        let mut d = init()?;
+        for (op, ctx) in ops.iter() {
+            d.dispatch_mut_with(op.clone(), ctx);
+        }
+        rs.push(Replica::with_data(&log, d));
+    }
+
+    Ok((log, rs))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::format;
+    use std::path::PathBuf;
+
+    // Returns a path in the temporary directory that is unique to this test.
+    fn segment_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("nr-persist-{}-{}", std::process::id(), name));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    // Really dumb data structure to test persistence with. Also keeps the context
+    // of every operation, which isn't part of its checkpoints.
+    #[derive(Default)]
+    struct Data {
+        ops: Vec<u64>,
+        ctxs: Vec<WriteContext>,
+    }
+
+    impl Dispatch for Data {
+        type ReadOperation = ();
+        type WriteOperation = u64;
+        type Response = usize;
+
+        fn dispatch(&self, _op: Self::ReadOperation) -> Self::Response {
+            self.ops.len()
+        }
+
+        fn dispatch_mut(&mut self, op: Self::WriteOperation) -> Self::Response {
+            self.ops.push(op);
+            self.ops.len()
+        }
+
+        fn dispatch_mut_with(&mut self, op: Self::WriteOperation, ctx: &WriteContext) -> usize {
+            self.ctxs.push(*ctx);
+            self.dispatch_mut(op)
+        }
+    }
+
+    impl Persistent for Data {
+        fn encode(&self, buf: &mut Vec<u8>) {
+            for op in &self.ops {
+                op.encode(buf);
+            }
+        }
+
+        fn decode(buf: &[u8]) -> Option<Self> {
+            let ops = buf
+                .chunks(8)
+                .map(u64::decode)
+                .collect::<Option<Vec<u64>>>()?;
+            Some(Data {
+                ops,
+                ctxs: Vec::new(),
+            })
+        }
+    }
+
+    // Tests that integers can be encoded and decoded again.
+    #[test]
+    fn test_persistent_int() {
+        let mut buf = Vec::new();
+        0xdead_beef_u64.encode(&mut buf);
+        assert_eq!(buf.len(), 8);
+        assert_eq!(u64::decode(&buf), Some(0xdead_beef));
+        assert_eq!(u32::decode(&buf), None);
+    }
+
+    // Tests that batches written to a segment can be read back in log order.
+    #[test]
+    fn test_segment_write_read() {
+        let path = segment_path("write-read");
+        let mut s = Segment::open(&path, FsyncPolicy::Always).unwrap();
+        s.write(2, &[12u64, 13], 1, &[], None).unwrap();
+        s.write(0, &[10u64, 11], 1, &[], None).unwrap();
+
+        let mut contents = Vec::new();
+        let records = read_records(&path, &mut contents).unwrap();
+        let ops: Vec<(usize, u64)> = records
+            .iter()
+            .map(|(pos, r)| {
+                (
+                    *pos,
+                    u64::decode(&contents[r.start + RECORD_HEADER..r.end]).unwrap(),
+                )
+            })
+            .collect();
+        assert_eq!(ops, [(0, 10), (1, 11), (2, 12), (3, 13)]);
+        fs::remove_file(&path).unwrap();
+    }
+
+    // Tests that a partially written record at the end of a segment is ignored.
+    #[test]
+    fn test_segment_torn_record() {
+        let path = segment_path("torn");
+        let mut s = Segment::open(&path, FsyncPolicy::Never).unwrap();
+        s.write(0, &[10u64, 11], 1, &[], None).unwrap();
+        drop(s);
+
+        let len = fs::metadata(&path).unwrap().len();
+        OpenOptions::new()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_len(len - 3)
+            .unwrap();
+
+        let mut contents = Vec::new();
+        let records = read_records(&path, &mut contents).unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].0, 0);
+        fs::remove_file(&path).unwrap();
+    }
+
+    // Tests that a log refuses to overwrite a segment that holds operations.
+    #[test]
+    fn test_log_with_segment_exists() {
+        let path = segment_path("exists");
+        let mut s = Segment::open(&path, FsyncPolicy::Never).unwrap();
+        s.write(0, &[10u64], 1, &[], None).unwrap();
+
+        let e = Log::<u64>::with_segment(1024, &path, FsyncPolicy::Never).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::AlreadyExists);
+        fs::remove_file(&path).unwrap();
+    }
+
+    // Tests that batches appended concurrently by several replicas are written to
+    // the segment in log order.
+    #[test]
+    fn test_log_segment_order() {
+        let path = segment_path("order");
+        let log =
+            Arc::new(Log::<u64>::with_segment(1024 * 1024, &path, FsyncPolicy::Never).unwrap());
+
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                let log = log.clone();
+                std::thread::spawn(move || {
+                    let replica = Replica::<Data>::new(&log);
+                    let idx = replica.register().unwrap();
+                    for i in 0..1000 {
+                        replica.execute_mut(i, idx);
+                    }
+                })
+            })
+            .collect();
+        for t in threads {
+            t.join().unwrap();
+        }
+
+        let mut contents = Vec::new();
+        let records = read_records(&path, &mut contents).unwrap();
+        assert_eq!(records.len(), 4000);
+        for (i, w) in records.windows(2).enumerate() {
+            assert_eq!(w[0].0, i);
+            assert_eq!(w[0].1.end, w[1].1.start);
+        }
+        fs::remove_file(&path).unwrap();
+    }
+
+    // Tests that a batch that can't be written to the segment is not executed,
+    // and that the replicas keep consuming the log past it.
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_log_segment_failed() {
+        let mut log = Log::<u64>::new(1024);
+        let segment = Segment::open(Path::new("/dev/full"), FsyncPolicy::Never).unwrap();
+        log.persist_to(segment, 0);
+        let log = Arc::new(log);
+
+        let one = Replica::<Data>::new(&log);
+        let two = log.register().unwrap();
+        let idx = one.register().unwrap();
+        assert_eq!(one.try_execute_mut(10, idx), Err(Error::SegmentFailed));
+        one.verify(|d| assert!(d.ops.is_empty()));
+        // Nothing is left pending on the context, so unregistering doesn't try to
+        // append the operation again.
+        one.unregister(idx);
+
+        log.exec(two, &mut |_op: u64, _r: usize| {
+            panic!("Executed a lost operation!")
+        });
+        assert_eq!(log.get_ctail(), 1);
+    }
+
+    // Tests that recovery fails on a gap in the segment, and leaves the segment
+    // untouched.
+    #[test]
+    fn test_recover_gap() {
+        let path = segment_path("gap");
+        let mut s = Segment::open(&path, FsyncPolicy::Never).unwrap();
+        s.write(0, &[10u64, 11], 1, &[], None).unwrap();
+        s.write(3, &[13u64], 1, &[], None).unwrap();
+        drop(s);
+        let len = fs::metadata(&path).unwrap().len();
+
+        let e = recover::<Data, _>(1024, &path, FsyncPolicy::Never, 2).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(
+            e.get_ref().and_then(|e| e.downcast_ref::<Error>()),
+            Some(&Error::LogOutOfRange)
+        );
+        assert_eq!(fs::metadata(&path).unwrap().len(), len);
+        fs::remove_file(&path).unwrap();
+    }
+
+    // Tests that recovery cuts off a partially written record, and that the
+    // recovered log continues appending right after the last recovered operation.
+    #[test]
+    fn test_recover_torn_record() {
+        let path = segment_path("recover-torn");
+        let mut s = Segment::open(&path, FsyncPolicy::Never).unwrap();
+        s.write(0, &[10u64, 11], 1, &[], None).unwrap();
+        drop(s);
+        let len = fs::metadata(&path).unwrap().len();
+        OpenOptions::new()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_len(len - 3)
+            .unwrap();
+
+        let (log, replicas) = recover::<Data, _>(1024, &path, FsyncPolicy::Never, 2).unwrap();
+        assert_eq!(log.get_ctail(), 1);
+        for r in &replicas {
+            r.verify(|d| assert_eq!(d.ops, [10]));
+        }
+
+        let idx = replicas[0].register().unwrap();
+        assert_eq!(replicas[0].execute_mut(12, idx), 2);
+        drop(replicas);
+        drop(log);
+
+        let (_log, replicas) = recover::<Data, _>(1024, &path, FsyncPolicy::Never, 1).unwrap();
+        replicas[0].verify(|d| assert_eq!(d.ops, [10, 12]));
+        fs::remove_file(&path).unwrap();
+    }
+
+    // Tests that recovered operations are replayed with the same context as when
+    // they were first executed.
+    #[test]
+    fn test_recover_write_context() {
+        struct Ticks(std::sync::atomic::AtomicU64);
+        impl crate::clock::Clock for Ticks {
+            fn now(&self) -> Duration {
+                let t = self.0.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                Duration::from_millis(t)
+            }
+        }
+
+        let path = segment_path("context");
+        let mut expected = Vec::new();
+        {
+            let mut log = Log::<u64>::with_segment(1024, &path, FsyncPolicy::Never).unwrap();
+            log.update_clock(Ticks(std::sync::atomic::AtomicU64::new(3)));
+            let log = Arc::new(log);
+            let _zero = Replica::<Data>::new(&log);
+            let replica = Replica::<Data>::new(&log);
+            let one = replica.register().unwrap();
+            let two = replica.register().unwrap();
+            replica.execute_mut(10, one);
+            replica.execute_mut(11, two);
+            replica.verify(|d| expected = d.ctxs.clone());
+        }
+        assert_eq!(expected[1].replica, 2);
+        assert_eq!(expected[1].thread, 2);
+        assert_eq!(expected[1].timestamp, Some(Duration::from_millis(4)));
+
+        let (_log, replicas) = recover::<Data, _>(1024, &path, FsyncPolicy::Never, 1).unwrap();
+        replicas[0].verify(|d| assert_eq!(d.ctxs, expected));
+        fs::remove_file(&path).unwrap();
+    }
+
+    // Tests that restoring from a checkpoint older than the first operation in
+    // the segment fails, and leaves the segment untouched.
+    #[test]
+    fn test_restore_checkpoint_too_old() {
+        let path = segment_path("too-old");
+        let mut checkpoint = Vec::new();
+        {
+            let log = Arc::new(Log::<u64>::new(1024));
+            let replica = Replica::<Data>::new(&log);
+            let idx = replica.register().unwrap();
+            replica.execute_mut(1, idx);
+            assert_eq!(replica.checkpoint(idx, &mut checkpoint).unwrap(), 1);
+        }
+
+        let mut s = Segment::open(&path, FsyncPolicy::Never).unwrap();
+        s.write(2, &[12u64, 13], 1, &[], None).unwrap();
+        drop(s);
+        let len = fs::metadata(&path).unwrap().len();
+
+        let e =
+            restore::<Data, _, _>(1024, &checkpoint[..], &path, FsyncPolicy::Never, 1).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(fs::metadata(&path).unwrap().len(), len);
+        fs::remove_file(&path).unwrap();
+    }
+
+    // Tests that restoring from a checkpoint only replays the operations appended
+    // after it, and drops the ones before it from the segment.
+    #[test]
+    fn test_checkpoint_restore() {
+        let path = segment_path("checkpoint");
+        let mut checkpoint = Vec::new();
+        {
+            let log = Arc::new(Log::<u64>::with_segment(1024, &path, FsyncPolicy::Never).unwrap());
+            let replica = Replica::<Data>::new(&log);
+            let idx = replica.register().unwrap();
+            for i in 0..10 {
+                replica.execute_mut(i, idx);
+            }
+            assert_eq!(replica.checkpoint(idx, &mut checkpoint).unwrap(), 10);
+            for i in 10..15 {
+                replica.execute_mut(i, idx);
+            }
+        }
+
+        let (log, replicas) =
+            restore::<Data, _, _>(1024, &checkpoint[..], &path, FsyncPolicy::Never, 2).unwrap();
+        assert_eq!(log.get_ctail(), 15);
+        for r in &replicas {
+            r.verify(|d| assert_eq!(d.ops, (0..15).collect::<Vec<u64>>()));
+        }
+
+        let mut contents = Vec::new();
+        let records = read_records(&path, &mut contents).unwrap();
+        assert_eq!(records.first().map(|(pos, _r)| *pos), Some(10));
+        assert_eq!(records.len(), 5);
+
+        // The log keeps appending to the compacted segment.
+        let idx = replicas[0].register().unwrap();
+        assert_eq!(replicas[0].execute_mut(15, idx), 16);
+        drop(replicas);
+        drop(log);
+
+        let (_log, replicas) =
+            restore::<Data, _, _>(1024, &checkpoint[..], &path, FsyncPolicy::Never, 1).unwrap();
+        replicas[0].verify(|d| assert_eq!(d.ops, (0..16).collect::<Vec<u64>>()));
+
+        // Without the checkpoint, the segment misses the operations before it.
+        let e = recover::<Data, _>(1024, &path, FsyncPolicy::Never, 1).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        fs::remove_file(&path).unwrap();
+    }
+
+    // Tests that a corrupted checkpoint is rejected.
+    #[test]
+    fn test_checkpoint_corrupted() {
+        let path = segment_path("corrupted");
+        let log = Arc::new(Log::<u64>::with_segment(1024, &path, FsyncPolicy::Never).unwrap());
+        let replica = Replica::<Data>::new(&log);
+        let idx = replica.register().unwrap();
+        replica.execute_mut(1, idx);
+
+        let mut checkpoint = Vec::new();
+        replica.checkpoint(idx, &mut checkpoint).unwrap();
+        let last = checkpoint.len() - 1;
+        checkpoint[last] ^= 0xff;
+
+        let e = read_checkpoint(&checkpoint[..]).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,343 @@
+// Copyright © 2019-2020 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Placement of the memory backing the shared log and the replicas.
+//!
+//! By default, the shared log is allocated with the global allocator and ends up
+//! on whichever NUMA node first touches it. `Log::new_in` instead allocates the
+//! log through a [Placement](trait.Placement.html), and `Replica::with_data_in`
+//! allocates the data structure and the per-thread contexts of a replica through
+//! one.
+//!
+//! With the `numa` feature, [Numa](struct.Numa.html) binds memory to a node or
+//! interleaves it across nodes using `mbind`, and can back allocations with huge
+//! pages (`mmap` with `MAP_HUGETLB`), falling back to regular pages if none are
+//! available.
+
+use alloc::alloc::{alloc, dealloc, handle_alloc_error, Layout};
+use alloc::sync::Arc;
+use core::ops::{Deref, DerefMut};
+use core::ptr::{self, NonNull};
+
+/// Decides where the memory of a log or a replica is placed.
+///
+/// # Safety
+/// `alloc` must return null or a pointer to memory that fits `layout`, and which
+/// stays valid until it is passed to `dealloc` together with the same layout.
+pub unsafe trait Placement: Send + Sync {
+    /// Allocates memory for `layout`. Returns null if the allocation failed.
+    fn alloc(&self, layout: Layout) -> *mut u8;
+
+    /// Frees memory previously returned by `alloc` for `layout`.
+    ///
+    /// # Safety
+    /// `ptr` must have been returned by `alloc` on this placement with the same
+    /// `layout`, and must not be used afterwards.
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout);
+}
+
+/// Allocates memory with the global allocator and leaves placement to the
+/// operating system (usually, memory ends up on the node that touches it first).
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Global;
+
+unsafe impl Placement for Global {
+    fn alloc(&self, layout: Layout) -> *mut u8 {
+        unsafe { alloc(layout) }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        dealloc(ptr, layout)
+    }
+}
+
+/// Owns a value, or a slice of values, in memory allocated through a
+/// [Placement](trait.Placement.html), like a `Box` does with the global allocator.
+pub(crate) struct Placed<T: ?Sized> {
+    ptr: NonNull<T>,
+    layout: Layout,
+    placement: Arc<dyn Placement>,
+}
+
+unsafe impl<T: ?Sized + Send> Send for Placed<T> {}
+unsafe impl<T: ?Sized + Sync> Sync for Placed<T> {}
+
+impl<T> Placed<T> {
+    /// Moves `value` into memory allocated through `placement`.
+    pub(crate) fn new(value: T, placement: Arc<dyn Placement>) -> Placed<T> {
+        let layout = Layout::new::<T>();
+        let ptr = allocate(layout, &*placement) as *mut T;
+        unsafe { ptr.write(value) };
+
+        Placed {
+            ptr: unsafe { NonNull::new_unchecked(ptr) },
+            layout,
+            placement,
+        }
+    }
+}
+
+impl<T> Placed<[T]> {
+    /// Allocates `len` values through `placement`, and initializes the value at
+    /// every index `i` with `f(i)`.
+    pub(crate) fn from_fn<F: FnMut(usize) -> T>(
+        len: usize,
+        mut f: F,
+        placement: Arc<dyn Placement>,
+    ) -> Placed<[T]> {
+        let layout = Layout::array::<T>(len).expect("Slice is too large.");
+        let ptr = allocate(layout, &*placement) as *mut T;
+        for i in 0..len {
+            unsafe { ptr.add(i).write(f(i)) };
+        }
+
+        Placed {
+            ptr: unsafe { NonNull::new_unchecked(ptr::slice_from_raw_parts_mut(ptr, len)) },
+            layout,
+            placement,
+        }
+    }
+}
+
+impl<T: ?Sized> Deref for Placed<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { self.ptr.as_ref() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for Placed<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { self.ptr.as_mut() }
+    }
+}
+
+impl<T: ?Sized> Drop for Placed<T> {
+    fn drop(&mut self) {
+        unsafe {
+            ptr::drop_in_place(self.ptr.as_ptr());
+            if self.layout.size() != 0 {
+                self.placement
+                    .dealloc(self.ptr.as_ptr() as *mut u8, self.layout);
+            }
+        }
+    }
+}
+
+/// Allocates memory for `layout` through `placement`. Zero sized layouts get a
+/// dangling pointer instead; aborts if the placement is out of memory.
+fn allocate(layout: Layout, placement: &dyn Placement) -> *mut u8 {
+    if layout.size() == 0 {
+        return layout.align() as *mut u8;
+    }
+
+    let ptr = placement.alloc(layout);
+    if ptr.is_null() {
+        handle_alloc_error(layout);
+    }
+    ptr
+}
+
+#[cfg(all(feature = "numa", target_os = "linux"))]
+pub use numa::Numa;
+
+#[cfg(all(feature = "numa", target_os = "linux"))]
+mod numa {
+    use super::Placement;
+    use core::alloc::Layout;
+    use core::ptr;
+
+    /// Memory policies and flags from `<linux/mempolicy.h>`.
+    const MPOL_BIND: i32 = 2;
+    const MPOL_INTERLEAVE: i32 = 3;
+
+    /// The number of nodes that fit into the node mask passed to `mbind`.
+    const MAX_NODES: usize = 64;
+
+    /// Size of a (regular) huge page on x86-64.
+    const HUGE_PAGE_SIZE: usize = 2 * 1024 * 1024;
+
+    /// Places memory on a set of NUMA nodes using `mbind`.
+    ///
+    /// Allocations are made with `mmap`, and the memory policy is applied before
+    /// the memory is touched for the first time.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use node_replication::placement::Numa;
+    /// use node_replication::Log;
+    ///
+    /// // A log interleaved across the first two nodes, backed by huge pages
+    /// // if there are any.
+    /// let l = Log::<u64>::new_in(2 * 1024 * 1024, Numa::interleave(&[0, 1]).huge_pages());
+    /// ```
+    #[derive(Clone, Copy, Debug)]
+    pub struct Numa {
+        /// `MPOL_BIND` or `MPOL_INTERLEAVE`.
+        mode: i32,
+
+        /// Bit `i` is set if node `i` is part of the policy.
+        nodes: u64,
+
+        /// Try backing allocations with huge pages.
+        huge_pages: bool,
+    }
+
+    impl Numa {
+        /// Binds memory to `node`.
+        pub fn bind(node: usize) -> Numa {
+            Numa::new(MPOL_BIND, &[node])
+        }
+
+        /// Interleaves memory page by page across `nodes`.
+        pub fn interleave(nodes: &[usize]) -> Numa {
+            Numa::new(MPOL_INTERLEAVE, nodes)
+        }
+
+        /// Backs allocations with huge pages. Falls back to regular pages if the
+        /// system doesn't have any huge pages available.
+        pub fn huge_pages(mut self) -> Numa {
+            self.huge_pages = true;
+            self
+        }
+
+        fn new(mode: i32, nodes: &[usize]) -> Numa {
+            assert!(!nodes.is_empty(), "Need at least one node.");
+            let nodes = nodes.iter().fold(0, |mask, &node| {
+                assert!(node < MAX_NODES, "Node {} is out of range.", node);
+                mask | (1u64 << node)
+            });
+
+            Numa {
+                mode,
+                nodes,
+                huge_pages: false,
+            }
+        }
+
+        /// Length of the mapping backing an allocation of `size` bytes.
+        fn mapping_len(&self, size: usize) -> usize {
+            let granularity = if self.huge_pages {
+                HUGE_PAGE_SIZE
+            } else {
+                page_size()
+            };
+            (size.max(1) + granularity - 1) & !(granularity - 1)
+        }
+
+        /// Applies the memory policy to the page aligned range `[addr, addr + len)`.
+        fn mbind(&self, addr: usize, len: usize) -> bool {
+            let mask = self.nodes as libc::c_ulong;
+            let r = unsafe {
+                libc::syscall(
+                    libc::SYS_mbind,
+                    addr,
+                    len,
+                    self.mode,
+                    &mask as *const libc::c_ulong,
+                    // The kernel ignores the last bit of the mask.
+                    MAX_NODES + 1,
+                    0,
+                )
+            };
+            r == 0
+        }
+    }
+
+    unsafe impl Placement for Numa {
+        fn alloc(&self, layout: Layout) -> *mut u8 {
+            assert!(layout.align() <= page_size(), "Alignment error!");
+            let len = self.mapping_len(layout.size());
+            let prot = libc::PROT_READ | libc::PROT_WRITE;
+            let flags = libc::MAP_PRIVATE | libc::MAP_ANONYMOUS;
+
+            let mut mem = libc::MAP_FAILED;
+            if self.huge_pages {
+                mem = unsafe {
+                    libc::mmap(ptr::null_mut(), len, prot, flags | libc::MAP_HUGETLB, -1, 0)
+                };
+                if mem == libc::MAP_FAILED {
+                    debug!("No huge pages available, falling back to regular pages.");
+                }
+            }
+            if mem == libc::MAP_FAILED {
+                mem = unsafe { libc::mmap(ptr::null_mut(), len, prot, flags, -1, 0) };
+            }
+            if mem == libc::MAP_FAILED {
+                return ptr::null_mut();
+            }
+
+            if !self.mbind(mem as usize, len) {
+                warn!(
+                    "Failed to apply the memory policy (mbind) to {} bytes.",
+                    len
+                );
+            }
+
+            mem as *mut u8
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            libc::munmap(ptr as *mut libc::c_void, self.mapping_len(layout.size()));
+        }
+
+    }
+
+    fn page_size() -> usize {
+        unsafe { libc::sysconf(libc::_SC_PAGESIZE) as usize }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Tests that the global placement hands out usable memory.
+    #[test]
+    fn test_global_alloc() {
+        let layout = Layout::from_size_align(4096, 64).unwrap();
+        let p = Global.alloc(layout);
+        assert!(!p.is_null());
+        assert_eq!(p as usize % 64, 0);
+        unsafe {
+            p.write_bytes(0xa, 4096);
+            Global.dealloc(p, layout);
+        }
+    }
+
+    // Tests that memory placed on a node can be used, whether or not the system
+    // has huge pages or more than one node.
+    #[test]
+    #[cfg(all(feature = "numa", target_os = "linux"))]
+    fn test_numa_alloc() {
+        let layout = Layout::from_size_align(3 * 1024 * 1024, 64).unwrap();
+        for placement in [Numa::bind(0), Numa::interleave(&[0]).huge_pages()].iter() {
+            let p = placement.alloc(layout);
+            assert!(!p.is_null());
+            unsafe {
+                p.write_bytes(0xa, layout.size());
+                assert_eq!(*p.add(layout.size() - 1), 0xa);
+                placement.dealloc(p, layout);
+            }
+        }
+    }
+
+    // Tests that placed values and slices are initialized, and dropped and freed
+    // through their placement again.
+    #[test]
+    fn test_placed() {
+        let placement: Arc<dyn Placement> = Arc::new(Global);
+        let value = Arc::new(7u64);
+        let one = Placed::new(value.clone(), placement.clone());
+        let many = Placed::<[Arc<u64>]>::from_fn(3, |_i| value.clone(), placement);
+        assert_eq!(**one, 7);
+        assert_eq!(many.len(), 3);
+        assert_eq!(Arc::strong_count(&value), 5);
+
+        drop(one);
+        drop(many);
+        assert_eq!(Arc::strong_count(&value), 1);
+    }
+}
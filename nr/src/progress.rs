@@ -0,0 +1,328 @@
+// Copyright © 2019-2020 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Keeps idle replicas from holding back garbage collection on the shared log.
+//!
+//! A replica only consumes the shared log while its threads issue operations.
+//! Once all of them go idle, the log can't garbage collect the entries the
+//! replica hasn't executed yet, and threads appending on other replicas
+//! eventually block until some thread calls `Replica::sync` on the idle one.
+//!
+//! A [Progress](struct.Progress.html) watches a set of replicas and syncs the
+//! ones that are behind the log but didn't make progress on their own since it
+//! last looked. In `no_std` environments, the application calls
+//! `Progress::poll` periodically, for example from a timer. With the `std`
+//! feature, `Progress::spawn` polls from a background thread.
+
+#[cfg(not(loom))]
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+#[cfg(loom)]
+use loom::sync::Arc;
+
+#[cfg(feature = "std")]
+use core::time::Duration;
+#[cfg(feature = "std")]
+use std::sync::atomic::{AtomicBool, Ordering};
+#[cfg(feature = "std")]
+use std::thread::JoinHandle;
+
+use crate::context::MAX_PENDING_OPS;
+use crate::log::MAX_REPLICAS_PER_LOG;
+use crate::replica::{Replica, ReplicaToken, MAX_THREADS_PER_REPLICA};
+use crate::{Dispatch, Error};
+
+/// A replica watched by a [Progress](struct.Progress.html).
+struct Watched<'a, D, const MAX_REPLICAS: usize, const MAX_THREADS: usize, const MAX_PENDING: usize>
+where
+    D: Sized + Dispatch + Sync,
+{
+    replica: Arc<Replica<'a, D, MAX_REPLICAS, MAX_THREADS, MAX_PENDING>>,
+
+    /// The thread registered with `replica` to sync it.
+    token: ReplicaToken,
+
+    /// The local tail of `replica` when it was last polled.
+    ltail: usize,
+}
+
+/// Syncs replicas whose threads went idle, so that they don't hold back
+/// garbage collection on the shared log.
+///
+/// # Example
+///
+/// ```
+/// use node_replication::progress::Progress;
+/// use node_replication::Dispatch;
+/// use node_replication::Log;
+/// use node_replication::Replica;
+///
+/// use std::sync::Arc;
+///
+/// #[derive(Default)]
+/// struct Data {
+///     junk: u64,
+/// }
+///
+/// impl Dispatch for Data {
+///     type ReadOperation = ();
+///     type WriteOperation = u64;
+///     type Response = u64;
+///
+///     fn dispatch(&self, _op: Self::ReadOperation) -> Self::Response {
+///         self.junk
+///     }
+///
+///     fn dispatch_mut(&mut self, op: Self::WriteOperation) -> Self::Response {
+///         self.junk = op;
+///         op
+///     }
+/// }
+///
+/// let log = Arc::new(Log::<<Data as Dispatch>::WriteOperation>::default());
+/// let busy = Replica::<Data>::new(&log);
+/// let idle = Replica::<Data>::new(&log);
+///
+/// let mut progress = Progress::new();
+/// progress.watch(&idle).expect("Failed to register with replica.");
+///
+/// let idx = busy.register().expect("Failed to register with replica.");
+/// busy.execute_mut(100, idx);
+///
+/// // `idle` didn't execute the write yet, `poll` syncs it.
+/// assert_eq!(progress.poll(), 1);
+/// assert_eq!(progress.poll(), 0);
+/// ```
+pub struct Progress<
+    'a,
+    D,
+    const MAX_REPLICAS: usize = MAX_REPLICAS_PER_LOG,
+    const MAX_THREADS: usize = MAX_THREADS_PER_REPLICA,
+    const MAX_PENDING: usize = MAX_PENDING_OPS,
+> where
+    D: Sized + Dispatch + Sync,
+{
+    replicas: Vec<Watched<'a, D, MAX_REPLICAS, MAX_THREADS, MAX_PENDING>>,
+}
+
+impl<'a, D, const MAX_REPLICAS: usize, const MAX_THREADS: usize, const MAX_PENDING: usize>
+    Progress<'a, D, MAX_REPLICAS, MAX_THREADS, MAX_PENDING>
+where
+    D: Sized + Dispatch + Sync,
+{
+    /// Creates a `Progress` that doesn't watch any replicas yet.
+    pub fn new() -> Self {
+        Progress {
+            replicas: Vec::new(),
+        }
+    }
+
+    /// Starts watching `replica`. Registers a thread with the replica, which is
+    /// used to sync it and unregistered again when the `Progress` is dropped.
+    pub fn watch(
+        &mut self,
+        replica: &Arc<Replica<'a, D, MAX_REPLICAS, MAX_THREADS, MAX_PENDING>>,
+    ) -> Result<(), Error> {
+        let token = replica.try_register()?;
+        let (ltail, _) = replica.tails();
+        self.replicas.push(Watched {
+            replica: replica.clone(),
+            token,
+            ltail,
+        });
+        Ok(())
+    }
+
+    /// Syncs every watched replica that is behind the log and didn't execute any
+    /// operations since the last call. Returns the number of replicas it synced.
+    ///
+    /// Doesn't wait for replicas that another thread is combining on; that thread
+    /// brings them up to date. Replicas that fail to sync are no longer watched.
+    pub fn poll(&mut self) -> usize {
+        let mut synced = 0;
+        let mut i = 0;
+        while i < self.replicas.len() {
+            let w = &mut self.replicas[i];
+            let (ltail, ctail) = w.replica.tails();
+            if ltail == w.ltail && ltail < ctail {
+                if let Err(e) = w.replica.try_catch_up(w.token) {
+                    warn!("Stopped watching replica {}: {}", w.replica.id(), e);
+                    self.replicas.swap_remove(i);
+                    continue;
+                }
+                synced += 1;
+            }
+
+            w.ltail = w.replica.tails().0;
+            i += 1;
+        }
+        synced
+    }
+}
+
+impl<'a, D, const MAX_REPLICAS: usize, const MAX_THREADS: usize, const MAX_PENDING: usize> Default
+    for Progress<'a, D, MAX_REPLICAS, MAX_THREADS, MAX_PENDING>
+where
+    D: Sized + Dispatch + Sync,
+{
+    fn default() -> Self {
+        Progress::new()
+    }
+}
+
+impl<'a, D, const MAX_REPLICAS: usize, const MAX_THREADS: usize, const MAX_PENDING: usize> Drop
+    for Progress<'a, D, MAX_REPLICAS, MAX_THREADS, MAX_PENDING>
+where
+    D: Sized + Dispatch + Sync,
+{
+    fn drop(&mut self) {
+        for w in self.replicas.iter() {
+            w.replica.unregister(w.token);
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl<D, const MAX_REPLICAS: usize, const MAX_THREADS: usize, const MAX_PENDING: usize>
+    Progress<'static, D, MAX_REPLICAS, MAX_THREADS, MAX_PENDING>
+where
+    D: Sized + Dispatch + Sync + Send + 'static,
+    <D as Dispatch>::Response: Send,
+{
+    /// Moves the `Progress` to a background thread, which polls the watched
+    /// replicas every `interval` until the returned handle is dropped.
+    pub fn spawn(mut self, interval: Duration) -> ProgressThread {
+        let stop = Arc::new(AtomicBool::new(false));
+        let stopped = stop.clone();
+        let handle = std::thread::Builder::new()
+            .name("nr-progress".into())
+            .spawn(move || {
+                while !stopped.load(Ordering::Acquire) {
+                    self.poll();
+                    std::thread::park_timeout(interval);
+                }
+            })
+            .expect("Failed to spawn the progress thread.");
+
+        ProgressThread {
+            stop,
+            handle: Some(handle),
+        }
+    }
+}
+
+/// Handle of the thread started by `Progress::spawn`. Dropping it stops the
+/// thread, which unregisters from the watched replicas.
+#[cfg(feature = "std")]
+pub struct ProgressThread {
+    stop: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>,
+}
+
+#[cfg(feature = "std")]
+impl Drop for ProgressThread {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Release);
+        if let Some(handle) = self.handle.take() {
+            handle.thread().unpark();
+            let _ = handle.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate std;
+
+    use super::*;
+    use crate::Log;
+
+    #[derive(Default)]
+    struct Data {
+        junk: u64,
+    }
+
+    impl Dispatch for Data {
+        type ReadOperation = ();
+        type WriteOperation = u64;
+        type Response = u64;
+
+        fn dispatch(&self, _op: Self::ReadOperation) -> Self::Response {
+            self.junk
+        }
+
+        fn dispatch_mut(&mut self, op: Self::WriteOperation) -> Self::Response {
+            self.junk += op;
+            self.junk
+        }
+    }
+
+    // Tests that poll syncs idle replicas, but leaves busy ones alone.
+    #[test]
+    fn test_progress_poll() {
+        let slog = Arc::new(Log::<<Data as Dispatch>::WriteOperation>::new(1024 * 1024));
+        let busy = Replica::<Data>::new(&slog);
+        let idle = Replica::<Data>::new(&slog);
+
+        let mut progress = Progress::new();
+        progress.watch(&busy).unwrap();
+        progress.watch(&idle).unwrap();
+        assert_eq!(progress.poll(), 0);
+
+        let idx = busy.register().unwrap();
+        for _i in 0..10 {
+            busy.execute_mut(1, idx);
+        }
+        assert_eq!(progress.poll(), 1);
+        assert_eq!(idle.tails(), busy.tails());
+        assert_eq!(progress.poll(), 0);
+
+        // `busy` is behind now, but executed operations since the last poll.
+        busy.execute_mut(1, idx);
+        let ridx = idle.register().unwrap();
+        idle.execute_mut(1, ridx);
+        assert!(busy.tails().0 < idle.tails().0);
+        assert_eq!(progress.poll(), 0);
+        assert_eq!(progress.poll(), 1);
+        assert_eq!(busy.tails(), idle.tails());
+    }
+
+    // Tests that dropping a Progress unregisters it from the watched replicas.
+    #[test]
+    fn test_progress_drop_unregisters() {
+        let slog = Arc::new(Log::<<Data as Dispatch>::WriteOperation>::new(1024 * 1024));
+        let replica = Replica::<Data>::new(&slog);
+
+        let mut progress = Progress::new();
+        progress.watch(&replica).unwrap();
+        drop(progress);
+
+        assert_eq!(replica.register().map(|idx| idx.id()), Some(1));
+    }
+
+    // Tests that the progress thread keeps a small log from filling up while
+    // one of the replicas is idle.
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_progress_spawn() {
+        let slog = Arc::new(Log::<<Data as Dispatch>::WriteOperation>::new(
+            4 * 1024 * core::mem::size_of::<usize>(),
+        ));
+        let busy = Replica::<Data>::new(&slog);
+        let idle = Replica::<Data>::new(&slog);
+
+        let mut progress = Progress::new();
+        progress.watch(&idle).unwrap();
+        let thread = progress.spawn(Duration::from_millis(1));
+
+        let idx = busy.register().unwrap();
+        for _i in 0..100_000 {
+            busy.execute_mut(1, idx);
+        }
+        drop(thread);
+
+        let ridx = idle.register().unwrap();
+        assert_eq!(ridx.id(), 1);
+        assert_eq!(idle.execute((), ridx), 100_000);
+    }
+}
@@ -0,0 +1,694 @@
+// Copyright © 2019-2020 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Recording and deterministic replay of the operations executed by a replica.
+//!
+//! `Replica::record` makes a replica write every operation it executes to a
+//! recording, together with the response it computed: the write operations in
+//! the order of the shared log along with their [WriteContext](../struct.WriteContext.html),
+//! and the read operations issued on the replica along with their position
+//! relative to the writes.
+//!
+//! [replay](fn.replay.html) feeds a recording into a data structure that starts
+//! out from the same state, and reports every operation whose response differs
+//! from the recorded one. This makes it possible to debug a `Dispatch`
+//! implementation offline, for example one whose `dispatch_mut` depends on
+//! something other than the operation and the state of the data structure.
+//!
+//! A recording starts with a magic number and is followed by a sequence of
+//! records. Every record starts with a header holding the kind of operation,
+//! its position, the issuing replica and thread, its timestamp, the lengths of the encoded
+//! operation and response and a checksum, followed by the operation and the
+//! response as produced by [Persistent::encode](../persist/trait.Persistent.html#tymethod.encode).
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::convert::TryInto;
+use core::fmt;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::time::Duration;
+
+use std::io::{self, Read, Write};
+use std::sync::Mutex;
+
+use crate::persist::{checksum, Persistent};
+use crate::{Dispatch, Replica, ReplicaToken, WriteContext};
+
+/// Identifies a recording written by `Replica::record`.
+const RECORDING_MAGIC: [u8; 8] = *b"NRREC001";
+
+/// Size of the header in front of every record: the kind (1 byte), the position,
+/// replica, thread and timestamp (8 bytes each), the lengths of the encoded
+/// operation and response (4 bytes each) and their checksum (4 bytes).
+const RECORD_HEADER: usize = 45;
+
+/// Stands in for the timestamp of an operation that doesn't have one.
+const NO_TIMESTAMP: u64 = u64::MAX;
+
+/// Kind of a record holding a write operation.
+const WRITE: u8 = 0;
+/// Kind of a record holding a read operation.
+const READ: u8 = 1;
+
+/// Receives the operations executed by a replica while it is being recorded.
+pub(crate) trait Sink<D: Dispatch>: Send {
+    /// Records the next write operation on the log.
+    fn write(
+        &mut self,
+        ctx: &WriteContext,
+        op: &<D as Dispatch>::WriteOperation,
+        resp: &<D as Dispatch>::Response,
+    );
+
+    /// Records a read operation issued by `thread`.
+    fn read(
+        &mut self,
+        thread: usize,
+        op: &<D as Dispatch>::ReadOperation,
+        resp: &<D as Dispatch>::Response,
+    );
+
+    /// Flushes the recording and returns the first error that occurred while
+    /// writing it.
+    fn finish(self: Box<Self>) -> io::Result<()>;
+}
+
+/// The recording a replica writes to, if any.
+pub(crate) struct Recorder<D: Dispatch> {
+    /// True while `sink` holds a recording.
+    active: AtomicBool,
+
+    /// The recording. Only locked while the replica is being recorded.
+    sink: Mutex<Option<Box<dyn Sink<D>>>>,
+}
+
+impl<D: Dispatch> Recorder<D> {
+    pub(crate) fn new() -> Recorder<D> {
+        Recorder {
+            active: AtomicBool::new(false),
+            sink: Mutex::new(None),
+        }
+    }
+
+    /// Returns true if the replica is being recorded.
+    #[inline(always)]
+    pub(crate) fn is_active(&self) -> bool {
+        self.active.load(Ordering::Relaxed)
+    }
+
+    /// See `Sink::write`.
+    pub(crate) fn write(
+        &self,
+        ctx: &WriteContext,
+        op: &<D as Dispatch>::WriteOperation,
+        resp: &<D as Dispatch>::Response,
+    ) {
+        if let Some(sink) = self.sink.lock().unwrap().as_mut() {
+            sink.write(ctx, op, resp);
+        }
+    }
+
+    /// See `Sink::read`.
+    pub(crate) fn read(
+        &self,
+        thread: usize,
+        op: &<D as Dispatch>::ReadOperation,
+        resp: &<D as Dispatch>::Response,
+    ) {
+        if let Some(sink) = self.sink.lock().unwrap().as_mut() {
+            sink.read(thread, op, resp);
+        }
+    }
+
+    /// Starts writing to `sink`. Must be called while the replica's local tail
+    /// can't move.
+    fn start(&self, sink: Box<dyn Sink<D>>) {
+        *self.sink.lock().unwrap() = Some(sink);
+        self.active.store(true, Ordering::Relaxed);
+    }
+
+    /// Stops recording. Returns `None` if the replica wasn't being recorded.
+    fn stop(&self) -> Option<io::Result<()>> {
+        self.active.store(false, Ordering::Relaxed);
+        let sink = self.sink.lock().unwrap().take();
+        sink.map(|s| s.finish())
+    }
+}
+
+/// Writes the records of a replica to `W`.
+struct Recording<W: Write> {
+    /// Where the records go.
+    w: W,
+
+    /// Identifier of the recorded replica on the log.
+    replica: usize,
+
+    /// Logical log index of the next write operation.
+    pos: usize,
+
+    /// Staging buffer for a record, so that every record is written with a
+    /// single call.
+    buffer: Vec<u8>,
+
+    /// The first error that occurred while writing to `w`. Nothing is written
+    /// after an error.
+    error: Option<io::Error>,
+}
+
+impl<W: Write> Recording<W> {
+    /// Writes a record; `encode` appends the encoded operation and response to
+    /// the buffer and returns the length of the operation.
+    fn record<F: FnOnce(&mut Vec<u8>) -> usize>(
+        &mut self,
+        kind: u8,
+        ctx: &WriteContext,
+        encode: F,
+    ) {
+        if self.error.is_some() {
+            return;
+        }
+
+        self.buffer.clear();
+        self.buffer.extend_from_slice(&[0u8; RECORD_HEADER]);
+        let op = encode(&mut self.buffer);
+        let resp = self.buffer.len() - RECORD_HEADER - op;
+        let sum = checksum(&self.buffer[RECORD_HEADER..]);
+
+        let timestamp = ctx.timestamp.map_or(NO_TIMESTAMP, |t| t.as_nanos() as u64);
+        self.buffer[0] = kind;
+        self.buffer[1..9].copy_from_slice(&(ctx.pos as u64).to_le_bytes());
+        self.buffer[9..17].copy_from_slice(&(ctx.replica as u64).to_le_bytes());
+        self.buffer[17..25].copy_from_slice(&(ctx.thread as u64).to_le_bytes());
+        self.buffer[25..33].copy_from_slice(&timestamp.to_le_bytes());
+        self.buffer[33..37].copy_from_slice(&(op as u32).to_le_bytes());
+        self.buffer[37..41].copy_from_slice(&(resp as u32).to_le_bytes());
+        self.buffer[41..45].copy_from_slice(&sum.to_le_bytes());
+
+        if let Err(e) = self.w.write_all(&self.buffer) {
+            warn!("Failed to write to the recording, stopped recording: {}", e);
+            self.error = Some(e);
+        }
+    }
+}
+
+impl<D, W> Sink<D> for Recording<W>
+where
+    D: Dispatch,
+    <D as Dispatch>::ReadOperation: Persistent,
+    <D as Dispatch>::WriteOperation: Persistent,
+    <D as Dispatch>::Response: Persistent,
+    W: Write + Send,
+{
+    fn write(
+        &mut self,
+        ctx: &WriteContext,
+        op: &<D as Dispatch>::WriteOperation,
+        resp: &<D as Dispatch>::Response,
+    ) {
+        self.record(WRITE, ctx, |buf| {
+            let start = buf.len();
+            op.encode(buf);
+            let len = buf.len() - start;
+            resp.encode(buf);
+            len
+        });
+        self.pos = ctx.pos + 1;
+    }
+
+    fn read(
+        &mut self,
+        thread: usize,
+        op: &<D as Dispatch>::ReadOperation,
+        resp: &<D as Dispatch>::Response,
+    ) {
+        let ctx = WriteContext {
+            pos: self.pos,
+            replica: self.replica,
+            thread,
+            timestamp: None,
+        };
+        self.record(READ, &ctx, |buf| {
+            let start = buf.len();
+            op.encode(buf);
+            let len = buf.len() - start;
+            resp.encode(buf);
+            len
+        });
+    }
+
+    fn finish(mut self: Box<Self>) -> io::Result<()> {
+        match self.error.take() {
+            Some(e) => Err(e),
+            None => self.w.flush(),
+        }
+    }
+}
+
+impl<'a, D, const MAX_REPLICAS: usize, const MAX_THREADS: usize, const MAX_PENDING: usize>
+    Replica<'a, D, MAX_REPLICAS, MAX_THREADS, MAX_PENDING>
+where
+    D: Sized + Dispatch + Sync,
+    <D as Dispatch>::ReadOperation: Persistent,
+    <D as Dispatch>::WriteOperation: Persistent,
+    <D as Dispatch>::Response: Persistent,
+{
+    /// Starts recording the operations this replica executes, and their responses,
+    /// to `w`. Returns the logical log index the recording starts at. `idx` is
+    /// the token of the calling thread.
+    ///
+    /// Every operation costs an additional clone and a write to `w` while the
+    /// replica is being recorded, so `w` should be buffered. A recording can be
+    /// [replayed](record/fn.replay.html) starting from the state the data
+    /// structure had when the recording started; for a replica that is recorded
+    /// before any operation is appended to the log, that's the state it was
+    /// created with.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use node_replication::{Dispatch, Log, Replica};
+    /// use node_replication::record;
+    ///
+    /// use std::fs::File;
+    /// use std::io::BufWriter;
+    /// use std::sync::Arc;
+    ///
+    /// #[derive(Default)]
+    /// struct Data {
+    ///     junk: u64,
+    /// }
+    ///
+    /// impl Dispatch for Data {
+    ///     type ReadOperation = u64;
+    ///     type WriteOperation = u64;
+    ///     type Response = u64;
+    ///
+    ///     fn dispatch(&self, op: Self::ReadOperation) -> Self::Response {
+    ///         self.junk + op
+    ///     }
+    ///
+    ///     fn dispatch_mut(&mut self, op: Self::WriteOperation) -> Self::Response {
+    ///         self.junk += op;
+    ///         self.junk
+    ///     }
+    /// }
+    ///
+    /// let replica = Replica::<Data>::new(&Arc::new(Log::<u64>::new(1024 * 1024)));
+    /// let idx = replica.register().unwrap();
+    ///
+    /// let path = std::env::temp_dir().join("nr-record-example");
+    /// let file = BufWriter::new(File::create(&path).unwrap());
+    /// replica.record(idx, file).unwrap();
+    /// replica.execute_mut(40, idx);
+    /// replica.execute(2, idx);
+    /// replica.stop_recording().unwrap();
+    ///
+    /// // Later, and possibly elsewhere.
+    /// let replay = record::replay(File::open(&path).unwrap(), Data::default()).unwrap();
+    /// assert_eq!(replay.operations, 2);
+    /// assert!(replay.divergences.is_empty());
+    /// # std::fs::remove_file(&path).unwrap();
+    /// ```
+    pub fn record<W: Write + Send + 'static>(
+        &self,
+        idx: ReplicaToken,
+        mut w: W,
+    ) -> io::Result<usize> {
+        w.write_all(&RECORDING_MAGIC)?;
+
+        // The local tail doesn't move while the recording is installed, so its
+        // first write operation is the one at the local tail.
+        Ok(self.synced(idx.id(), |_data, ltail| {
+            self.recorder.start(Box::new(Recording {
+                w,
+                replica: self.id(),
+                pos: ltail,
+                buffer: Vec::new(),
+                error: None,
+            }));
+            ltail
+        }))
+    }
+
+    /// Stops recording this replica, and flushes the recording. Returns the first
+    /// error that occurred while writing it.
+    ///
+    /// Succeeds without doing anything if the replica isn't being recorded.
+    pub fn stop_recording(&self) -> io::Result<()> {
+        self.recorder.stop().unwrap_or(Ok(()))
+    }
+}
+
+/// An operation in a recording.
+pub enum Operation<D: Dispatch> {
+    /// A write operation, executed by all replicas in log order.
+    Write(<D as Dispatch>::WriteOperation),
+
+    /// A read operation, executed on the recorded replica.
+    Read(<D as Dispatch>::ReadOperation),
+}
+
+// Implemented by hand; deriving would require `D` itself to implement these.
+impl<D: Dispatch> Clone for Operation<D> {
+    fn clone(&self) -> Self {
+        match self {
+            Operation::Write(op) => Operation::Write(op.clone()),
+            Operation::Read(op) => Operation::Read(op.clone()),
+        }
+    }
+}
+
+impl<D: Dispatch> fmt::Debug for Operation<D> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Operation::Write(op) => f.debug_tuple("Write").field(op).finish(),
+            Operation::Read(op) => f.debug_tuple("Read").field(op).finish(),
+        }
+    }
+}
+
+impl<D: Dispatch> PartialEq for Operation<D> {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Operation::Write(a), Operation::Write(b)) => a == b,
+            (Operation::Read(a), Operation::Read(b)) => a == b,
+            _ => false,
+        }
+    }
+}
+
+/// An operation whose response during replay differed from the recorded one.
+pub struct Divergence<D: Dispatch> {
+    /// The context of a write operation. For a read operation, `pos` is the
+    /// logical index of the first write operation it didn't see, `replica` is
+    /// the recorded replica and there is no `timestamp`.
+    pub ctx: WriteContext,
+
+    /// The operation.
+    pub op: Operation<D>,
+
+    /// The response that was recorded.
+    pub expected: <D as Dispatch>::Response,
+
+    /// The response during replay.
+    pub actual: <D as Dispatch>::Response,
+}
+
+/// The outcome of [replay](fn.replay.html).
+pub struct Replay<D: Dispatch> {
+    /// The number of operations that were replayed.
+    pub operations: usize,
+
+    /// The operations whose response differed from the recorded one, in the order
+    /// they were replayed in.
+    pub divergences: Vec<Divergence<D>>,
+}
+
+/// A record read back from a recording.
+struct Record<'r> {
+    kind: u8,
+    ctx: WriteContext,
+    op: &'r [u8],
+    resp: &'r [u8],
+}
+
+/// Splits `contents` into records. A record that was only partially written ends
+/// the recording.
+fn parse(contents: &[u8]) -> io::Result<Vec<Record>> {
+    let invalid = |msg| io::Error::new(io::ErrorKind::InvalidData, msg);
+    if contents.len() < RECORDING_MAGIC.len() || contents[0..8] != RECORDING_MAGIC {
+        return Err(invalid("not a recording"));
+    }
+
+    let mut records = Vec::new();
+    let mut offset = RECORDING_MAGIC.len();
+    while contents.len() - offset >= RECORD_HEADER {
+        let header = &contents[offset..offset + RECORD_HEADER];
+        let field = |r: core::ops::Range<usize>| u64::from_le_bytes(header[r].try_into().unwrap());
+        let op = u32::from_le_bytes(header[33..37].try_into().unwrap()) as usize;
+        let resp = u32::from_le_bytes(header[37..41].try_into().unwrap()) as usize;
+        let sum = u32::from_le_bytes(header[41..45].try_into().unwrap());
+
+        let start = offset + RECORD_HEADER;
+        let end = start + op + resp;
+        if end > contents.len() || checksum(&contents[start..end]) != sum {
+            warn!(
+                "Ignoring a partially written record at offset {} in the recording",
+                offset
+            );
+            break;
+        }
+
+        let timestamp = field(25..33);
+        records.push(Record {
+            kind: header[0],
+            ctx: WriteContext {
+                pos: field(1..9) as usize,
+                replica: field(9..17) as usize,
+                thread: field(17..25) as usize,
+                timestamp: if timestamp == NO_TIMESTAMP {
+                    None
+                } else {
+                    Some(Duration::from_nanos(timestamp))
+                },
+            },
+            op: &contents[start..start + op],
+            resp: &contents[start + op..end],
+        });
+        offset = end;
+    }
+
+    Ok(records)
+}
+
+/// Replays a recording written by `Replica::record` on `data`, and compares the
+/// responses with the recorded ones. `data` must be in the state the recorded
+/// replica was in when the recording started.
+///
+/// Write operations are replayed in log order, and passed to
+/// `Dispatch::dispatch_mut_with` along with the context they had on the log. A
+/// read operation is replayed right after the write operations the recorded
+/// replica had executed when it was issued. Responses are compared by their
+/// encoding, so `Response` doesn't need to implement `PartialEq`.
+///
+/// Fails if the recording can't be read or decoded, or if write operations are
+/// missing from it.
+pub fn replay<D, R>(mut recording: R, mut data: D) -> io::Result<Replay<D>>
+where
+    D: Sized + Dispatch,
+    <D as Dispatch>::ReadOperation: Persistent,
+    <D as Dispatch>::WriteOperation: Persistent,
+    <D as Dispatch>::Response: Persistent,
+    R: Read,
+{
+    let invalid = |msg| io::Error::new(io::ErrorKind::InvalidData, msg);
+
+    let mut contents = Vec::new();
+    recording.read_to_end(&mut contents)?;
+    let mut records = parse(&contents)?;
+
+    // Reads are recorded by the threads that issue them, so they can end up in
+    // the recording after writes they didn't see.
+    records.sort_by_key(|r| (r.ctx.pos, r.kind == WRITE));
+    let mut writes = records
+        .iter()
+        .filter(|r| r.kind == WRITE)
+        .map(|r| r.ctx.pos);
+    if let Some(first) = writes.next() {
+        if writes
+            .zip(first + 1..)
+            .any(|(pos, expected)| pos != expected)
+        {
+            return Err(invalid("write operations are missing from the recording"));
+        }
+    }
+
+    let mut divergences = Vec::new();
+    let mut actual_bytes = Vec::new();
+    for r in &records {
+        let (op, actual) = match r.kind {
+            WRITE => {
+                let op = <D as Dispatch>::WriteOperation::decode(r.op)
+                    .ok_or_else(|| invalid("failed to decode a write operation"))?;
+                (
+                    Operation::Write(op.clone()),
+                    data.dispatch_mut_with(op, &r.ctx),
+                )
+            }
+            READ => {
+                let op = <D as Dispatch>::ReadOperation::decode(r.op)
+                    .ok_or_else(|| invalid("failed to decode a read operation"))?;
+                (Operation::Read(op.clone()), data.dispatch(op))
+            }
+            _ => return Err(invalid("unknown kind of record")),
+        };
+
+        actual_bytes.clear();
+        actual.encode(&mut actual_bytes);
+        if actual_bytes[..] != *r.resp {
+            let expected = <D as Dispatch>::Response::decode(r.resp)
+                .ok_or_else(|| invalid("failed to decode a response"))?;
+            divergences.push(Divergence {
+                ctx: r.ctx,
+                op,
+                expected,
+                actual,
+            });
+        }
+    }
+
+    Ok(Replay {
+        operations: records.len(),
+        divergences,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Log;
+    use std::sync::Arc;
+
+    // A counter whose writes behave differently on a "faulty" instance. The
+    // response to a write also depends on its position on the log.
+    #[derive(Default)]
+    struct Counter {
+        value: u64,
+        faulty: bool,
+    }
+
+    impl Dispatch for Counter {
+        type ReadOperation = u64;
+        type WriteOperation = u64;
+        type Response = u64;
+
+        fn dispatch(&self, op: Self::ReadOperation) -> Self::Response {
+            self.value * op
+        }
+
+        fn dispatch_mut(&mut self, op: Self::WriteOperation) -> Self::Response {
+            if self.faulty && op == 3 {
+                self.value += 1;
+            }
+            self.value += op;
+            self.value
+        }
+
+        fn dispatch_mut_with(&mut self, op: Self::WriteOperation, ctx: &WriteContext) -> u64 {
+            self.dispatch_mut(op) + 1000 * ctx.pos as u64
+        }
+    }
+
+    // A buffer the test can still read after the replica is done writing to it.
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    // Records two replicas sharing a log from the point of view of the first one.
+    fn record_counter() -> Vec<u8> {
+        let recording = Arc::new(Mutex::new(Vec::new()));
+
+        let log = Arc::new(Log::<u64>::new(1024 * 1024));
+        let one = Replica::<Counter>::new(&log);
+        let two = Replica::<Counter>::new(&log);
+        let t1 = one.register().unwrap();
+        let t2 = one.register().unwrap();
+        let t3 = two.register().unwrap();
+
+        two.execute_mut(100, t3);
+        assert_eq!(one.record(t1, Shared(recording.clone())).unwrap(), 1);
+        one.execute_mut(1, t1);
+        one.execute_mut(2, t2);
+        two.execute_mut(3, t3);
+        assert_eq!(one.execute(2, t2), 212);
+        one.execute_mut(4, t1);
+        one.stop_recording().unwrap();
+        one.execute_mut(5, t1);
+
+        Arc::try_unwrap(recording).unwrap().into_inner().unwrap()
+    }
+
+    // Tests that replaying a recording from the right state doesn't diverge, and
+    // that the records hold the issuing replicas and threads.
+    #[test]
+    fn test_record_replay() {
+        let recording = record_counter();
+        let records = parse(&recording).unwrap();
+        let summary: Vec<(u8, usize, usize, usize)> = records
+            .iter()
+            .map(|r| (r.kind, r.ctx.pos, r.ctx.replica, r.ctx.thread))
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                (WRITE, 1, 1, 1),
+                (WRITE, 2, 1, 2),
+                (WRITE, 3, 2, 1),
+                (READ, 4, 1, 2),
+                (WRITE, 4, 1, 1)
+            ]
+        );
+
+        let replay = replay(
+            &recording[..],
+            Counter {
+                value: 100,
+                faulty: false,
+            },
+        )
+        .unwrap();
+        assert_eq!(replay.operations, 5);
+        assert!(replay.divergences.is_empty());
+    }
+
+    // Tests that replay reports every response that differs from the recording.
+    #[test]
+    fn test_replay_divergence() {
+        let recording = record_counter();
+        let replay = replay(
+            &recording[..],
+            Counter {
+                value: 100,
+                faulty: true,
+            },
+        )
+        .unwrap();
+
+        let divergences: Vec<(usize, usize, Operation<Counter>, u64, u64)> = replay
+            .divergences
+            .into_iter()
+            .map(|d| (d.ctx.pos, d.ctx.thread, d.op, d.expected, d.actual))
+            .collect();
+        assert_eq!(
+            divergences,
+            [
+                (3, 1, Operation::Write(3), 3106, 3107),
+                (4, 2, Operation::Read(2), 212, 214),
+                (4, 1, Operation::Write(4), 4110, 4111)
+            ]
+        );
+    }
+
+    // Tests that a recording with a missing write operation is rejected.
+    #[test]
+    fn test_replay_missing_write() {
+        let recording = record_counter();
+        let records = parse(&recording).unwrap();
+        let size = |r: &Record| RECORD_HEADER + r.op.len() + r.resp.len();
+        let second = RECORDING_MAGIC.len() + size(&records[0]);
+        let next = second + size(&records[1]);
+
+        let mut broken = recording[..second].to_vec();
+        broken.extend_from_slice(&recording[next..]);
+        let e = replay(&broken[..], Counter::default()).err().unwrap();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -2,11 +2,14 @@
 // SPDX-License-Identifier: Apache-2.0 OR MIT
 
 use core::cell::RefCell;
+use core::future::{poll_fn, Future};
 use core::hint::spin_loop;
 #[cfg(not(loom))]
-use core::sync::atomic::{AtomicUsize, Ordering};
+use core::sync::atomic::{fence, AtomicBool, AtomicUsize, Ordering};
+use core::task::Poll;
+use core::time::Duration;
 #[cfg(loom)]
-use loom::sync::atomic::{AtomicUsize, Ordering};
+use loom::sync::atomic::{fence, AtomicBool, AtomicUsize, Ordering};
 
 #[cfg(not(loom))]
 use alloc::sync::Arc;
@@ -17,18 +20,32 @@ use alloc::vec::Vec;
 
 use crossbeam_utils::CachePadded;
 
-use super::context::Context;
-use super::log::Log;
+use super::clock::Clock;
+#[cfg(feature = "std")]
+use super::clock::StdClock;
+use super::context::{Context, MAX_PENDING_OPS};
+use super::error::Error;
+use super::log::{Log, MAX_REPLICAS_PER_LOG};
+use super::placement::{Global, Placed, Placement};
+#[cfg(feature = "persistence")]
+use super::record::Recorder;
 use super::rwlock::RwLock;
-use super::Dispatch;
+#[cfg(feature = "stats")]
+use super::stats::{ReplicaCounters, ReplicaStats};
+use super::wait::{Spin, WaitStrategy};
 use super::ReusableBoxFuture;
+use super::{Dispatch, WriteContext};
 
 /// A token handed out to threads registered with replicas.
 ///
+/// A thread that is done with a replica hands its token back with
+/// `Replica::unregister`, so that the replica can give the slot to another thread.
+///
 /// # Note
 /// Ideally this would be an affine type and returned again by
 /// `execute` and `execute_ro`. However it feels like this would
-/// hurt API ergonomics a lot.
+/// hurt API ergonomics a lot. For the same reason, tokens aren't
+/// released when they are dropped.
 #[derive(Copy, Clone, Debug, PartialEq)]
 pub struct ReplicaToken(usize);
 
@@ -54,12 +71,8 @@ impl ReplicaToken {
     }
 }
 
-/// The maximum number of threads that can be registered with a replica. If more than
-/// this number of threads try to register, the register() function will return None.
-///
-/// # Important
-/// If this number is adjusted due to the use of the `arr_macro::arr` macro we
-/// have to adjust the `256` literals in the `new` constructor of `Replica`.
+/// The default maximum number of threads that can be registered with a replica. If more
+/// than this number of threads try to register, the register() function will return None.
 #[cfg(not(loom))]
 pub const MAX_THREADS_PER_REPLICA: usize = 256;
 #[cfg(loom)]
@@ -68,19 +81,140 @@ const_assert!(
     MAX_THREADS_PER_REPLICA >= 1 && (MAX_THREADS_PER_REPLICA & (MAX_THREADS_PER_REPLICA - 1) == 0)
 );
 
+/// Buffers the combiner uses to coalesce a batch of operations, and to hand the
+/// issuers of coalesced operations their responses.
+struct Coalescing<D: Dispatch> {
+    /// The number of operations merged into each operation of the batch.
+    runs: Vec<usize>,
+
+    /// The original operations of every run longer than one, in order.
+    originals: Vec<<D as Dispatch>::WriteOperation>,
+
+    /// The number of coalesced operations attributed to every thread.
+    threads: Vec<usize>,
+
+    /// One response per original operation, along with its position on the log.
+    responses: Vec<(<D as Dispatch>::Response, usize)>,
+}
+
+impl<D: Dispatch> Coalescing<D> {
+    fn new() -> Self {
+        Coalescing {
+            runs: Vec::new(),
+            originals: Vec::new(),
+            threads: Vec::new(),
+            responses: Vec::new(),
+        }
+    }
+
+    /// Coalesces runs of adjacent operations in `ops` in place. `threads` holds
+    /// the number of operations issued by every thread, as passed to
+    /// `Log::append_with`. Returns false, leaving `ops` untouched, if nothing was
+    /// coalesced. Otherwise, `self.threads` holds the number of coalesced
+    /// operations issued by every thread.
+    fn coalesce(
+        &mut self,
+        ops: &mut Vec<<D as Dispatch>::WriteOperation>,
+        threads: &[usize],
+    ) -> bool {
+        // Most batches have nothing to merge; leave them alone without any
+        // bookkeeping.
+        let (first, op) = match (1..ops.len())
+            .find_map(|r| D::coalesce(&ops[r - 1], &ops[r]).map(|op| (r, op)))
+        {
+            Some(merged) => merged,
+            None => return false,
+        };
+
+        // Every operation before the first merged pair is a run of its own.
+        self.runs.clear();
+        self.runs.resize(first - 1, 1);
+        self.runs.push(2);
+        self.originals.clear();
+        let mut w = first - 1;
+        self.originals.push(core::mem::replace(&mut ops[w], op));
+        self.originals.push(ops[first].clone());
+
+        for r in first + 1..ops.len() {
+            if let Some(op) = D::coalesce(&ops[w], &ops[r]) {
+                let run = self.runs.last_mut().unwrap();
+                if *run == 1 {
+                    self.originals.push(core::mem::replace(&mut ops[w], op));
+                } else {
+                    ops[w] = op;
+                }
+                self.originals.push(ops[r].clone());
+                *run += 1;
+                continue;
+            }
+
+            w += 1;
+            ops.swap(w, r);
+            self.runs.push(1);
+        }
+        ops.truncate(w + 1);
+
+        // Attribute every coalesced operation to the issuer of the first
+        // operation of its run.
+        self.threads.clear();
+        self.threads.resize(threads.len(), 0);
+        let mut issuers = threads
+            .iter()
+            .enumerate()
+            .flat_map(|(t, n)| (0..*n).map(move |_i| t));
+        for run in self.runs.iter() {
+            self.threads[issuers.next().unwrap()] += 1;
+            for _i in 1..*run {
+                issuers.next();
+            }
+        }
+        true
+    }
+
+    /// Fills `self.responses` with the response of every original operation,
+    /// given the `results` of the coalesced operations.
+    fn split(&mut self, results: &[(<D as Dispatch>::Response, usize)]) {
+        debug_assert_eq!(self.runs.len(), results.len());
+        self.responses.clear();
+
+        let mut o = 0;
+        for (run, (resp, pos)) in self.runs.iter().zip(results.iter()) {
+            if *run == 1 {
+                self.responses.push((resp.clone(), *pos));
+                continue;
+            }
+
+            let ops = &self.originals[o..o + run];
+            for i in 0..*run {
+                self.responses.push((D::split_response(ops, i, resp), *pos));
+            }
+            o += run;
+        }
+    }
+}
+
 /// An instance of a replicated data structure. Uses a shared log to scale
 /// operations on the data structure across cores and processors.
 ///
 /// Takes in one type argument: `D` represents the underlying sequential data
 /// structure `D` must implement the `Dispatch` trait.
 ///
+/// The const parameters are the ones of the [Log](struct.Log.html) the replica
+/// uses. `MAX_THREADS` bounds the number of threads that can register with the
+/// replica and `MAX_PENDING` the number of operations each of them can batch.
+///
 /// A thread can be registered against the replica by calling `register()`. A
 /// mutable operation can be issued by calling `execute_mut()` (immutable uses
 /// `execute`). A mutable operation will be eventually executed against the replica
 /// along with any operations that were received on other replicas that share
 /// the same underlying log.
-pub struct Replica<'a, D>
-where
+pub struct Replica<
+    'a,
+    D,
+    const MAX_REPLICAS: usize = MAX_REPLICAS_PER_LOG,
+    const MAX_THREADS: usize = MAX_THREADS_PER_REPLICA,
+    const MAX_PENDING: usize = MAX_PENDING_OPS,
+> where
     D: Sized + Dispatch + Sync,
 {
     /// A replica-identifier received when the replica is registered against
@@ -95,11 +229,20 @@ where
     /// Idx that will be handed out to the next thread that registers with the replica.
     next: CachePadded<AtomicUsize>,
 
+    /// Bitmask of the threads that registered a waker with their context, so
+    /// that the combiner only wakes those when it's done. See `wake_missed`.
+    wakers: Vec<AtomicUsize>,
+
+    /// Set once a task registered a waker with one of the contexts. Until then,
+    /// combiners don't look for wakers at all. See `register_waker`.
+    async_waiters: AtomicBool,
+
     /// List of per-thread contexts. Threads buffer write operations in here when they
     /// cannot perform flat combining (because another thread might be doing so).
     ///
-    /// The vector is initialized with `MAX_THREADS_PER_REPLICA` elements.
-    contexts: Vec<Context<<D as Dispatch>::WriteOperation, <D as Dispatch>::Response>>,
+    /// The slice holds `MAX_THREADS` elements.
+    contexts:
+        Placed<[Context<<D as Dispatch>::WriteOperation, <D as Dispatch>::Response, MAX_PENDING>]>,
 
     /// A buffer of operations for flat combining. The combiner stages operations in
     /// here and then batch appends them into the shared log. This helps amortize
@@ -109,26 +252,49 @@ where
     /// Number of operations collected by the combiner from each thread at any
     /// given point of time. Index `i` holds the number of operations collected from
     /// thread with identifier `i + 1`.
-    inflight: RefCell<[usize; MAX_THREADS_PER_REPLICA]>,
+    inflight: RefCell<[usize; MAX_THREADS]>,
+
+    /// A buffer of results collected after flat combining, along with the logical
+    /// index of each operation on the log. With the help of `inflight`, the combiner
+    /// enqueues these results into the appropriate thread context.
+    result: RefCell<Vec<(<D as Dispatch>::Response, usize)>>,
 
-    /// A buffer of results collected after flat combining. With the help of `inflight`,
-    /// the combiner enqueues these results into the appropriate thread context.
-    result: RefCell<Vec<<D as Dispatch>::Response>>,
+    /// Buffers used by the combiner to coalesce operations, see
+    /// `Dispatch::coalesce`.
+    coalescing: RefCell<Coalescing<D>>,
 
     /// Reference to the shared log that operations will be appended to and the
     /// data structure will be updated from.
-    slog: Arc<Log<'a, <D as Dispatch>::WriteOperation>>,
+    slog: Arc<Log<'a, <D as Dispatch>::WriteOperation, MAX_REPLICAS, MAX_THREADS, MAX_PENDING>>,
 
     /// The underlying replicated data structure. Shared between threads registered
     /// with this replica. Each replica maintains its own.
-    data: CachePadded<RwLock<D>>,
+    data: Placed<CachePadded<RwLock<D, MAX_THREADS>>>,
+
+    /// Decides what threads do while they wait on the replica or the shared log.
+    wait: alloc::sync::Arc<dyn WaitStrategy>,
+
+    /// Counters about flat combining and reads on this replica.
+    #[cfg(feature = "stats")]
+    stats: ReplicaCounters,
+
+    /// Where the operations executed by this replica are recorded to, if
+    /// anywhere. See `Replica::record`.
+    #[cfg(feature = "persistence")]
+    pub(crate) recorder: Recorder<D>,
 }
 
 /// The Replica is Sync. Member variables are protected by a CAS on `combiner`.
 /// Contexts are thread-safe.
-unsafe impl<'a, D> Sync for Replica<'a, D> where D: Sized + Sync + Dispatch {}
+unsafe impl<'a, D, const MAX_REPLICAS: usize, const MAX_THREADS: usize, const MAX_PENDING: usize>
+    Sync for Replica<'a, D, MAX_REPLICAS, MAX_THREADS, MAX_PENDING>
+where
+    D: Sized + Sync + Dispatch,
+{
+}
 
-impl<'a, D> core::fmt::Debug for Replica<'a, D>
+impl<'a, D, const MAX_REPLICAS: usize, const MAX_THREADS: usize, const MAX_PENDING: usize>
+    core::fmt::Debug for Replica<'a, D, MAX_REPLICAS, MAX_THREADS, MAX_PENDING>
 where
     D: Sized + Sync + Dispatch,
 {
@@ -137,7 +303,8 @@ where
     }
 }
 
-impl<'a, D> Replica<'a, D>
+impl<'a, D, const MAX_REPLICAS: usize, const MAX_THREADS: usize, const MAX_PENDING: usize>
+    Replica<'a, D, MAX_REPLICAS, MAX_THREADS, MAX_PENDING>
 where
     D: Sized + Default + Dispatch + Sync,
 {
@@ -191,12 +358,111 @@ where
     /// // Create a replica that uses the above log.
     /// let replica = Replica::<Data>::new(&log);
     /// ```
-    pub fn new<'b>(log: &Arc<Log<'b, <D as Dispatch>::WriteOperation>>) -> Arc<Replica<'b, D>> {
+    pub fn new<'b>(
+        log: &Arc<Log<'b, <D as Dispatch>::WriteOperation, MAX_REPLICAS, MAX_THREADS, MAX_PENDING>>,
+    ) -> Arc<Replica<'b, D, MAX_REPLICAS, MAX_THREADS, MAX_PENDING>> {
         Replica::with_data(log, Default::default())
     }
+
+    /// Similar to [`Replica<D>::new`], but returns an error instead of panicking
+    /// if the shared log can't take another replica.
+    pub fn try_new<'b>(
+        log: &Arc<Log<'b, <D as Dispatch>::WriteOperation, MAX_REPLICAS, MAX_THREADS, MAX_PENDING>>,
+    ) -> Result<Arc<Replica<'b, D, MAX_REPLICAS, MAX_THREADS, MAX_PENDING>>, Error> {
+        Replica::try_with_data(log, Default::default())
+    }
+}
+
+impl<'a, D, const MAX_REPLICAS: usize, const MAX_THREADS: usize, const MAX_PENDING: usize>
+    Replica<'a, D, MAX_REPLICAS, MAX_THREADS, MAX_PENDING>
+where
+    D: Sized + Clone + Dispatch + Sync,
+{
+    /// Constructs a replica that joins the shared log of an `existing` replica after
+    /// operations have already been appended to (and possibly garbage collected from)
+    /// the log.
+    ///
+    /// Under the combiner lock of `existing`, the existing replica is brought up to
+    /// date with the log, its data structure is cloned and the new replica starts
+    /// consuming the log from the position the clone was taken at. `idx` is the
+    /// token of the calling thread on `existing`.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use node_replication::Dispatch;
+    /// use node_replication::Log;
+    /// use node_replication::Replica;
+    ///
+    /// use std::sync::Arc;
+    ///
+    /// #[derive(Default, Clone)]
+    /// struct Data {
+    ///     junk: u64,
+    /// }
+    ///
+    /// impl Dispatch for Data {
+    ///     type ReadOperation = ();
+    ///     type WriteOperation = u64;
+    ///     type Response = Option<u64>;
+    ///
+    ///     fn dispatch(
+    ///         &self,
+    ///         _op: Self::ReadOperation,
+    ///     ) -> Self::Response {
+    ///         Some(self.junk)
+    ///     }
+    ///
+    ///     fn dispatch_mut(
+    ///         &mut self,
+    ///         op: Self::WriteOperation,
+    ///     ) -> Self::Response {
+    ///         self.junk = op;
+    ///         None
+    ///     }
+    /// }
+    ///
+    /// let log = Arc::new(Log::<<Data as Dispatch>::WriteOperation>::default());
+    /// let replica = Replica::<Data>::new(&log);
+    /// let idx = replica.register().expect("Failed to register with replica.");
+    /// replica.execute_mut(100, idx);
+    ///
+    /// // The new replica starts out with a copy of `replica`'s data structure.
+    /// let joined = Replica::join_from(&replica, idx);
+    /// let jdx = joined.register().expect("Failed to register with replica.");
+    /// assert_eq!(joined.execute((), jdx), Some(100));
+    /// ```
+    pub fn join_from(existing: &Self, idx: ReplicaToken) -> Arc<Self> {
+        Replica::try_join_from(existing, idx).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    /// Similar to [`Replica<D>::join_from`], but returns
+    /// [`Error::ReplicasExhausted`](enum.Error.html) instead of panicking if the
+    /// shared log can't take another replica.
+    pub fn try_join_from(existing: &Self, idx: ReplicaToken) -> Result<Arc<Self>, Error> {
+        // The existing replica holds back garbage collection at `ltail` until
+        // its combiner lock is released again.
+        let (d, lidx) = existing
+            .synced(idx.0, |data, ltail| {
+                existing
+                    .slog
+                    .register_at(ltail)
+                    .map(|lidx| (data.clone(), lidx))
+            })
+            .ok_or(Error::ReplicasExhausted)?;
+
+        Ok(Replica::create(
+            &existing.slog,
+            lidx,
+            d,
+            existing.wait.clone(),
+            alloc::sync::Arc::new(Global),
+        ))
+    }
 }
 
-impl<'a, D> Replica<'a, D>
+impl<'a, D, const MAX_REPLICAS: usize, const MAX_THREADS: usize, const MAX_PENDING: usize>
+    Replica<'a, D, MAX_REPLICAS, MAX_THREADS, MAX_PENDING>
 where
     D: Sized + Dispatch + Sync,
 {
@@ -209,106 +475,305 @@ where
     /// If `with_data` is used, care must be taken that the same state is passed
     /// to every Replica object. If not the resulting operations executed
     /// against replicas may not give deterministic results.
-    #[cfg(not(feature = "unstable"))]
     pub fn with_data<'b>(
-        log: &Arc<Log<'b, <D as Dispatch>::WriteOperation>>,
+        log: &Arc<Log<'b, <D as Dispatch>::WriteOperation, MAX_REPLICAS, MAX_THREADS, MAX_PENDING>>,
         d: D,
-    ) -> Arc<Replica<'b, D>> {
-        let mut contexts = Vec::with_capacity(MAX_THREADS_PER_REPLICA);
-        // Add `MAX_THREADS_PER_REPLICA` contexts
-        for _idx in 0..MAX_THREADS_PER_REPLICA {
-            contexts.push(Default::default());
-        }
+    ) -> Arc<Replica<'b, D, MAX_REPLICAS, MAX_THREADS, MAX_PENDING>> {
+        Replica::try_with_data(log, d).unwrap_or_else(|e| panic!("{}", e))
+    }
 
-        Arc::new(
-            Replica {
-                idx: log.register().unwrap(),
-                combiner: CachePadded::new(AtomicUsize::new(0)),
-                next: CachePadded::new(AtomicUsize::new(1)),
-                contexts,
-                buffer:
-                    RefCell::new(
-                        Vec::with_capacity(
-                            MAX_THREADS_PER_REPLICA
-                                * Context::<
-                                    <D as Dispatch>::WriteOperation,
-                                    <D as Dispatch>::Response,
-                                >::batch_size(),
-                        ),
-                    ),
-                inflight: RefCell::new([0; MAX_THREADS_PER_REPLICA]),
-                result:
-                    RefCell::new(
-                        Vec::with_capacity(
-                            MAX_THREADS_PER_REPLICA
-                                * Context::<
-                                    <D as Dispatch>::WriteOperation,
-                                    <D as Dispatch>::Response,
-                                >::batch_size(),
-                        ),
-                    ),
-                slog: log.clone(),
-                data: CachePadded::new(RwLock::<D>::new(d)),
-            },
+    /// Similar to [`Replica<D>::with_data`], but returns
+    /// [`Error::ReplicasExhausted`](enum.Error.html) instead of panicking if the
+    /// shared log can't take another replica.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use node_replication::Dispatch;
+    /// use node_replication::Error;
+    /// use node_replication::Log;
+    /// use node_replication::Replica;
+    ///
+    /// use std::sync::Arc;
+    ///
+    /// #[derive(Default)]
+    /// struct Data {
+    ///     junk: u64,
+    /// }
+    ///
+    /// impl Dispatch for Data {
+    ///     type ReadOperation = ();
+    ///     type WriteOperation = u64;
+    ///     type Response = Option<u64>;
+    ///
+    ///     fn dispatch(&self, _op: Self::ReadOperation) -> Self::Response {
+    ///         Some(self.junk)
+    ///     }
+    ///
+    ///     fn dispatch_mut(&mut self, op: Self::WriteOperation) -> Self::Response {
+    ///         self.junk = op;
+    ///         None
+    ///     }
+    /// }
+    ///
+    /// // A log that supports a single replica.
+    /// let log = Arc::new(Log::<<Data as Dispatch>::WriteOperation, 2>::default());
+    /// let one = Replica::try_with_data(&log, Data::default());
+    /// assert!(one.is_ok());
+    ///
+    /// let two = Replica::try_with_data(&log, Data::default());
+    /// assert_eq!(two.err(), Some(Error::ReplicasExhausted));
+    /// ```
+    pub fn try_with_data<'b>(
+        log: &Arc<Log<'b, <D as Dispatch>::WriteOperation, MAX_REPLICAS, MAX_THREADS, MAX_PENDING>>,
+        d: D,
+    ) -> Result<Arc<Replica<'b, D, MAX_REPLICAS, MAX_THREADS, MAX_PENDING>>, Error> {
+        Replica::try_with_wait_strategy(log, d, Spin)
+    }
+
+    /// Similar to [`Replica<D>::with_data`], but threads wait on the replica and
+    /// the shared log according to `wait` instead of busy spinning.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use node_replication::wait::Spin;
+    /// use node_replication::Dispatch;
+    /// use node_replication::Log;
+    /// use node_replication::Replica;
+    ///
+    /// use std::sync::Arc;
+    ///
+    /// #[derive(Default)]
+    /// struct Data {
+    ///     junk: u64,
+    /// }
+    ///
+    /// impl Dispatch for Data {
+    ///     type ReadOperation = ();
+    ///     type WriteOperation = u64;
+    ///     type Response = Option<u64>;
+    ///
+    ///     fn dispatch(&self, _op: Self::ReadOperation) -> Self::Response {
+    ///         Some(self.junk)
+    ///     }
+    ///
+    ///     fn dispatch_mut(&mut self, op: Self::WriteOperation) -> Self::Response {
+    ///         self.junk = op;
+    ///         None
+    ///     }
+    /// }
+    ///
+    /// let log = Arc::new(Log::<<Data as Dispatch>::WriteOperation>::default());
+    /// let replica = Replica::with_wait_strategy(&log, Data::default(), Spin);
+    /// let idx = replica.register().expect("Failed to register with replica.");
+    /// replica.execute_mut(100, idx);
+    /// assert_eq!(replica.execute((), idx), Some(100));
+    /// ```
+    pub fn with_wait_strategy<'b>(
+        log: &Arc<Log<'b, <D as Dispatch>::WriteOperation, MAX_REPLICAS, MAX_THREADS, MAX_PENDING>>,
+        d: D,
+        wait: impl WaitStrategy + 'static,
+    ) -> Arc<Replica<'b, D, MAX_REPLICAS, MAX_THREADS, MAX_PENDING>> {
+        Replica::try_with_wait_strategy(log, d, wait).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    /// Similar to [`Replica<D>::with_wait_strategy`], but returns
+    /// [`Error::ReplicasExhausted`](enum.Error.html) instead of panicking if the
+    /// shared log can't take another replica.
+    pub fn try_with_wait_strategy<'b>(
+        log: &Arc<Log<'b, <D as Dispatch>::WriteOperation, MAX_REPLICAS, MAX_THREADS, MAX_PENDING>>,
+        d: D,
+        wait: impl WaitStrategy + 'static,
+    ) -> Result<Arc<Replica<'b, D, MAX_REPLICAS, MAX_THREADS, MAX_PENDING>>, Error> {
+        let idx = log.register().ok_or(Error::ReplicasExhausted)?;
+        Ok(Replica::create(
+            log,
+            idx,
+            d,
+            alloc::sync::Arc::new(wait),
+            alloc::sync::Arc::new(Global),
+        ))
+    }
+
+    /// Similar to [`Replica<D>::with_data`], but allocates the data structure
+    /// (`d` is moved there, but not any memory `d` allocates on its own) and the
+    /// per-thread contexts through `placement`. The rest of the replica, such as
+    /// the buffers used for flat combining, is allocated with the global allocator.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use node_replication::placement::Global;
+    /// use node_replication::Dispatch;
+    /// use node_replication::Log;
+    /// use node_replication::Replica;
+    ///
+    /// use std::sync::Arc;
+    ///
+    /// #[derive(Default)]
+    /// struct Data {
+    ///     junk: u64,
+    /// }
+    ///
+    /// impl Dispatch for Data {
+    ///     type ReadOperation = ();
+    ///     type WriteOperation = u64;
+    ///     type Response = Option<u64>;
+    ///
+    ///     fn dispatch(&self, _op: Self::ReadOperation) -> Self::Response {
+    ///         Some(self.junk)
+    ///     }
+    ///
+    ///     fn dispatch_mut(&mut self, op: Self::WriteOperation) -> Self::Response {
+    ///         self.junk = op;
+    ///         None
+    ///     }
+    /// }
+    ///
+    /// let log = Arc::new(Log::<<Data as Dispatch>::WriteOperation>::new_in(1024 * 1024, Global));
+    /// let replica = Replica::with_data_in(&log, Data { junk: 7 }, Global);
+    /// let idx = replica.register().expect("Failed to register with replica.");
+    /// assert_eq!(replica.execute((), idx), Some(7));
+    /// ```
+    pub fn with_data_in<'b>(
+        log: &Arc<Log<'b, <D as Dispatch>::WriteOperation, MAX_REPLICAS, MAX_THREADS, MAX_PENDING>>,
+        d: D,
+        placement: impl Placement + 'static,
+    ) -> Arc<Replica<'b, D, MAX_REPLICAS, MAX_THREADS, MAX_PENDING>> {
+        let idx = log
+            .register()
+            .unwrap_or_else(|| panic!("{}", Error::ReplicasExhausted));
+        Replica::create(
+            log,
+            idx,
+            d,
+            alloc::sync::Arc::new(Spin),
+            alloc::sync::Arc::new(placement),
         )
     }
 
-    /// See `with_data` documentation without unstable feature.
+    /// Constructs a replica with identifier `idx` on the shared log `log`. The
+    /// data structure and the per-thread contexts are allocated through `placement`.
+    #[cfg(not(feature = "unstable"))]
+    fn create<'b>(
+        log: &Arc<Log<'b, <D as Dispatch>::WriteOperation, MAX_REPLICAS, MAX_THREADS, MAX_PENDING>>,
+        idx: usize,
+        d: D,
+        wait: alloc::sync::Arc<dyn WaitStrategy>,
+        placement: alloc::sync::Arc<dyn Placement>,
+    ) -> Arc<Replica<'b, D, MAX_REPLICAS, MAX_THREADS, MAX_PENDING>> {
+        // Add `MAX_THREADS` contexts
+        let contexts = Placed::from_fn(MAX_THREADS, |_idx| Default::default(), placement.clone());
+
+        Arc::new(Replica {
+            idx,
+            combiner: CachePadded::new(AtomicUsize::new(0)),
+            next: CachePadded::new(AtomicUsize::new(1)),
+            wakers: (0..MAX_THREADS)
+                .step_by(usize::BITS as usize)
+                .map(|_i| AtomicUsize::new(0))
+                .collect(),
+            async_waiters: AtomicBool::new(false),
+            contexts,
+            buffer: RefCell::new(Vec::with_capacity(
+                MAX_THREADS
+                    * Context::<
+                        <D as Dispatch>::WriteOperation,
+                        <D as Dispatch>::Response,
+                        MAX_PENDING,
+                    >::batch_size(),
+            )),
+            inflight: RefCell::new([0; MAX_THREADS]),
+            result: RefCell::new(Vec::with_capacity(
+                MAX_THREADS
+                    * Context::<
+                        <D as Dispatch>::WriteOperation,
+                        <D as Dispatch>::Response,
+                        MAX_PENDING,
+                    >::batch_size(),
+            )),
+            coalescing: RefCell::new(Coalescing::new()),
+            slog: log.clone(),
+            data: Placed::new(CachePadded::new(RwLock::new(d)), placement),
+            wait,
+            #[cfg(feature = "stats")]
+            stats: ReplicaCounters::new(),
+            #[cfg(feature = "persistence")]
+            recorder: Recorder::new(),
+        })
+    }
+
+    /// See `create` documentation without unstable feature.
     #[cfg(feature = "unstable")]
-    pub fn with_data<'b>(
-        log: &Arc<Log<'b, <D as Dispatch>::WriteOperation>>,
+    fn create<'b>(
+        log: &Arc<Log<'b, <D as Dispatch>::WriteOperation, MAX_REPLICAS, MAX_THREADS, MAX_PENDING>>,
+        idx: usize,
         d: D,
-    ) -> Arc<Replica<'b, D>> {
+        wait: alloc::sync::Arc<dyn WaitStrategy>,
+        placement: alloc::sync::Arc<dyn Placement>,
+    ) -> Arc<Replica<'b, D, MAX_REPLICAS, MAX_THREADS, MAX_PENDING>> {
         use core::mem::MaybeUninit;
-        let mut uninit_replica: Arc<MaybeUninit<Replica<D>>> = Arc::new_zeroed();
+        let mut uninit_replica: Arc<
+            MaybeUninit<Replica<D, MAX_REPLICAS, MAX_THREADS, MAX_PENDING>>,
+        > = Arc::new_zeroed();
 
         // This is the preferred (but unsafe) mode of initialization as it avoids
         // putting the big Replica object on the stack first.
         unsafe {
             let uninit_ptr = Arc::get_mut_unchecked(&mut uninit_replica).as_mut_ptr();
             uninit_ptr.write(Replica {
-                idx: log.register().unwrap(),
+                idx,
                 combiner: CachePadded::new(AtomicUsize::new(0)),
                 next: CachePadded::new(AtomicUsize::new(1)),
-                contexts: Vec::with_capacity(MAX_THREADS_PER_REPLICA),
-                buffer:
-                    RefCell::new(
-                        Vec::with_capacity(
-                            MAX_THREADS_PER_REPLICA
-                                * Context::<
-                                    <D as Dispatch>::WriteOperation,
-                                    <D as Dispatch>::Response,
-                                >::batch_size(),
-                        ),
-                    ),
-                inflight: RefCell::new([0; MAX_THREADS_PER_REPLICA]),
-                result:
-                    RefCell::new(
-                        Vec::with_capacity(
-                            MAX_THREADS_PER_REPLICA
-                                * Context::<
-                                    <D as Dispatch>::WriteOperation,
-                                    <D as Dispatch>::Response,
-                                >::batch_size(),
-                        ),
-                    ),
+                wakers: (0..MAX_THREADS)
+                    .step_by(usize::BITS as usize)
+                    .map(|_i| AtomicUsize::new(0))
+                    .collect(),
+                async_waiters: AtomicBool::new(false),
+                // Add `MAX_THREADS` contexts
+                contexts: Placed::from_fn(
+                    MAX_THREADS,
+                    |_idx| Default::default(),
+                    placement.clone(),
+                ),
+                buffer: RefCell::new(Vec::with_capacity(
+                    MAX_THREADS
+                        * Context::<
+                            <D as Dispatch>::WriteOperation,
+                            <D as Dispatch>::Response,
+                            MAX_PENDING,
+                        >::batch_size(),
+                )),
+                inflight: RefCell::new([0; MAX_THREADS]),
+                result: RefCell::new(Vec::with_capacity(
+                    MAX_THREADS
+                        * Context::<
+                            <D as Dispatch>::WriteOperation,
+                            <D as Dispatch>::Response,
+                            MAX_PENDING,
+                        >::batch_size(),
+                )),
+                coalescing: RefCell::new(Coalescing::new()),
                 slog: log.clone(),
-                data: CachePadded::new(RwLock::<D>::new(d)),
+                data: Placed::new(CachePadded::new(RwLock::new(d)), placement),
+                wait,
+                #[cfg(feature = "stats")]
+                stats: ReplicaCounters::new(),
+                #[cfg(feature = "persistence")]
+                recorder: Recorder::new(),
             });
 
-            let mut replica = uninit_replica.assume_init();
-            // Add `MAX_THREADS_PER_REPLICA` contexts
-            for _idx in 0..MAX_THREADS_PER_REPLICA {
-                Arc::get_mut(&mut replica)
-                    .unwrap()
-                    .contexts
-                    .push(Default::default());
-            }
-
-            replica
+            uninit_replica.assume_init()
         }
     }
 
+    /// Returns the identifier of this replica on its shared log. It matches the
+    /// `replica` reported in a [LagEvent](struct.LagEvent.html).
+    pub fn id(&self) -> usize {
+        self.idx
+    }
+
     /// Registers a thread with this replica. Returns an idx inside an Option if the registration
     /// was successfull. None if the registration failed.
     ///
@@ -355,12 +820,30 @@ where
     /// let idx = replica.register().expect("Failed to register with replica.");
     /// ```
     pub fn register(&self) -> Option<ReplicaToken> {
+        self.try_register().ok()
+    }
+
+    /// Same as `register`, but returns [`Error::ThreadsExhausted`](enum.Error.html)
+    /// if all `MAX_THREADS` slots of the replica are taken.
+    pub fn try_register(&self) -> Result<ReplicaToken, Error> {
+        // Prefer slots that threads released; their contexts are drained already.
+        let next = self.next.load(Ordering::SeqCst);
+        for idx in 1..next {
+            if self.contexts[idx - 1]
+                .free
+                .compare_exchange(true, false, Ordering::Acquire, Ordering::Relaxed)
+                .is_ok()
+            {
+                return Ok(ReplicaToken(idx));
+            }
+        }
+
         // Loop until we either run out of identifiers or we manage to increment `next`.
         loop {
             let idx = self.next.load(Ordering::SeqCst);
 
-            if idx > MAX_THREADS_PER_REPLICA {
-                return None;
+            if idx > MAX_THREADS {
+                return Err(Error::ThreadsExhausted);
             };
 
             if self
@@ -371,12 +854,16 @@ where
                 continue;
             };
 
-            return Some(ReplicaToken(idx));
+            return Ok(ReplicaToken(idx));
         }
     }
 
-    /// Executes an mutable operation against this replica and returns a response.
-    /// `idx` is an identifier for the thread performing the execute operation.
+    /// Releases the slot of the thread identified by `idx`, so that the replica can
+    /// hand it out to a thread that registers later on. `idx` must not be used
+    /// after this call.
+    ///
+    /// Waits until the thread's pending operations were executed, and drops the
+    /// responses it didn't collect.
     ///
     /// # Example
     ///
@@ -395,45 +882,39 @@ where
     /// impl Dispatch for Data {
     ///     type ReadOperation = ();
     ///     type WriteOperation = u64;
-    ///     type Response = Option<u64>;
+    ///     type Response = u64;
     ///
-    ///     fn dispatch(
-    ///         &self,
-    ///         _op: Self::ReadOperation,
-    ///     ) -> Self::Response {
-    ///         Some(self.junk)
+    ///     fn dispatch(&self, _op: Self::ReadOperation) -> Self::Response {
+    ///         self.junk
     ///     }
     ///
-    ///     fn dispatch_mut(
-    ///         &mut self,
-    ///         op: Self::WriteOperation,
-    ///     ) -> Self::Response {
+    ///     fn dispatch_mut(&mut self, op: Self::WriteOperation) -> Self::Response {
     ///         self.junk = op;
-    ///         None
+    ///         op
     ///     }
     /// }
     ///
     /// let log = Arc::new(Log::<<Data as Dispatch>::WriteOperation>::default());
     /// let replica = Replica::<Data>::new(&log);
+    ///
     /// let idx = replica.register().expect("Failed to register with replica.");
+    /// replica.execute_mut(100, idx);
+    /// replica.unregister(idx);
     ///
-    /// // execute_mut() can be used to write to the replicated data structure.
-    /// let res = replica.execute_mut(100, idx);
-    /// assert_eq!(None, res);
-    pub fn execute_mut(
-        &self,
-        op: <D as Dispatch>::WriteOperation,
-        idx: ReplicaToken,
-    ) -> <D as Dispatch>::Response {
-        // Enqueue the operation onto the thread local batch and then try to flat combine.
-        while !self.make_pending(op.clone(), idx.0) {}
-        self.try_combine(idx.0);
+    /// // The next thread to register gets the same slot.
+    /// assert_eq!(replica.register(), Some(idx));
+    /// ```
+    pub fn unregister(&self, idx: ReplicaToken) {
+        self.flush(idx);
 
-        // Return the response to the caller function.
-        self.get_response(idx.0)
+        let context = &self.contexts[idx.0 - 1];
+        while context.res().is_some() {}
+
+        let was_free = context.free.swap(true, Ordering::Release);
+        assert!(!was_free, "Thread {} unregistered twice.", idx.0);
     }
 
-    /// Executes a read-only operation against this replica and returns a response.
+    /// Executes an mutable operation against this replica and returns a response.
     /// `idx` is an identifier for the thread performing the execute operation.
     ///
     /// # Example
@@ -474,39 +955,648 @@ where
     /// let log = Arc::new(Log::<<Data as Dispatch>::WriteOperation>::default());
     /// let replica = Replica::<Data>::new(&log);
     /// let idx = replica.register().expect("Failed to register with replica.");
-    /// let _wr = replica.execute_mut(100, idx);
     ///
-    /// // execute() can be used to read from the replicated data structure.
-    /// let res = replica.execute((), idx);
-    /// assert_eq!(Some(100), res);
-    pub fn execute(
+    /// // execute_mut() can be used to write to the replicated data structure.
+    /// let res = replica.execute_mut(100, idx);
+    /// assert_eq!(None, res);
+    pub fn execute_mut(
         &self,
-        op: <D as Dispatch>::ReadOperation,
+        op: <D as Dispatch>::WriteOperation,
         idx: ReplicaToken,
     ) -> <D as Dispatch>::Response {
+        self.try_execute_mut(op, idx)
+            .unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    /// Same as `execute_mut`, but returns an error instead of panicking if this
+    /// replica fell off the shared log. The replica can't be used anymore then.
+    /// Returns `Error::SegmentFailed` if the operation couldn't be persisted; it
+    /// is dropped without being executed then.
+    pub fn try_execute_mut(
+        &self,
+        op: <D as Dispatch>::WriteOperation,
+        idx: ReplicaToken,
+    ) -> Result<<D as Dispatch>::Response, Error> {
+        // Enqueue the operation onto the thread local batch and then try to flat combine.
+        self.make_pending_until(&op, idx.0, || false)?;
+        self.try_combine(idx.0)?;
+
+        // Return the response to the caller function.
+        self.get_response(idx.0)
+    }
+
+    /// Same as `execute_mut`, but also returns the logical index the operation
+    /// was appended at on the shared log.
+    ///
+    /// The index can be handed to a thread on another replica, which passes it
+    /// to `execute_at_least` to read a state that includes the operation.
+    pub fn execute_mut_with_pos(
+        &self,
+        op: <D as Dispatch>::WriteOperation,
+        idx: ReplicaToken,
+    ) -> (<D as Dispatch>::Response, usize) {
+        self.make_pending_until(&op, idx.0, || false)
+            .and_then(|_| self.try_combine(idx.0))
+            .and_then(|_| self.get_response_at_until(idx.0, || false))
+            .unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    /// Executes a batch of mutable operations against this replica and returns
+    /// their responses, in order. `idx` is an identifier for the thread performing
+    /// the execute operation.
+    ///
+    /// All operations that fit into the thread's context are enqueued at once and
+    /// appended to the shared log by a single round of flat combining. Batches with
+    /// more than `MAX_PENDING` operations are split up into several rounds.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use node_replication::Dispatch;
+    /// use node_replication::Log;
+    /// use node_replication::Replica;
+    ///
+    /// use std::sync::Arc;
+    ///
+    /// #[derive(Default)]
+    /// struct Data {
+    ///     junk: u64,
+    /// }
+    ///
+    /// impl Dispatch for Data {
+    ///     type ReadOperation = ();
+    ///     type WriteOperation = u64;
+    ///     type Response = u64;
+    ///
+    ///     fn dispatch(&self, _op: Self::ReadOperation) -> Self::Response {
+    ///         self.junk
+    ///     }
+    ///
+    ///     fn dispatch_mut(&mut self, op: Self::WriteOperation) -> Self::Response {
+    ///         self.junk += op;
+    ///         self.junk
+    ///     }
+    /// }
+    ///
+    /// let log = Arc::new(Log::<<Data as Dispatch>::WriteOperation>::default());
+    /// let replica = Replica::<Data>::new(&log);
+    /// let idx = replica.register().expect("Failed to register with replica.");
+    ///
+    /// let res = replica.execute_mut_batch(&[1, 2, 3], idx);
+    /// assert_eq!(res, vec![1, 3, 6]);
+    /// ```
+    pub fn execute_mut_batch(
+        &self,
+        ops: &[<D as Dispatch>::WriteOperation],
+        idx: ReplicaToken,
+    ) -> Vec<<D as Dispatch>::Response> {
+        self.try_execute_mut_batch(ops, idx)
+            .unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    /// Same as `execute_mut_batch`, but returns an error instead of panicking if
+    /// this replica fell off the shared log. The replica can't be used anymore then.
+    pub fn try_execute_mut_batch(
+        &self,
+        ops: &[<D as Dispatch>::WriteOperation],
+        idx: ReplicaToken,
+    ) -> Result<Vec<<D as Dispatch>::Response>, Error> {
+        let mut responses = Vec::with_capacity(ops.len());
+
+        let mut iteration = 1;
+        while responses.len() < ops.len() {
+            // Earlier operations of the thread (from `execute_mut_noreply`) might
+            // take up part of the context; they are appended along with ours.
+            let n = self.contexts[idx.0 - 1].enqueue_batch(&ops[responses.len()..]);
+            self.try_combine(idx.0)?;
+            if n == 0 {
+                self.wait.relax(iteration);
+                iteration += 1;
+                continue;
+            }
+
+            // Collecting the responses frees up the slots for the rest of the batch.
+            for _i in 0..n {
+                responses.push(self.get_response(idx.0)?);
+            }
+        }
+
+        Ok(responses)
+    }
+
+    /// Enqueues a mutable operation on the thread's context and returns right away,
+    /// without waiting for the operation to be executed. Its response is dropped.
+    /// `idx` is an identifier for the thread performing the execute operation.
+    ///
+    /// The operation is appended to the shared log by the next round of flat
+    /// combining on this replica; until then, reads (even on the same thread) may
+    /// not observe it. Use `flush` to wait for all such operations of the thread.
+    /// If the thread's context is full, this combines until there is space again.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use node_replication::Dispatch;
+    /// use node_replication::Log;
+    /// use node_replication::Replica;
+    ///
+    /// use std::sync::Arc;
+    ///
+    /// #[derive(Default)]
+    /// struct Data {
+    ///     junk: u64,
+    /// }
+    ///
+    /// impl Dispatch for Data {
+    ///     type ReadOperation = ();
+    ///     type WriteOperation = u64;
+    ///     type Response = u64;
+    ///
+    ///     fn dispatch(&self, _op: Self::ReadOperation) -> Self::Response {
+    ///         self.junk
+    ///     }
+    ///
+    ///     fn dispatch_mut(&mut self, op: Self::WriteOperation) -> Self::Response {
+    ///         self.junk += op;
+    ///         self.junk
+    ///     }
+    /// }
+    ///
+    /// let log = Arc::new(Log::<<Data as Dispatch>::WriteOperation>::default());
+    /// let replica = Replica::<Data>::new(&log);
+    /// let idx = replica.register().expect("Failed to register with replica.");
+    ///
+    /// for i in 0..100 {
+    ///     replica.execute_mut_noreply(i, idx);
+    /// }
+    /// replica.flush(idx);
+    /// assert_eq!(replica.execute((), idx), 4950);
+    /// ```
+    pub fn execute_mut_noreply(&self, op: <D as Dispatch>::WriteOperation, idx: ReplicaToken) {
+        let mut iteration = 1;
+        while !self.contexts[idx.0 - 1].enqueue_noreply(op.clone()) {
+            // Nobody appended our earlier operations yet; they need to go first.
+            self.try_combine(idx.0).unwrap_or_else(|e| panic!("{}", e));
+            self.wait.relax(iteration);
+            iteration += 1;
+        }
+    }
+
+    /// Waits until all operations the thread enqueued on this replica were
+    /// executed, including the ones from `execute_mut_noreply`. `idx` is an
+    /// identifier for the thread.
+    pub fn flush(&self, idx: ReplicaToken) {
+        let mut iteration = 1;
+        while self.contexts[idx.0 - 1].has_pending() {
+            self.try_combine(idx.0).unwrap_or_else(|e| panic!("{}", e));
+            self.wait.relax(iteration);
+            iteration += 1;
+        }
+        self.contexts[idx.0 - 1].reclaim();
+    }
+
+    /// Executes a read-only operation against this replica and returns a response.
+    /// `idx` is an identifier for the thread performing the execute operation.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use node_replication::Dispatch;
+    /// use node_replication::Log;
+    /// use node_replication::Replica;
+    ///
+    /// use std::sync::Arc;
+    ///
+    /// #[derive(Default)]
+    /// struct Data {
+    ///     junk: u64,
+    /// }
+    ///
+    /// impl Dispatch for Data {
+    ///     type ReadOperation = ();
+    ///     type WriteOperation = u64;
+    ///     type Response = Option<u64>;
+    ///
+    ///     fn dispatch(
+    ///         &self,
+    ///         _op: Self::ReadOperation,
+    ///     ) -> Self::Response {
+    ///         Some(self.junk)
+    ///     }
+    ///
+    ///     fn dispatch_mut(
+    ///         &mut self,
+    ///         op: Self::WriteOperation,
+    ///     ) -> Self::Response {
+    ///         self.junk = op;
+    ///         None
+    ///     }
+    /// }
+    ///
+    /// let log = Arc::new(Log::<<Data as Dispatch>::WriteOperation>::default());
+    /// let replica = Replica::<Data>::new(&log);
+    /// let idx = replica.register().expect("Failed to register with replica.");
+    /// let _wr = replica.execute_mut(100, idx);
+    ///
+    /// // execute() can be used to read from the replicated data structure.
+    /// let res = replica.execute((), idx);
+    /// assert_eq!(Some(100), res);
+    pub fn execute(
+        &self,
+        op: <D as Dispatch>::ReadOperation,
+        idx: ReplicaToken,
+    ) -> <D as Dispatch>::Response {
+        self.read_only(op, idx.0)
+            .unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    /// Same as `execute`, but returns an error instead of panicking if this
+    /// replica fell off the shared log while catching up with it.
+    pub fn try_execute(
+        &self,
+        op: <D as Dispatch>::ReadOperation,
+        idx: ReplicaToken,
+    ) -> Result<<D as Dispatch>::Response, Error> {
         self.read_only(op, idx.0)
     }
 
-    /// Busy waits until a response is available within the thread's context.
+    /// Brings this replica up to date with the shared log (like `execute`) and
+    /// then invokes `f` on the replicated data structure, returning its result.
+    /// `idx` is an identifier for the thread performing the read.
+    ///
+    /// Unlike a `ReadOperation`, `f` can borrow from the data structure, for
+    /// example to iterate over it without cloning it. `f` runs with the replica's
+    /// reader lock held, so writes on this replica wait until it returns. Reads
+    /// through `read_with` aren't recorded (see `Replica::record`).
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use node_replication::Dispatch;
+    /// use node_replication::Log;
+    /// use node_replication::Replica;
+    ///
+    /// use std::sync::Arc;
+    ///
+    /// #[derive(Default)]
+    /// struct Data {
+    ///     items: Vec<u64>,
+    /// }
+    ///
+    /// impl Dispatch for Data {
+    ///     type ReadOperation = ();
+    ///     type WriteOperation = u64;
+    ///     type Response = usize;
+    ///
+    ///     fn dispatch(&self, _op: Self::ReadOperation) -> Self::Response {
+    ///         self.items.len()
+    ///     }
+    ///
+    ///     fn dispatch_mut(&mut self, op: Self::WriteOperation) -> Self::Response {
+    ///         self.items.push(op);
+    ///         self.items.len()
+    ///     }
+    /// }
+    ///
+    /// let log = Arc::new(Log::<<Data as Dispatch>::WriteOperation>::default());
+    /// let replica = Replica::<Data>::new(&log);
+    /// let idx = replica.register().expect("Failed to register with replica.");
+    /// for i in 1..=4 {
+    ///     replica.execute_mut(i, idx);
+    /// }
+    ///
+    /// let evens = replica.read_with(idx, |d| d.items.iter().filter(|i| *i % 2 == 0).count());
+    /// assert_eq!(evens, 2);
+    /// ```
+    pub fn read_with<R, F: FnOnce(&D) -> R>(&self, idx: ReplicaToken, f: F) -> R {
+        self.try_read_with(idx, f)
+            .unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    /// Same as `read_with`, but returns an error instead of panicking if this
+    /// replica fell off the shared log while catching up with it.
+    pub fn try_read_with<R, F: FnOnce(&D) -> R>(
+        &self,
+        idx: ReplicaToken,
+        f: F,
+    ) -> Result<R, Error> {
+        self.sync_for_reads(idx.0, self.slog.get_ctail(), || false)?;
+        Ok(f(&self.data.read(idx.0 - 1)))
+    }
+
+    /// Executes a read-only operation against this replica without waiting for it
+    /// to catch up with the shared log, as long as it is at most `max_lag` entries
+    /// behind the log's completed tail. Otherwise, the replica first catches up
+    /// until it is within `max_lag` entries. With `max_lag` set to `usize::MAX`, the
+    /// read always executes right away.
+    ///
+    /// Unlike `execute`, the read is not linearizable: it may miss up to `max_lag`
+    /// write operations that already completed on other replicas. It does reflect
+    /// every write operation that completed on this replica (including the ones of
+    /// the calling thread), and reads on the same replica never go back in time.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use node_replication::Dispatch;
+    /// use node_replication::Log;
+    /// use node_replication::Replica;
+    ///
+    /// use std::sync::Arc;
+    ///
+    /// #[derive(Default)]
+    /// struct Data {
+    ///     junk: u64,
+    /// }
+    ///
+    /// impl Dispatch for Data {
+    ///     type ReadOperation = ();
+    ///     type WriteOperation = u64;
+    ///     type Response = u64;
+    ///
+    ///     fn dispatch(&self, _op: Self::ReadOperation) -> Self::Response {
+    ///         self.junk
+    ///     }
+    ///
+    ///     fn dispatch_mut(&mut self, op: Self::WriteOperation) -> Self::Response {
+    ///         self.junk = op;
+    ///         op
+    ///     }
+    /// }
+    ///
+    /// let log = Arc::new(Log::<<Data as Dispatch>::WriteOperation>::default());
+    /// let one = Replica::<Data>::new(&log);
+    /// let two = Replica::<Data>::new(&log);
+    /// let idx1 = one.register().expect("Failed to register with replica.");
+    /// let idx2 = two.register().expect("Failed to register with replica.");
+    /// two.execute_mut(100, idx2);
+    ///
+    /// // The first replica hasn't seen the write yet.
+    /// assert_eq!(one.execute_stale((), idx1, usize::MAX), 0);
+    /// assert_eq!(one.execute_stale((), idx1, 0), 100);
+    /// ```
+    pub fn execute_stale(
+        &self,
+        op: <D as Dispatch>::ReadOperation,
+        idx: ReplicaToken,
+        max_lag: usize,
+    ) -> <D as Dispatch>::Response {
+        let ctail = self.slog.get_ctail().saturating_sub(max_lag);
+        self.read_only_until(op, idx.0, ctail, || false)
+            .unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    /// Executes a read-only operation against this replica once it has executed
+    /// the write operation at logical index `pos` of the shared log (as returned by
+    /// `execute_mut_with_pos`), along with every operation before it.
+    ///
+    /// Unlike `execute`, this doesn't wait for the replica to catch up with all
+    /// operations that completed on the log, only with the ones the caller has
+    /// seen. That is enough to preserve causality when threads on different
+    /// replicas hand off work to each other.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use node_replication::Dispatch;
+    /// use node_replication::Log;
+    /// use node_replication::Replica;
+    ///
+    /// use std::sync::Arc;
+    ///
+    /// #[derive(Default)]
+    /// struct Data {
+    ///     junk: u64,
+    /// }
+    ///
+    /// impl Dispatch for Data {
+    ///     type ReadOperation = ();
+    ///     type WriteOperation = u64;
+    ///     type Response = u64;
+    ///
+    ///     fn dispatch(&self, _op: Self::ReadOperation) -> Self::Response {
+    ///         self.junk
+    ///     }
+    ///
+    ///     fn dispatch_mut(&mut self, op: Self::WriteOperation) -> Self::Response {
+    ///         self.junk = op;
+    ///         op
+    ///     }
+    /// }
+    ///
+    /// let log = Arc::new(Log::<<Data as Dispatch>::WriteOperation>::default());
+    /// let one = Replica::<Data>::new(&log);
+    /// let two = Replica::<Data>::new(&log);
+    /// let idx1 = one.register().expect("Failed to register with replica.");
+    /// let idx2 = two.register().expect("Failed to register with replica.");
+    ///
+    /// let (_resp, pos) = two.execute_mut_with_pos(100, idx2);
+    /// assert_eq!(one.execute_at_least((), idx1, pos), 100);
+    /// ```
+    pub fn execute_at_least(
+        &self,
+        op: <D as Dispatch>::ReadOperation,
+        idx: ReplicaToken,
+        pos: usize,
+    ) -> <D as Dispatch>::Response {
+        self.read_only_until(op, idx.0, pos.saturating_add(1), || false)
+            .unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    /// Executes a mutable operation against this replica, but gives up once
+    /// `clock` reaches `deadline`.
+    ///
+    /// Returns `Error::TimedOut` if the operation could not be appended to the
+    /// shared log in time; it will not be executed then. Returns `Error::InFlight`
+    /// if another thread's round of flat combining picked up the operation, but
+    /// its response didn't arrive in time. The operation will be executed in that
+    /// case, and the thread must collect its response with
+    /// `collect_response_deadline` before issuing further operations.
+    ///
+    /// The deadline is checked while the thread waits, including while it waits
+    /// for garbage collection on the shared log as the combiner. Once its batch is
+    /// appended, the combiner stops waiting for the head of the log to advance at
+    /// the deadline, and finishes the round of flat combining.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use core::cell::Cell;
+    /// use core::time::Duration;
+    /// use node_replication::clock::Clock;
+    /// use node_replication::Dispatch;
+    /// use node_replication::Log;
+    /// use node_replication::Replica;
+    ///
+    /// use std::sync::Arc;
+    ///
+    /// #[derive(Default)]
+    /// struct Data {
+    ///     junk: u64,
+    /// }
+    ///
+    /// impl Dispatch for Data {
+    ///     type ReadOperation = ();
+    ///     type WriteOperation = u64;
+    ///     type Response = Option<u64>;
+    ///
+    ///     fn dispatch(&self, _op: Self::ReadOperation) -> Self::Response {
+    ///         Some(self.junk)
+    ///     }
+    ///
+    ///     fn dispatch_mut(&mut self, op: Self::WriteOperation) -> Self::Response {
+    ///         self.junk = op;
+    ///         None
+    ///     }
+    /// }
+    ///
+    /// // A clock that moves forward by a microsecond every time it is read.
+    /// struct Ticks(Cell<u64>);
+    ///
+    /// impl Clock for Ticks {
+    ///     fn now(&self) -> Duration {
+    ///         self.0.set(self.0.get() + 1);
+    ///         Duration::from_micros(self.0.get())
+    ///     }
+    /// }
+    ///
+    /// let log = Arc::new(Log::<<Data as Dispatch>::WriteOperation>::default());
+    /// let replica = Replica::<Data>::new(&log);
+    /// let idx = replica.register().expect("Failed to register with replica.");
+    ///
+    /// let clock = Ticks(Cell::new(0));
+    /// let deadline = Duration::from_millis(1);
+    /// assert_eq!(replica.execute_mut_deadline(100, idx, &clock, deadline), Ok(None));
+    /// assert_eq!(replica.execute_deadline((), idx, &clock, deadline), Ok(Some(100)));
+    /// ```
+    pub fn execute_mut_deadline<C: Clock + ?Sized>(
+        &self,
+        op: <D as Dispatch>::WriteOperation,
+        idx: ReplicaToken,
+        clock: &C,
+        deadline: Duration,
+    ) -> Result<<D as Dispatch>::Response, Error> {
+        let expired = || clock.now() >= deadline;
+
+        // Only once the operation is in the thread's context can a combiner pick it up.
+        self.make_pending_until(&op, idx.0, expired)?;
+        self.try_combine_until(idx.0, &expired, true)?;
+
+        self.get_response_until(idx.0, expired)
+    }
+
+    /// Executes a read-only operation against this replica, but gives up with
+    /// `Error::TimedOut` if the replica doesn't catch up with the shared log
+    /// before `clock` reaches `deadline`.
+    pub fn execute_deadline<C: Clock + ?Sized>(
+        &self,
+        op: <D as Dispatch>::ReadOperation,
+        idx: ReplicaToken,
+        clock: &C,
+        deadline: Duration,
+    ) -> Result<<D as Dispatch>::Response, Error> {
+        self.read_only_until(op, idx.0, self.slog.get_ctail(), || clock.now() >= deadline)
+    }
+
+    /// Waits for the response to an operation that `execute_mut_deadline` (or
+    /// `execute_mut_timeout`) reported as `Error::InFlight`, until `clock` reaches
+    /// `deadline`. Returns `Error::InFlight` again if the response doesn't arrive
+    /// in time.
+    pub fn collect_response_deadline<C: Clock + ?Sized>(
+        &self,
+        idx: ReplicaToken,
+        clock: &C,
+        deadline: Duration,
+    ) -> Result<<D as Dispatch>::Response, Error> {
+        self.try_combine(idx.0)?;
+        self.get_response_until(idx.0, || clock.now() >= deadline)
+    }
+
+    /// Same as `execute_mut_deadline`, with a deadline `timeout` from now.
+    #[cfg(feature = "std")]
+    pub fn execute_mut_timeout(
+        &self,
+        op: <D as Dispatch>::WriteOperation,
+        idx: ReplicaToken,
+        timeout: Duration,
+    ) -> Result<<D as Dispatch>::Response, Error> {
+        self.execute_mut_deadline(op, idx, &StdClock::new(), timeout)
+    }
+
+    /// Same as `execute_deadline`, with a deadline `timeout` from now.
+    #[cfg(feature = "std")]
+    pub fn execute_timeout(
+        &self,
+        op: <D as Dispatch>::ReadOperation,
+        idx: ReplicaToken,
+        timeout: Duration,
+    ) -> Result<<D as Dispatch>::Response, Error> {
+        self.execute_deadline(op, idx, &StdClock::new(), timeout)
+    }
+
+    /// Same as `collect_response_deadline`, with a deadline `timeout` from now.
+    #[cfg(feature = "std")]
+    pub fn collect_response_timeout(
+        &self,
+        idx: ReplicaToken,
+        timeout: Duration,
+    ) -> Result<<D as Dispatch>::Response, Error> {
+        self.collect_response_deadline(idx, &StdClock::new(), timeout)
+    }
+
+    /// Waits until a response is available within the thread's context.
     /// `idx` identifies this thread.
-    fn get_response(&self, idx: usize) -> <D as Dispatch>::Response {
+    fn get_response(&self, idx: usize) -> Result<<D as Dispatch>::Response, Error> {
+        self.get_response_until(idx, || false)
+    }
+
+    /// Same as `get_response`, but gives up with `Error::InFlight` once `expired`
+    /// returns true.
+    fn get_response_until<E: Fn() -> bool>(
+        &self,
+        idx: usize,
+        expired: E,
+    ) -> Result<<D as Dispatch>::Response, Error> {
+        self.get_response_at_until(idx, expired)
+            .map(|(resp, _pos)| resp)
+    }
+
+    /// Same as `get_response_until`, but also returns the logical index the
+    /// operation was appended at on the shared log.
+    fn get_response_at_until<E: Fn() -> bool>(
+        &self,
+        idx: usize,
+        expired: E,
+    ) -> Result<(<D as Dispatch>::Response, usize), Error> {
         let mut iter = 0;
         let interval = 1 << 29;
 
         // Keep trying to retrieve a response from the thread context. After trying `interval`
         // times with no luck, try to perform flat combining to make some progress.
+        let mut iteration = 1;
         loop {
-            let r = self.contexts[idx - 1].res();
+            let r = self.contexts[idx - 1].res_at();
             if let Some(resp) = r {
-                return resp;
+                return Ok(resp);
+            }
+
+            if expired() {
+                return Err(Error::InFlight);
             }
 
             iter += 1;
 
             if iter == interval {
-                self.try_combine(idx);
+                self.try_combine(idx)?;
                 iter = 0;
             }
+
+            // The wait strategy might have parked us for a while; if so, make sure
+            // our operation didn't get stuck in the context without a combiner.
+            if self.wait.block(iteration, &self.contexts[idx - 1].parker) {
+                self.try_combine(idx)?;
+            }
+            iteration += 1;
         }
     }
 
@@ -523,7 +1613,7 @@ where
         // Use an idx greater than the maximum that can be allocated.
         while self.combiner.compare_exchange_weak(
             0,
-            MAX_THREADS_PER_REPLICA + 2,
+            MAX_THREADS + 2,
             Ordering::Acquire,
             Ordering::Acquire,
         ) != Ok(0)
@@ -532,45 +1622,170 @@ where
         }
 
         let mut data = self.data.write(self.next.load(Ordering::Relaxed));
-        let mut f = |o: <D as Dispatch>::WriteOperation, _i: usize| {
-            data.dispatch_mut(o);
+        let mut f = |o: <D as Dispatch>::WriteOperation, ctx: &WriteContext| {
+            self.apply(&mut data, o, ctx);
         };
 
-        self.slog.exec(self.idx, &mut f);
+        self.slog
+            .exec_with(self.idx, &mut f, &Spin)
+            .unwrap_or_else(|e| panic!("{}", e));
 
         v(&data);
 
         self.combiner.store(0, Ordering::Release);
     }
 
+    /// Acquires the combiner lock on behalf of thread `tid`, brings the replica up
+    /// to date with the shared log and invokes `f` with the data structure and the
+    /// replica's local tail on the log. The local tail cannot move while `f` runs.
+    pub(crate) fn synced<R, F: FnOnce(&D, usize) -> R>(&self, tid: usize, f: F) -> R {
+        let mut iteration = 1;
+        while self
+            .combiner
+            .compare_exchange_weak(0, tid, Ordering::Acquire, Ordering::Acquire)
+            != Ok(0)
+        {
+            self.wait.relax(iteration);
+            iteration += 1;
+        }
+
+        let r = {
+            let mut data = self
+                .data
+                .write_with(self.next.load(Ordering::Relaxed), &*self.wait);
+            let mut d = |o: <D as Dispatch>::WriteOperation, ctx: &WriteContext| {
+                self.apply(&mut data, o, ctx);
+            };
+            self.slog
+                .exec_with(self.idx, &mut d, &*self.wait)
+                .unwrap_or_else(|e| panic!("{}", e));
+
+            f(&data, self.slog.get_ltail(self.idx))
+        };
+
+        self.combiner.store(0, Ordering::Release);
+        r
+    }
+
     /// This method is useful when a replica stops making progress and some threads
     /// on another replica are still active. The active replica will use all the entries
     /// in the log and won't be able perform garbage collection because of the inactive
     /// replica. So, this method syncs up the replica against the underlying log.
     pub fn sync(&self, idx: ReplicaToken) {
         let ctail = self.slog.get_ctail();
+        let mut iteration = 1;
         while !self.slog.is_replica_synced_for_reads(self.idx, ctail) {
-            self.try_combine(idx.0);
-            spin_loop();
+            self.try_combine(idx.0).unwrap_or_else(|e| panic!("{}", e));
+            self.wait.relax(iteration);
+            iteration += 1;
         }
     }
 
+    /// Brings the replica up to date with the shared log with a round of flat
+    /// combining on behalf of `idx`, unless another thread is combining already.
+    pub(crate) fn try_catch_up(&self, idx: ReplicaToken) -> Result<(), Error> {
+        self.try_combine(idx.0)
+    }
+
+    /// Returns the local tail of this replica and the completed tail of the log.
+    pub(crate) fn tails(&self) -> (usize, usize) {
+        (self.slog.get_ltail(self.idx), self.slog.get_ctail())
+    }
+
+    /// Returns the counters collected by this replica.
+    #[cfg(feature = "stats")]
+    pub fn stats(&self) -> ReplicaStats {
+        self.stats.snapshot()
+    }
+
     /// Issues a read-only operation against the replica and returns a response.
     /// Makes sure the replica is synced up against the log before doing so.
     fn read_only(
         &self,
         op: <D as Dispatch>::ReadOperation,
         tid: usize,
+    ) -> Result<<D as Dispatch>::Response, Error> {
+        self.read_only_until(op, tid, self.slog.get_ctail(), || false)
+    }
+
+    /// Same as `read_only`, but gives up with `Error::TimedOut` once `expired`
+    /// returns true while the replica is still catching up with the log. The
+    /// replica only catches up until it executed all operations before the
+    /// logical index `ctail`.
+    fn read_only_until<E: Fn() -> bool>(
+        &self,
+        op: <D as Dispatch>::ReadOperation,
+        tid: usize,
+        ctail: usize,
+        expired: E,
+    ) -> Result<<D as Dispatch>::Response, Error> {
+        self.sync_for_reads(tid, ctail, expired)?;
+        Ok(self.read_now(op, tid))
+    }
+
+    /// Combines on behalf of thread `tid` until the replica executed all
+    /// operations before the logical index `ctail`, or `expired` returns true.
+    fn sync_for_reads<E: Fn() -> bool>(
+        &self,
+        tid: usize,
+        ctail: usize,
+        expired: E,
+    ) -> Result<(), Error> {
+        // We can perform the read only if our replica is synced up against
+        // the shared log. If it isn't, then try to combine until it is synced up.
+        #[cfg(feature = "stats")]
+        if !self.slog.is_replica_synced_for_reads(self.idx, ctail) {
+            self.stats.read_sync_waits.fetch_add(1, Ordering::Relaxed);
+        }
+        let mut iteration = 1;
+        while !self.slog.is_replica_synced_for_reads(self.idx, ctail) {
+            if expired() {
+                return Err(Error::TimedOut);
+            }
+            self.try_combine(tid)?;
+            self.wait.relax(iteration);
+            iteration += 1;
+        }
+
+        Ok(())
+    }
+
+    /// Executes a read-only operation against the replica as it is, and records
+    /// it if the replica is being recorded.
+    #[inline(always)]
+    fn read_now(
+        &self,
+        op: <D as Dispatch>::ReadOperation,
+        tid: usize,
+    ) -> <D as Dispatch>::Response {
+        #[cfg(feature = "persistence")]
+        if self.recorder.is_active() {
+            let data = self.data.read(tid - 1);
+            let resp = data.dispatch(op.clone());
+            self.recorder.read(tid, &op, &resp);
+            return resp;
+        }
+
+        self.data.read(tid - 1).dispatch(op)
+    }
+
+    /// Executes a write operation from the shared log against `data`, and records
+    /// it if the replica is being recorded.
+    #[inline(always)]
+    fn apply(
+        &self,
+        data: &mut D,
+        op: <D as Dispatch>::WriteOperation,
+        ctx: &WriteContext,
     ) -> <D as Dispatch>::Response {
-        // We can perform the read only if our replica is synced up against
-        // the shared log. If it isn't, then try to combine until it is synced up.
-        let ctail = self.slog.get_ctail();
-        while !self.slog.is_replica_synced_for_reads(self.idx, ctail) {
-            self.try_combine(tid);
-            spin_loop();
+        #[cfg(feature = "persistence")]
+        if self.recorder.is_active() {
+            let resp = data.dispatch_mut_with(op.clone(), ctx);
+            self.recorder.write(ctx, &op, &resp);
+            return resp;
         }
 
-        return self.data.read(tid - 1).dispatch(op);
+        data.dispatch_mut_with(op, ctx)
     }
 
     /// Enqueues an operation inside a thread local context. Returns a boolean
@@ -580,9 +1795,49 @@ where
         self.contexts[idx - 1].enqueue(op)
     }
 
+    /// Enqueues `op` inside the context of thread `tid`. While the context is
+    /// full, performs flat combining to make room, and gives up with
+    /// `Error::TimedOut` once `expired` returns true.
+    fn make_pending_until<E: Fn() -> bool>(
+        &self,
+        op: &<D as Dispatch>::WriteOperation,
+        tid: usize,
+        expired: E,
+    ) -> Result<(), Error> {
+        let mut iteration = 1;
+        while !self.make_pending(op.clone(), tid) {
+            if expired() {
+                return Err(Error::TimedOut);
+            }
+
+            // Earlier operations of the thread (from `execute_mut_noreply`) fill
+            // up the context; nobody appended them yet.
+            self.try_combine_until(tid, &expired, false)?;
+            self.wait.relax(iteration);
+            iteration += 1;
+        }
+        Ok(())
+    }
+
     /// Appends an operation to the log and attempts to perform flat combining.
     /// Accepts a thread `tid` as an argument. Required to acquire the combiner lock.
-    fn try_combine(&self, tid: usize) {
+    ///
+    /// Fails if this replica fell off the shared log while combining.
+    fn try_combine(&self, tid: usize) -> Result<(), Error> {
+        self.try_combine_until(tid, &|| false, false)
+    }
+
+    /// Same as `try_combine`, but gives up with `Error::TimedOut` if `expired`
+    /// returns true while the combiner waits for room on the shared log, before it
+    /// appended its batch. The collected operations stay in their contexts for the
+    /// next combiner then. If `retract` is set, the operation thread `tid` enqueued
+    /// last is removed instead; if it was executed already, this returns `Ok`.
+    fn try_combine_until(
+        &self,
+        tid: usize,
+        expired: &dyn Fn() -> bool,
+        retract: bool,
+    ) -> Result<(), Error> {
         // First, check if there already is a flat combiner. If there is no active flat combiner
         // then try to acquire the combiner lock. If there is, then just return.
         for _i in 0..4 {
@@ -595,14 +1850,14 @@ where
                 )
             } != 0
             {
-                return;
+                return Ok(());
             }
 
             #[cfg(loom)]
             {
                 if self.combiner.load(Ordering::Relaxed) != 0 {
                     loom::thread::yield_now();
-                    return;
+                    return Ok(());
                 }
             }
         }
@@ -615,21 +1870,105 @@ where
         {
             #[cfg(loom)]
             loom::thread::yield_now();
-            return;
+            return Ok(());
         }
 
         // Successfully became the combiner; perform one round of flat combining.
-        self.combine();
+        let mut r = self.combine(expired);
+        if r == Err(Error::TimedOut) && retract && !self.contexts[tid - 1].retract() {
+            r = Ok(());
+        }
+
+        // None of our operations can be appended anymore; remove them so that they
+        // don't linger in the context.
+        if r == Err(Error::SegmentFailed) {
+            while self.contexts[tid - 1].retract() {}
+        }
 
         // Allow other threads to perform flat combining once we have finished all our work.
         // At this point, we've dropped all mutable references to thread contexts and to
         // the staging buffer as well.
         self.combiner.store(0, Ordering::Release);
+
+        self.wake_missed();
+
+        // Pass the entries we appended to the log's observer, if it has one. This
+        // doesn't wait if another thread is doing so already.
+        self.slog.drain_observer();
+        r
+    }
+
+    /// Wakes up the tasks whose operations were enqueued while we combined, and
+    /// missed this round, so that they don't wait for a combiner that isn't
+    /// coming. Only looks at the contexts of threads that registered a waker.
+    fn wake_missed(&self) {
+        // A task that registers from now on tries to combine itself afterwards.
+        if !self.async_waiters.load(Ordering::Relaxed) {
+            return;
+        }
+
+        // Pairs with `register_waker`: either we see the bit of a thread, or the
+        // thread's attempt to combine sees that we released the combiner lock.
+        fence(Ordering::SeqCst);
+        for (w, word) in self.wakers.iter().enumerate() {
+            let mut bits = word.load(Ordering::SeqCst);
+            while bits != 0 {
+                let bit = bits & bits.wrapping_neg();
+                bits &= !bit;
+
+                let context =
+                    &self.contexts[w * usize::BITS as usize + bit.trailing_zeros() as usize];
+                let parker = &context.parker;
+                if context.has_pending() {
+                    parker.unpark();
+                }
+
+                // Forget threads whose waker was used up. A thread that registers
+                // again in the meantime sets its bit again after registering.
+                if !parker.has_waker() {
+                    word.fetch_and(!bit, Ordering::SeqCst);
+                    if parker.has_waker() {
+                        word.fetch_or(bit, Ordering::SeqCst);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Registers the waker of the task that waits on thread `tid`'s context. The
+    /// combiner wakes the task once it made progress on the context, or once it
+    /// is done if the task's operations missed its round.
+    fn register_waker(&self, tid: usize, waker: &core::task::Waker) {
+        // Combiners only look for wakers once the first task registered one. Turn
+        // that on with the combiner lock held, so that the combiners of all later
+        // rounds see it and we see the responses of all earlier ones. If another
+        // thread is combining, wake the task so that it tries again.
+        if !self.async_waiters.load(Ordering::Acquire) {
+            if self
+                .combiner
+                .compare_exchange(0, tid, Ordering::Acquire, Ordering::Acquire)
+                .is_ok()
+            {
+                self.async_waiters.store(true, Ordering::Release);
+                self.combiner.store(0, Ordering::Release);
+            } else {
+                waker.wake_by_ref();
+            }
+        }
+
+        self.contexts[tid - 1].parker.register(waker);
+        let (w, b) = (
+            (tid - 1) / usize::BITS as usize,
+            (tid - 1) % usize::BITS as usize,
+        );
+        self.wakers[w].fetch_or(1 << b, Ordering::SeqCst);
     }
 
     /// Performs one round of flat combining. Collects, appends and executes operations.
+    /// Gives up with `Error::TimedOut` if `expired` returns true before the operations
+    /// could be appended.
     #[inline(always)]
-    fn combine(&self) {
+    fn combine(&self, expired: &dyn Fn() -> bool) -> Result<(), Error> {
         let mut buffer = self.buffer.borrow_mut();
         let mut operations = self.inflight.borrow_mut();
         let mut results = self.result.borrow_mut();
@@ -641,38 +1980,66 @@ where
 
         // Collect operations from each thread registered with this replica.
         for i in 1..next {
+            if self.contexts[i - 1].free.load(Ordering::Relaxed) {
+                continue;
+            }
             operations[i - 1] = self.contexts[i - 1].ops(&mut buffer);
         }
 
+        #[cfg(feature = "stats")]
+        self.stats.combined(buffer.len());
+
+        let mut coalescing = self.coalescing.borrow_mut();
+        let coalesced = coalescing.coalesce(&mut buffer, &operations[..next - 1]);
+
         // Append all collected operations into the shared log. We pass a closure
         // in here because operations on the log might need to be consumed for GC.
         {
-            let mut data = self.data.write(next);
-            let f = |o: <D as Dispatch>::WriteOperation, i: usize| {
-                #[cfg(not(loom))]
-                let resp = data.dispatch_mut(o);
-                #[cfg(loom)]
-                let resp = data.dispatch_mut(o);
-                if i == self.idx {
-                    results.push(resp);
+            let threads = if coalesced {
+                &coalescing.threads[..]
+            } else {
+                &operations[..next - 1]
+            };
+            let mut data = self.data.write_with(next, &*self.wait);
+            let f = |o: <D as Dispatch>::WriteOperation, ctx: &WriteContext| {
+                let resp = self.apply(&mut data, o, ctx);
+                if ctx.replica == self.idx {
+                    results.push((resp, ctx.pos));
                 }
             };
-            self.slog.append(&buffer, self.idx, f);
+            let r = self
+                .slog
+                .append_with(&buffer, threads, self.idx, f, &*self.wait, expired);
+            if r == Err(Error::TimedOut) || r == Err(Error::SegmentFailed) {
+                // None of the operations were executed; they stay in the contexts,
+                // until the threads that enqueued them retract them.
+                operations[..next - 1].iter_mut().for_each(|n| *n = 0);
+            }
+            r?;
         }
 
         // Execute any operations on the shared log against this replica.
         {
-            let mut data = self.data.write(next);
-            let mut f = |o: <D as Dispatch>::WriteOperation, i: usize| {
-                let resp = data.dispatch_mut(o);
-                if i == self.idx {
-                    results.push(resp)
+            let mut data = self.data.write_with(next, &*self.wait);
+            let mut f = |o: <D as Dispatch>::WriteOperation, ctx: &WriteContext| {
+                let resp = self.apply(&mut data, o, ctx);
+                if ctx.replica == self.idx {
+                    results.push((resp, ctx.pos))
                 };
             };
-            self.slog.exec(self.idx, &mut f);
+            self.slog.exec_with(self.idx, &mut f, &*self.wait)?;
         }
 
-        // Return/Enqueue responses back into the appropriate thread context(s).
+        let results = if coalesced {
+            coalescing.split(&results);
+            &coalescing.responses[..]
+        } else {
+            &results[..]
+        };
+
+        // Return/Enqueue responses back into the appropriate thread context(s). Only
+        // look for tasks to wake once some task registered a waker.
+        let wake = self.async_waiters.load(Ordering::Relaxed);
         let (mut s, mut f) = (0, 0);
         for i in 1..next {
             if operations[i - 1] == 0 {
@@ -681,41 +2048,214 @@ where
 
             f += operations[i - 1];
             self.contexts[i - 1].enqueue_resps(&results[s..f]);
+            if wake {
+                self.contexts[i - 1].parker.wake_task();
+            }
             s += operations[i - 1];
             operations[i - 1] = 0;
         }
+
+        Ok(())
+    }
+
+    /// Tries to enqueue `op` on the context of thread `tid`. If the context is
+    /// full, registers the task's waker with it and returns `Poll::Pending`; the
+    /// combiner wakes the task once it made progress on the context.
+    fn poll_make_pending(
+        &self,
+        op: &<D as Dispatch>::WriteOperation,
+        tid: usize,
+        cx: &mut core::task::Context<'_>,
+    ) -> Poll<Result<(), Error>> {
+        if self.make_pending(op.clone(), tid) {
+            return Poll::Ready(Ok(()));
+        }
+
+        self.register_waker(tid, cx.waker());
+        self.try_combine(tid)?;
+        if self.make_pending(op.clone(), tid) {
+            return Poll::Ready(Ok(()));
+        }
+        Poll::Pending
+    }
+
+    /// Returns the next response on the context of thread `tid` if there is one.
+    /// Otherwise, registers the task's waker with the context, which the combiner
+    /// wakes once it enqueued the response, and returns `Poll::Pending`.
+    fn poll_response(
+        &self,
+        tid: usize,
+        cx: &mut core::task::Context<'_>,
+    ) -> Poll<Result<(<D as Dispatch>::Response, usize), Error>> {
+        if let Some(resp) = self.contexts[tid - 1].res_at() {
+            return Poll::Ready(Ok(resp));
+        }
+
+        // Register before trying again, so that a response enqueued in between
+        // wakes the task.
+        self.register_waker(tid, cx.waker());
+        self.try_combine(tid)?;
+        match self.contexts[tid - 1].res_at() {
+            Some(resp) => Poll::Ready(Ok(resp)),
+            None => Poll::Pending,
+        }
+    }
+
+    /// Executes a read-only operation once the replica caught up with the log's
+    /// completed tail `ctail`, performing flat combining to get there. Yields to
+    /// the executor, instead of waiting, while another thread is combining.
+    fn poll_read(
+        &self,
+        op: &<D as Dispatch>::ReadOperation,
+        tid: usize,
+        ctail: usize,
+        cx: &mut core::task::Context<'_>,
+    ) -> Poll<Result<<D as Dispatch>::Response, Error>> {
+        if !self.slog.is_replica_synced_for_reads(self.idx, ctail) {
+            self.try_combine(tid)?;
+            if !self.slog.is_replica_synced_for_reads(self.idx, ctail) {
+                cx.waker().wake_by_ref();
+                return Poll::Pending;
+            }
+        }
+
+        Poll::Ready(Ok(self.read_now(op.clone(), tid)))
+    }
+
+    /// Same as `execute_mut`, but returns a future that resolves to the response
+    /// instead of waiting for it. `idx` is an identifier for the thread (or task)
+    /// performing the execute operation.
+    ///
+    /// The operation is enqueued when the future is first polled. Neither then
+    /// nor while the response is outstanding does the future busy wait: it
+    /// returns `Poll::Pending` and is woken up by the combiner.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use node_replication::Dispatch;
+    /// use node_replication::Log;
+    /// use node_replication::Replica;
+    ///
+    /// use futures::executor::block_on;
+    /// use std::sync::Arc;
+    ///
+    /// #[derive(Default)]
+    /// struct Data {
+    ///     junk: u64,
+    /// }
+    ///
+    /// impl Dispatch for Data {
+    ///     type ReadOperation = ();
+    ///     type WriteOperation = u64;
+    ///     type Response = u64;
+    ///
+    ///     fn dispatch(&self, _op: Self::ReadOperation) -> Self::Response {
+    ///         self.junk
+    ///     }
+    ///
+    ///     fn dispatch_mut(&mut self, op: Self::WriteOperation) -> Self::Response {
+    ///         self.junk = op;
+    ///         op
+    ///     }
+    /// }
+    ///
+    /// let log = Arc::new(Log::<<Data as Dispatch>::WriteOperation>::default());
+    /// let replica = Replica::<Data>::new(&log);
+    /// let idx = replica.register().expect("Failed to register with replica.");
+    ///
+    /// block_on(async {
+    ///     assert_eq!(replica.execute_mut_future(100, idx).await, 100);
+    ///     assert_eq!(replica.execute_future((), idx).await, 100);
+    /// });
+    /// ```
+    pub fn execute_mut_future(
+        &self,
+        op: <D as Dispatch>::WriteOperation,
+        idx: ReplicaToken,
+    ) -> impl Future<Output = <D as Dispatch>::Response> + Send + '_ {
+        let mut op = Some(op);
+        poll_fn(move |cx| {
+            if let Some(o) = &op {
+                match self.poll_make_pending(o, idx.0, cx) {
+                    Poll::Ready(r) => r.unwrap_or_else(|e| panic!("{}", e)),
+                    Poll::Pending => return Poll::Pending,
+                }
+                op = None;
+            }
+
+            self.poll_response(idx.0, cx).map(|r| {
+                r.map(|(resp, _pos)| resp)
+                    .unwrap_or_else(|e| panic!("{}", e))
+            })
+        })
+    }
+
+    /// Same as `execute`, but returns a future that resolves to the response.
+    /// While the replica is behind the shared log and another thread is combining,
+    /// the future yields to the executor instead of waiting.
+    pub fn execute_future(
+        &self,
+        op: <D as Dispatch>::ReadOperation,
+        idx: ReplicaToken,
+    ) -> impl Future<Output = <D as Dispatch>::Response> + Send + '_ {
+        let mut ctail = None;
+        poll_fn(move |cx| {
+            let ctail = *ctail.get_or_insert_with(|| self.slog.get_ctail());
+            self.poll_read(&op, idx.0, ctail, cx)
+                .map(|r| r.unwrap_or_else(|e| panic!("{}", e)))
+        })
     }
 
+    /// Enqueues a mutable operation on the replica and sets `resp` to a future that
+    /// resolves to its response. Waits for a free slot on the thread's context,
+    /// yielding to the executor while the context is full.
+    ///
+    /// Reusing `resp` across operations avoids allocating a new future for every
+    /// operation. Like `execute_mut`, panics if the operation can't be executed.
     pub async fn async_execute_mut(
         &'a self,
         op: <D as Dispatch>::WriteOperation,
         rid: ReplicaToken,
         resp: &mut ReusableBoxFuture<'a, <D as Dispatch>::Response>,
     ) {
-        // Enqueue the operation onto the thread local batch.
-        // For a single thread the future will append the operation and yield.
-        async { while !self.make_pending(op.clone(), rid.0) {} }.await;
-
-        resp.set(async move {
-            // Check for the response even before becoming the combiner,
-            // as some other async combiner might have completed the work.
-            match self.contexts[rid.0 - 1].res() {
-                Some(res) => res,
-                None => {
-                    self.try_combine(rid.0);
-                    self.get_response(rid.0)
-                }
-            }
-        });
+        // Enqueue the operation onto the thread local batch, yielding while it's full.
+        poll_fn(|cx| self.poll_make_pending(&op, rid.0, cx))
+            .await
+            .unwrap_or_else(|e| panic!("{}", e));
+
+        resp.set(poll_fn(move |cx| {
+            self.poll_response(rid.0, cx).map(|r| {
+                r.map(|(resp, _pos)| resp)
+                    .unwrap_or_else(|e| panic!("{}", e))
+            })
+        }));
     }
 
+    /// Sets `resp` to a future that resolves to the response of the read-only
+    /// operation `op`, see `execute_future`.
+    ///
+    /// Reusing `resp` across operations avoids allocating a new future for every
+    /// operation. Like `execute`, panics if the operation can't be executed.
     pub fn async_execute(
         &'a self,
         op: <D as Dispatch>::ReadOperation,
         idx: ReplicaToken,
         resp: &mut ReusableBoxFuture<'a, <D as Dispatch>::Response>,
     ) {
-        resp.set(async move { self.read_only(op, idx.0) });
+        resp.set(self.execute_future(op, idx));
+    }
+}
+
+impl<'a, D, const MAX_REPLICAS: usize, const MAX_THREADS: usize, const MAX_PENDING: usize> Drop
+    for Replica<'a, D, MAX_REPLICAS, MAX_THREADS, MAX_PENDING>
+where
+    D: Sized + Dispatch + Sync,
+{
+    /// Releases this replica's identifier on the shared log, so that the log
+    /// no longer waits for the replica when garbage collecting entries.
+    fn drop(&mut self) {
+        self.slog.unregister(self.idx);
     }
 }
 
@@ -723,71 +2263,440 @@ where
 mod test {
     extern crate std;
 
-    use super::*;
-    use std::vec;
+    use super::*;
+    use std::vec;
+
+    // Really dumb data structure to test against the Replica and shared log.
+    #[derive(Default, Clone)]
+    struct Data {
+        junk: u64,
+    }
+
+    impl Dispatch for Data {
+        type ReadOperation = u64;
+        type WriteOperation = u64;
+        type Response = Result<u64, ()>;
+
+        fn dispatch(&self, _op: Self::ReadOperation) -> Self::Response {
+            Ok(self.junk)
+        }
+
+        fn dispatch_mut(&mut self, _op: Self::WriteOperation) -> Self::Response {
+            self.junk += 1;
+            return Ok(107);
+        }
+    }
+
+    // Tests whether we can construct a Replica given a log.
+    #[test]
+    fn test_replica_create() {
+        let slog = Arc::new(Log::<<Data as Dispatch>::WriteOperation>::new(1024));
+        let repl = Replica::<Data>::new(&slog);
+        assert_eq!(repl.idx, 1);
+        assert_eq!(repl.id(), 1);
+        assert_eq!(repl.combiner.load(Ordering::SeqCst), 0);
+        assert_eq!(repl.next.load(Ordering::SeqCst), 1);
+        assert_eq!(repl.contexts.len(), MAX_THREADS_PER_REPLICA);
+        assert_eq!(
+            repl.buffer.borrow().capacity(),
+            MAX_THREADS_PER_REPLICA * Context::<u64, Result<u64, ()>>::batch_size()
+        );
+        assert_eq!(repl.inflight.borrow().len(), MAX_THREADS_PER_REPLICA);
+        assert_eq!(
+            repl.result.borrow().capacity(),
+            MAX_THREADS_PER_REPLICA * Context::<u64, Result<u64, ()>>::batch_size()
+        );
+        assert_eq!(repl.data.read(0).junk, 0);
+    }
+
+    // Tests that a replica constructed with a placement allocates its data
+    // structure and contexts through it, and starts out with the data structure
+    // it was given.
+    #[test]
+    fn test_replica_with_data_in() {
+        use crate::placement::{Global, Placement};
+        use core::alloc::Layout;
+        use std::sync::Mutex;
+
+        #[derive(Clone, Default)]
+        struct Recording(Arc<Mutex<vec::Vec<(usize, bool)>>>);
+
+        unsafe impl Placement for Recording {
+            fn alloc(&self, layout: Layout) -> *mut u8 {
+                self.0.lock().unwrap().push((layout.size(), true));
+                Global.alloc(layout)
+            }
+
+            unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+                self.0.lock().unwrap().push((layout.size(), false));
+                Global.dealloc(ptr, layout)
+            }
+        }
+
+        let placement = Recording::default();
+        let slog = Arc::new(Log::<<Data as Dispatch>::WriteOperation>::new(1024));
+        let repl = Replica::with_data_in(&slog, Data { junk: 9 }, placement.clone());
+
+        let contexts = core::mem::size_of_val(&repl.contexts[..]);
+        let data = core::mem::size_of_val(&*repl.data);
+        assert_eq!(
+            *placement.0.lock().unwrap(),
+            [(contexts, true), (data, true)]
+        );
+
+        let idx = repl.register().unwrap();
+        assert_eq!(repl.execute(0, idx), Ok(9));
+
+        drop(repl);
+        assert_eq!(placement.0.lock().unwrap().len(), 4);
+    }
+
+    // Tests whether we can register with this replica and receive an idx.
+    #[test]
+    fn test_replica_register() {
+        let slog = Arc::new(Log::<<Data as Dispatch>::WriteOperation>::new(1024));
+        let repl = Replica::<Data>::new(&slog);
+        assert_eq!(repl.register(), Some(ReplicaToken(1)));
+        assert_eq!(repl.next.load(Ordering::SeqCst), 2);
+        repl.next.store(17, Ordering::SeqCst);
+        assert_eq!(repl.register(), Some(ReplicaToken(17)));
+        assert_eq!(repl.next.load(Ordering::SeqCst), 18);
+    }
+
+    // Tests whether registering more than the maximum limit of threads per replica is disallowed.
+    #[test]
+    fn test_replica_register_none() {
+        let slog = Arc::new(Log::<<Data as Dispatch>::WriteOperation>::new(1024));
+        let repl = Replica::<Data>::new(&slog);
+        repl.next
+            .store(MAX_THREADS_PER_REPLICA + 1, Ordering::SeqCst);
+        assert!(repl.register().is_none());
+    }
+
+    // Tests that a replica can be sized for more threads than the default, and
+    // that every one of them can read through the replica's lock.
+    #[test]
+    fn test_replica_custom_sizes() {
+        let slog = Arc::new(Log::<<Data as Dispatch>::WriteOperation, 2, 512, 2>::new(
+            1024,
+        ));
+        let repl = Replica::<Data, 2, 512, 2>::new(&slog);
+        assert_eq!(repl.contexts.len(), 512);
+        assert_eq!(repl.buffer.borrow().capacity(), 1024);
+
+        let mut tokens = Vec::new();
+        while let Some(idx) = repl.register() {
+            tokens.push(idx);
+        }
+        assert_eq!(tokens.len(), 512);
+
+        for (i, idx) in tokens.iter().enumerate() {
+            assert_eq!(Ok(107), repl.execute_mut(i as u64, *idx));
+            assert_eq!(Ok(i as u64 + 1), repl.execute(0, *idx));
+        }
+    }
+
+    // Tests that threads which park while they wait for their responses all get
+    // them, whether a combiner wakes them up or they time out and combine.
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_replica_spin_then_park() {
+        use crate::wait::SpinThenPark;
+        use core::time::Duration;
+
+        let slog = Arc::new(Log::<<Data as Dispatch>::WriteOperation>::new(1024));
+        let repl = Replica::with_wait_strategy(
+            &slog,
+            Data::default(),
+            SpinThenPark::new(4, Duration::from_millis(1)),
+        );
+
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                let repl = repl.clone();
+                std::thread::spawn(move || {
+                    let idx = repl.register().unwrap();
+                    for i in 0..200 {
+                        assert_eq!(Ok(107), repl.execute_mut(i, idx));
+                    }
+                })
+            })
+            .collect();
+        for t in threads {
+            t.join().unwrap();
+        }
+
+        let idx = repl.register().unwrap();
+        assert_eq!(Ok(800), repl.execute(0, idx));
+    }
+
+    // Tests that the fallible constructor and registration report exhausted
+    // replica and thread identifiers.
+    #[test]
+    fn test_replica_try_register() {
+        let slog = Arc::new(Log::<<Data as Dispatch>::WriteOperation, 2, 4, 2>::new(
+            1024,
+        ));
+        let repl = Replica::<Data, 2, 4, 2>::try_new(&slog).unwrap();
+        assert_eq!(
+            Replica::<Data, 2, 4, 2>::try_new(&slog).err(),
+            Some(Error::ReplicasExhausted)
+        );
+        assert_eq!(
+            Replica::try_with_wait_strategy(&slog, Data::default(), Spin).err(),
+            Some(Error::ReplicasExhausted)
+        );
+
+        for i in 1..=4 {
+            assert_eq!(repl.try_register(), Ok(ReplicaToken(i)));
+        }
+        assert_eq!(repl.try_register(), Err(Error::ThreadsExhausted));
+        assert_eq!(repl.register(), None);
+
+        assert_eq!(repl.try_execute_mut(0, ReplicaToken(4)), Ok(Ok(107)));
+        assert_eq!(repl.try_execute(0, ReplicaToken(1)), Ok(Ok(1)));
+    }
+
+    // A clock that moves forward by a millisecond every time it is read.
+    struct Ticks(core::cell::Cell<u64>);
+
+    impl Clock for Ticks {
+        fn now(&self) -> Duration {
+            self.0.set(self.0.get() + 1);
+            Duration::from_millis(self.0.get())
+        }
+    }
+
+    // Tests that a write that can't be enqueued before the deadline times out
+    // without being executed.
+    #[test]
+    fn test_replica_execute_mut_deadline_timed_out() {
+        let slog = Arc::new(Log::<<Data as Dispatch>::WriteOperation>::new(1024));
+        let repl = Replica::<Data>::new(&slog);
+        let idx = repl.register().unwrap();
+
+        // Fill up the thread's batch with operations nobody collects.
+        while repl.make_pending(121, idx.0) {}
+        repl.combiner.store(8, Ordering::SeqCst);
+
+        let clock = Ticks(core::cell::Cell::new(0));
+        assert_eq!(
+            repl.execute_mut_deadline(121, idx, &clock, Duration::from_millis(5)),
+            Err(Error::TimedOut)
+        );
+        assert_eq!(repl.contexts[0].tail.get(), MAX_PENDING_OPS);
+    }
+
+    // Tests that a write whose response doesn't arrive in time is reported as in
+    // flight, and that its response can be collected later.
+    #[test]
+    fn test_replica_execute_mut_deadline_in_flight() {
+        let slog = Arc::new(Log::<<Data as Dispatch>::WriteOperation>::new(1024));
+        let repl = Replica::<Data>::new(&slog);
+        let idx = repl.register().unwrap();
+
+        // Another thread holds the combiner lock.
+        repl.combiner.store(8, Ordering::SeqCst);
+        let clock = Ticks(core::cell::Cell::new(0));
+        assert_eq!(
+            repl.execute_mut_deadline(121, idx, &clock, Duration::from_millis(5)),
+            Err(Error::InFlight)
+        );
+        assert_eq!(repl.data.read(0).junk, 0);
 
-    // Really dumb data structure to test against the Replica and shared log.
-    #[derive(Default)]
-    struct Data {
-        junk: u64,
+        repl.combiner.store(0, Ordering::SeqCst);
+        let deadline = clock.now() + Duration::from_millis(5);
+        assert_eq!(
+            repl.collect_response_deadline(idx, &clock, deadline),
+            Ok(Ok(107))
+        );
+        assert_eq!(repl.execute(0, idx), Ok(1));
     }
 
-    impl Dispatch for Data {
-        type ReadOperation = u64;
-        type WriteOperation = u64;
-        type Response = Result<u64, ()>;
+    // Tests that a write gives up at its deadline instead of waiting for GC while
+    // another replica is stalled, and that writes go through once it catches up.
+    #[test]
+    fn test_replica_execute_mut_deadline_stalled_replica() {
+        let slog = Arc::new(Log::<<Data as Dispatch>::WriteOperation>::new(1024));
+        let one = Replica::<Data>::new(&slog);
+        let two = Replica::<Data>::new(&slog);
+        let i1 = one.register().unwrap();
+        let i2 = two.register().unwrap();
 
-        fn dispatch(&self, _op: Self::ReadOperation) -> Self::Response {
-            Ok(self.junk)
+        let clock = Ticks(core::cell::Cell::new(0));
+        let mut n = 0;
+        loop {
+            let deadline = clock.now() + Duration::from_millis(5);
+            match one.execute_mut_deadline(121, i1, &clock, deadline) {
+                Ok(resp) => assert_eq!(resp, Ok(107)),
+                Err(e) => {
+                    assert_eq!(e, Error::TimedOut);
+                    break;
+                }
+            }
+            n += 1;
         }
+        assert!(n > 0);
+        assert!(!one.contexts[0].has_pending());
+        assert_eq!(one.execute(0, i1), Ok(n));
 
-        fn dispatch_mut(&mut self, _op: Self::WriteOperation) -> Self::Response {
-            self.junk += 1;
-            return Ok(107);
-        }
+        two.sync(i2);
+        assert_eq!(one.execute_mut(121, i1), Ok(107));
+        assert_eq!(two.execute(0, i2), Ok(n + 1));
     }
 
-    // Tests whether we can construct a Replica given a log.
+    // Tests that a read on a replica that can't catch up with the log times out.
     #[test]
-    fn test_replica_create() {
+    fn test_replica_execute_deadline_timed_out() {
         let slog = Arc::new(Log::<<Data as Dispatch>::WriteOperation>::new(1024));
-        let repl = Replica::<Data>::new(&slog);
-        assert_eq!(repl.idx, 1);
-        assert_eq!(repl.combiner.load(Ordering::SeqCst), 0);
-        assert_eq!(repl.next.load(Ordering::SeqCst), 1);
-        assert_eq!(repl.contexts.len(), MAX_THREADS_PER_REPLICA);
+        let one = Replica::<Data>::new(&slog);
+        let two = Replica::<Data>::new(&slog);
+        let i1 = one.register().unwrap();
+        let i2 = two.register().unwrap();
+        assert_eq!(one.execute_mut(121, i1), Ok(107));
+
+        two.combiner.store(8, Ordering::SeqCst);
+        let clock = Ticks(core::cell::Cell::new(0));
         assert_eq!(
-            repl.buffer.borrow().capacity(),
-            MAX_THREADS_PER_REPLICA * Context::<u64, Result<u64, ()>>::batch_size()
+            two.execute_deadline(0, i2, &clock, Duration::from_millis(5)),
+            Err(Error::TimedOut)
         );
-        assert_eq!(repl.inflight.borrow().len(), MAX_THREADS_PER_REPLICA);
+
+        two.combiner.store(0, Ordering::SeqCst);
         assert_eq!(
-            repl.result.borrow().capacity(),
-            MAX_THREADS_PER_REPLICA * Context::<u64, Result<u64, ()>>::batch_size()
+            two.execute_deadline(0, i2, &clock, Duration::from_secs(60)),
+            Ok(Ok(1))
         );
-        assert_eq!(repl.data.read(0).junk, 0);
     }
 
-    // Tests whether we can register with this replica and receive an idx.
+    // Tests the std based timeouts.
     #[test]
-    fn test_replica_register() {
+    #[cfg(feature = "std")]
+    fn test_replica_execute_timeout() {
         let slog = Arc::new(Log::<<Data as Dispatch>::WriteOperation>::new(1024));
         let repl = Replica::<Data>::new(&slog);
-        assert_eq!(repl.register(), Some(ReplicaToken(1)));
-        assert_eq!(repl.next.load(Ordering::SeqCst), 2);
-        repl.next.store(17, Ordering::SeqCst);
-        assert_eq!(repl.register(), Some(ReplicaToken(17)));
-        assert_eq!(repl.next.load(Ordering::SeqCst), 18);
+        let idx = repl.register().unwrap();
+
+        let timeout = Duration::from_secs(60);
+        assert_eq!(repl.execute_mut_timeout(121, idx, timeout), Ok(Ok(107)));
+        assert_eq!(repl.execute_timeout(0, idx, timeout), Ok(Ok(1)));
+
+        repl.combiner.store(8, Ordering::SeqCst);
+        assert_eq!(
+            repl.execute_mut_timeout(121, idx, Duration::from_millis(1)),
+            Err(Error::InFlight)
+        );
+        repl.combiner.store(0, Ordering::SeqCst);
+        assert_eq!(repl.collect_response_timeout(idx, timeout), Ok(Ok(107)));
     }
 
-    // Tests whether registering more than the maximum limit of threads per replica is disallowed.
+    // Tests that dropping a replica releases its identifier on the shared log,
+    // which is handed out again to the next replica that is created or joins.
     #[test]
-    fn test_replica_register_none() {
+    fn test_replica_drop_unregisters() {
         let slog = Arc::new(Log::<<Data as Dispatch>::WriteOperation>::new(1024));
+        let one = Replica::<Data>::new(&slog);
+        let two = Replica::<Data>::new(&slog);
+        assert_eq!(two.idx, 2);
+
+        drop(two);
+        let three = Replica::<Data>::new(&slog);
+        assert_eq!(three.idx, 2);
+        drop(three);
+        let idx = one.register().unwrap();
+        let four = Replica::join_from(&one, idx);
+        assert_eq!(four.idx, 2);
+    }
+
+    // Tests that combiners pass the entries they append to the observer of the
+    // log, without anyone draining it.
+    #[test]
+    fn test_replica_observer() {
+        let seen = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let mut log = Log::<<Data as Dispatch>::WriteOperation>::new(1024);
+        let s = seen.clone();
+        log.update_observer(4, move |_pos, _replica, _op| {
+            s.fetch_add(1, Ordering::Relaxed);
+        });
+        let slog = Arc::new(log);
+
         let repl = Replica::<Data>::new(&slog);
-        repl.next
-            .store(MAX_THREADS_PER_REPLICA + 1, Ordering::SeqCst);
-        assert!(repl.register().is_none());
+        let idx = repl.register().unwrap();
+        for i in 0..100 {
+            assert_eq!(repl.execute_mut(i, idx), Ok(107));
+        }
+        assert_eq!(seen.load(Ordering::Relaxed), 100);
+    }
+
+    // Tests that replicas can be created and dropped more often than there are
+    // identifiers on the log, and that each new one catches up with the log.
+    #[test]
+    fn test_replica_create_drop_churn() {
+        let slog = Arc::new(Log::<<Data as Dispatch>::WriteOperation>::new(1024));
+        let one = Replica::<Data>::new(&slog);
+        let idx = one.register().unwrap();
+
+        for i in 0..2 * MAX_REPLICAS_PER_LOG {
+            assert_eq!(one.execute_mut(i as u64, idx), Ok(107));
+            let repl = Replica::<Data>::new(&slog);
+            assert_eq!(repl.idx, 2);
+            let tdx = repl.register().unwrap();
+            assert_eq!(repl.execute(0, tdx), Ok(i as u64 + 1));
+        }
+    }
+
+    // Tests that a replica created after another one was dropped executes the
+    // operations appended before, instead of taking over the dropped one's position.
+    #[test]
+    fn test_replica_new_after_drop() {
+        let slog = Arc::new(Log::<<Data as Dispatch>::WriteOperation>::new(1024));
+        let one = Replica::<Data>::new(&slog);
+        let two = Replica::<Data>::new(&slog);
+        let idx = one.register().unwrap();
+        for i in 0..5 {
+            assert_eq!(one.execute_mut(i, idx), Ok(107));
+        }
+        drop(two);
+
+        let three = Replica::<Data>::new(&slog);
+        let tdx = three.register().unwrap();
+        assert_eq!(one.execute(0, idx), Ok(5));
+        assert_eq!(three.execute(0, tdx), Ok(5));
+    }
+
+    // Tests that a replica joining late starts out with the state of the existing
+    // replica and keeps up with the log from there on.
+    #[test]
+    fn test_replica_join_from() {
+        let slog = Arc::new(Log::<<Data as Dispatch>::WriteOperation>::new(1024));
+        let one = Replica::<Data>::new(&slog);
+        let idx = one.register().unwrap();
+        // Enough operations to wrap around and garbage collect the log a few times.
+        for i in 0..50_000 {
+            assert_eq!(one.execute_mut(i, idx), Ok(107));
+        }
+
+        let two = Replica::join_from(&one, idx);
+        assert_eq!(two.idx, 2);
+        assert_eq!(slog.get_ltail(2), slog.get_ltail(1));
+        assert_eq!(two.data.read(0).junk, one.data.read(0).junk);
+
+        assert_eq!(one.execute_mut(0, idx), Ok(107));
+        let jdx = two.register().unwrap();
+        assert_eq!(two.execute(0, jdx), Ok(50_001));
+    }
+
+    // Tests that joining a log that can't take another replica fails with an error.
+    #[test]
+    fn test_replica_try_join_from() {
+        let slog = Arc::new(Log::<<Data as Dispatch>::WriteOperation, 2>::new(1024));
+        let one = Replica::<Data, 2>::new(&slog);
+        let idx = one.register().unwrap();
+        assert_eq!(
+            Replica::try_join_from(&one, idx).err(),
+            Some(Error::ReplicasExhausted)
+        );
+        assert_eq!(one.execute_mut(0, idx), Ok(107));
     }
 
     // Tests that we can successfully allow operations to go pending on this replica.
@@ -823,7 +2732,7 @@ mod test {
         let _idx = repl.register();
 
         repl.make_pending(121, 1);
-        repl.try_combine(1);
+        assert_eq!(repl.try_combine(1), Ok(()));
 
         assert_eq!(repl.combiner.load(Ordering::SeqCst), 0);
         assert_eq!(repl.data.read(0).junk, 1);
@@ -838,7 +2747,7 @@ mod test {
 
         repl.next.store(9, Ordering::SeqCst);
         repl.make_pending(121, 8);
-        repl.try_combine(1);
+        assert_eq!(repl.try_combine(1), Ok(()));
 
         assert_eq!(repl.data.read(0).junk, 1);
         assert_eq!(repl.contexts[7].res(), Some(Ok(107)));
@@ -853,7 +2762,7 @@ mod test {
         repl.next.store(9, Ordering::SeqCst);
         repl.combiner.store(8, Ordering::SeqCst);
         repl.make_pending(121, 1);
-        repl.try_combine(1);
+        assert_eq!(repl.try_combine(1), Ok(()));
 
         assert_eq!(repl.data.read(0).junk, 0);
         assert_eq!(repl.contexts[0].res(), None);
@@ -880,7 +2789,7 @@ mod test {
 
         repl.make_pending(121, 1);
 
-        assert_eq!(repl.get_response(1), Ok(107));
+        assert_eq!(repl.get_response(1), Ok(Ok(107)));
     }
 
     // Tests whether we can issue a read-only operation against the replica.
@@ -910,6 +2819,428 @@ mod test {
         assert_eq!(Ok(2), repl.execute(11, t1));
     }
 
+    // Tests that the stats of a replica count rounds of flat combining, the
+    // operations appended in them, and reads that had to wait on the log.
+    #[test]
+    #[cfg(feature = "stats")]
+    fn test_replica_stats() {
+        let slog = Arc::new(Log::<<Data as Dispatch>::WriteOperation>::default());
+        let repl = Replica::<Data>::new(&slog);
+        let idx = repl.register().expect("Failed to register with replica.");
+
+        for i in 0..3 {
+            assert_eq!(Ok(107), repl.execute_mut(i, idx));
+        }
+        assert_eq!(Ok(3), repl.execute(11, idx));
+
+        let stats = repl.stats();
+        assert_eq!(stats.combiner_rounds, 3);
+        assert_eq!(stats.combined_ops, 3);
+        assert_eq!(stats.max_batch, 1);
+        assert_eq!(stats.read_sync_waits, 0);
+
+        let o = [121, 212];
+        slog.append(&o, 2, |_o: u64, _i: usize| {});
+        slog.exec(2, &mut |_o: u64, _i: usize| {});
+        assert_eq!(Ok(5), repl.execute(11, idx));
+        assert_eq!(repl.stats().read_sync_waits, 1);
+    }
+
+    // Tests that every replica passes the same context to `dispatch_mut_with`.
+    #[test]
+    fn test_replica_dispatch_mut_with() {
+        use crate::clock::Clock;
+
+        #[derive(Default)]
+        struct Contexts {
+            seen: Vec<WriteContext>,
+        }
+
+        impl Dispatch for Contexts {
+            type ReadOperation = ();
+            type WriteOperation = u64;
+            type Response = usize;
+
+            fn dispatch(&self, _op: Self::ReadOperation) -> Self::Response {
+                self.seen.len()
+            }
+
+            fn dispatch_mut(&mut self, _op: Self::WriteOperation) -> Self::Response {
+                unreachable!("Replicas invoke dispatch_mut_with.")
+            }
+
+            fn dispatch_mut_with(&mut self, _op: u64, ctx: &WriteContext) -> usize {
+                self.seen.push(*ctx);
+                ctx.pos
+            }
+        }
+
+        struct Ticks(AtomicUsize);
+        impl Clock for Ticks {
+            fn now(&self) -> Duration {
+                Duration::from_secs(self.0.fetch_add(1, Ordering::Relaxed) as u64)
+            }
+        }
+
+        let mut log = Log::<u64>::default();
+        log.update_clock(Ticks(AtomicUsize::new(0)));
+        let slog = Arc::new(log);
+        let one = Replica::<Contexts>::new(&slog);
+        let two = Replica::<Contexts>::new(&slog);
+        let t1 = one.register().unwrap();
+        let t2 = one.register().unwrap();
+        let t3 = two.register().unwrap();
+
+        assert_eq!(one.execute_mut(10, t2), 0);
+        assert_eq!(two.execute_mut(11, t3), 1);
+        assert_eq!(one.execute_mut(12, t1), 2);
+        two.sync(t3);
+
+        let expected = [(0, 1, 2), (1, 2, 1), (2, 1, 1)];
+        for r in [&one, &two].iter() {
+            r.verify(|d| {
+                let seen: Vec<(usize, usize, usize)> = d
+                    .seen
+                    .iter()
+                    .map(|c| (c.pos, c.replica, c.thread))
+                    .collect();
+                assert_eq!(seen, expected);
+                let stamps: Vec<Option<Duration>> = d.seen.iter().map(|c| c.timestamp).collect();
+                assert_eq!(stamps, [0, 1, 2].map(|s| Some(Duration::from_secs(s))));
+            });
+        }
+    }
+
+    // Tests that a stale read only catches up with the log if the replica lags
+    // behind by more than the given number of entries.
+    #[test]
+    fn test_replica_execute_stale() {
+        let slog = Arc::new(Log::<<Data as Dispatch>::WriteOperation>::default());
+        let one = Replica::<Data>::new(&slog);
+        let two = Replica::<Data>::new(&slog);
+        let idx1 = one.register().unwrap();
+        let idx2 = two.register().unwrap();
+        for i in 0..3 {
+            assert_eq!(two.execute_mut(i, idx2), Ok(107));
+        }
+
+        assert_eq!(one.execute_stale(0, idx1, usize::MAX), Ok(0));
+        assert_eq!(one.execute_stale(0, idx1, 3), Ok(0));
+        assert_eq!(slog.get_ltail(one.idx), 0);
+
+        assert_eq!(one.execute_stale(0, idx1, 2), Ok(3));
+        assert_eq!(slog.get_ltail(one.idx), 3);
+        assert_eq!(two.execute_mut(3, idx2), Ok(107));
+        assert_eq!(one.execute_stale(0, idx1, 1), Ok(3));
+    }
+
+    // Tests that read_with() sees the writes of other replicas.
+    #[test]
+    fn test_replica_read_with() {
+        let slog = Arc::new(Log::<<Data as Dispatch>::WriteOperation>::default());
+        let one = Replica::<Data>::new(&slog);
+        let two = Replica::<Data>::new(&slog);
+        let idx1 = one.register().unwrap();
+        let idx2 = two.register().unwrap();
+
+        assert_eq!(two.execute_mut(0, idx2), Ok(107));
+        assert_eq!(two.execute_mut(0, idx2), Ok(107));
+
+        let mut seen = 0;
+        one.read_with(idx1, |d| seen = d.junk);
+        assert_eq!(seen, 2);
+        assert_eq!(slog.get_ltail(one.idx), 2);
+    }
+
+    // Tests that a batch of writes larger than the context is split up, and that
+    // its responses come back in order.
+    #[test]
+    fn test_replica_execute_mut_batch() {
+        let slog = Arc::new(Log::<<Data as Dispatch>::WriteOperation>::default());
+        let repl = Replica::<Data>::new(&slog);
+        let idx = repl.register().unwrap();
+
+        let ops = vec![0; 2 * MAX_PENDING_OPS + 3];
+        let resps = repl.execute_mut_batch(&ops, idx);
+        assert_eq!(resps, vec![Ok(107); ops.len()]);
+        assert_eq!(repl.execute(0, idx), Ok(ops.len() as u64));
+        assert_eq!(slog.get_ltail(repl.idx), ops.len());
+
+        assert_eq!(repl.execute_mut_batch(&[], idx), vec![]);
+        assert_eq!(repl.execute_mut(0, idx), Ok(107));
+    }
+
+    // Tests that writes that wait for their response go through when the
+    // thread's context is full of writes that don't.
+    #[test]
+    fn test_replica_execute_mut_after_noreply() {
+        let slog = Arc::new(Log::<<Data as Dispatch>::WriteOperation>::default());
+        let repl = Replica::<Data>::new(&slog);
+        let idx = repl.register().unwrap();
+
+        for i in 0..MAX_PENDING_OPS as u64 {
+            repl.execute_mut_noreply(i, idx);
+        }
+        assert_eq!(repl.execute_mut(0, idx), Ok(107));
+
+        for i in 0..MAX_PENDING_OPS as u64 {
+            repl.execute_mut_noreply(i, idx);
+        }
+        assert_eq!(repl.execute_mut_with_pos(0, idx).0, Ok(107));
+
+        for i in 0..MAX_PENDING_OPS as u64 {
+            repl.execute_mut_noreply(i, idx);
+        }
+        let clock = Ticks(core::cell::Cell::new(0));
+        assert_eq!(
+            repl.execute_mut_deadline(0, idx, &clock, Duration::from_secs(60)),
+            Ok(Ok(107))
+        );
+        assert_eq!(repl.execute(0, idx), Ok(3 * MAX_PENDING_OPS as u64 + 3));
+    }
+
+    // Tests that a batch goes through when the thread's context already holds
+    // operations that don't wait for a response.
+    #[test]
+    fn test_replica_execute_mut_batch_after_noreply() {
+        let slog = Arc::new(Log::<<Data as Dispatch>::WriteOperation>::default());
+        let repl = Replica::<Data>::new(&slog);
+        let idx = repl.register().unwrap();
+
+        repl.execute_mut_noreply(0, idx);
+        let resps = repl.execute_mut_batch(&[1; MAX_PENDING_OPS], idx);
+        assert_eq!(resps, vec![Ok(107); MAX_PENDING_OPS]);
+        assert_eq!(repl.execute(0, idx), Ok(MAX_PENDING_OPS as u64 + 1));
+    }
+
+    // Tests that threads can keep registering and unregistering without running
+    // out of slots, and that unregistering executes the thread's operations.
+    #[test]
+    fn test_replica_unregister() {
+        let slog = Arc::new(Log::<<Data as Dispatch>::WriteOperation>::default());
+        let repl = Replica::<Data>::new(&slog);
+        let idx1 = repl.register().unwrap();
+
+        for i in 0..2 * MAX_THREADS_PER_REPLICA {
+            let idx = repl.register().unwrap();
+            assert_eq!(idx.id(), 2);
+            repl.execute_mut_noreply(0, idx);
+            if i % 2 == 0 {
+                assert_eq!(repl.execute_mut(0, idx), Ok(107));
+            }
+            repl.unregister(idx);
+            assert!(repl.contexts[1].free.load(Ordering::Relaxed));
+        }
+
+        assert_eq!(repl.next.load(Ordering::SeqCst), 3);
+        assert_eq!(
+            repl.execute(0, idx1),
+            Ok(3 * MAX_THREADS_PER_REPLICA as u64)
+        );
+    }
+
+    // Tests that a thread can't unregister twice.
+    #[test]
+    #[should_panic]
+    fn test_replica_unregister_twice() {
+        let slog = Arc::new(Log::<<Data as Dispatch>::WriteOperation>::default());
+        let repl = Replica::<Data>::new(&slog);
+        let idx = repl.register().unwrap();
+
+        repl.unregister(idx);
+        repl.unregister(idx);
+    }
+
+    // Tests that writes without a reply get executed by a flush, or by the
+    // operations the thread issues after them.
+    #[test]
+    fn test_replica_execute_mut_noreply() {
+        let slog = Arc::new(Log::<<Data as Dispatch>::WriteOperation>::default());
+        let repl = Replica::<Data>::new(&slog);
+        let idx = repl.register().unwrap();
+
+        for i in 0..(2 * MAX_PENDING_OPS as u64 + 1) {
+            repl.execute_mut_noreply(i, idx);
+        }
+        assert!(repl.contexts[0].has_pending());
+        repl.flush(idx);
+        assert!(!repl.contexts[0].has_pending());
+        assert_eq!(repl.execute(0, idx), Ok(2 * MAX_PENDING_OPS as u64 + 1));
+
+        repl.execute_mut_noreply(0, idx);
+        assert_eq!(repl.execute_mut(0, idx), Ok(107));
+        assert_eq!(repl.contexts[0].res(), None);
+        assert_eq!(repl.execute(0, idx), Ok(2 * MAX_PENDING_OPS as u64 + 3));
+        repl.flush(idx);
+    }
+
+    // Tests that writes report their position on the log, and that reads only wait
+    // for the replica to execute the write at the position they are given.
+    #[test]
+    fn test_replica_execute_at_least() {
+        let slog = Arc::new(Log::<<Data as Dispatch>::WriteOperation>::default());
+        let one = Replica::<Data>::new(&slog);
+        let two = Replica::<Data>::new(&slog);
+        let idx1 = one.register().unwrap();
+        let idx2 = two.register().unwrap();
+
+        assert_eq!(one.execute_mut_with_pos(0, idx1), (Ok(107), 0));
+        assert_eq!(two.execute_mut_with_pos(0, idx2), (Ok(107), 1));
+        assert_eq!(two.execute_mut_with_pos(0, idx2), (Ok(107), 2));
+
+        assert_eq!(one.execute_at_least(0, idx1, 0), Ok(1));
+        assert_eq!(slog.get_ltail(one.idx), 1);
+        assert_eq!(one.execute_at_least(0, idx1, 1), Ok(3));
+        assert_eq!(slog.get_ltail(one.idx), 3);
+
+        assert_eq!(two.execute_mut_with_pos(0, idx2), (Ok(107), 3));
+        assert_eq!(one.execute_at_least(0, idx1, 2), Ok(3));
+        assert_eq!(one.execute(0, idx1), Ok(4));
+    }
+
+    // Counts how often the waker of a task was woken up.
+    #[derive(Default)]
+    struct WakeCount(AtomicUsize);
+
+    impl futures::task::ArcWake for WakeCount {
+        fn wake_by_ref(arc_self: &Arc<Self>) {
+            arc_self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    // Tests that a write future doesn't wait while another thread combines, and
+    // that the combiner wakes it up once it enqueued the response.
+    #[test]
+    fn test_replica_execute_mut_future() {
+        use core::future::Future;
+        use core::task::{Context, Poll};
+
+        let slog = Arc::new(Log::<<Data as Dispatch>::WriteOperation>::default());
+        let repl = Replica::<Data>::new(&slog);
+        let idx1 = repl.register().unwrap();
+        let idx2 = repl.register().unwrap();
+
+        let count = Arc::new(WakeCount::default());
+        let waker = futures::task::waker(count.clone());
+        let mut cx = Context::from_waker(&waker);
+
+        // Another thread is combining, and the context is full. Some task
+        // registered a waker before, so the combiner looks for wakers.
+        repl.async_waiters.store(true, Ordering::SeqCst);
+        repl.combiner.store(idx2.id(), Ordering::SeqCst);
+        for _i in 0..MAX_PENDING_OPS {
+            repl.execute_mut_noreply(0, idx1);
+        }
+        let mut fut = std::boxed::Box::pin(repl.execute_mut_future(0, idx1));
+        assert_eq!(fut.as_mut().poll(&mut cx), Poll::Pending);
+        assert_eq!(fut.as_mut().poll(&mut cx), Poll::Pending);
+        assert_eq!(count.0.load(Ordering::SeqCst), 0);
+
+        // The other thread's round of combining executes the full context.
+        repl.combiner.store(0, Ordering::SeqCst);
+        assert_eq!(repl.execute_mut(0, idx2), Ok(107));
+        assert_eq!(count.0.load(Ordering::SeqCst), 1);
+
+        // Now the operation is enqueued, but another thread combines again.
+        repl.combiner.store(idx2.id(), Ordering::SeqCst);
+        assert_eq!(fut.as_mut().poll(&mut cx), Poll::Pending);
+        repl.combiner.store(0, Ordering::SeqCst);
+        assert_eq!(repl.execute_mut(0, idx2), Ok(107));
+        assert_eq!(count.0.load(Ordering::SeqCst), 2);
+        assert_eq!(fut.as_mut().poll(&mut cx), Poll::Ready(Ok(107)));
+        assert_eq!(repl.execute(0, idx1), Ok(MAX_PENDING_OPS as u64 + 3));
+    }
+
+    // Tests that the combiner only wakes threads that registered a waker, and
+    // forgets them once their waker was used up.
+    #[test]
+    fn test_replica_wake_missed() {
+        use core::future::Future;
+        use core::task::{Context, Poll};
+
+        let slog = Arc::new(Log::<<Data as Dispatch>::WriteOperation>::default());
+        let repl = Replica::<Data>::new(&slog);
+        let idx1 = repl.register().unwrap();
+        let idx2 = repl.register().unwrap();
+        let idx3 = repl.register().unwrap();
+
+        let count = Arc::new(WakeCount::default());
+        let waker = futures::task::waker(count.clone());
+        let mut cx = Context::from_waker(&waker);
+
+        // The operation misses the round of the thread that is combining.
+        repl.async_waiters.store(true, Ordering::SeqCst);
+        repl.combiner.store(idx2.id(), Ordering::SeqCst);
+        let mut fut = std::boxed::Box::pin(repl.execute_mut_future(0, idx1));
+        assert_eq!(fut.as_mut().poll(&mut cx), Poll::Pending);
+        assert_eq!(repl.wakers[0].load(Ordering::SeqCst), 0b1);
+        assert!(repl.make_pending(0, idx3.id()));
+
+        repl.combiner.store(0, Ordering::SeqCst);
+        repl.wake_missed();
+        assert_eq!(count.0.load(Ordering::SeqCst), 1);
+        assert_eq!(repl.wakers[0].load(Ordering::SeqCst), 0);
+        assert_eq!(fut.as_mut().poll(&mut cx), Poll::Ready(Ok(107)));
+        assert_eq!(repl.get_response(idx3.id()), Ok(Ok(107)));
+    }
+
+    // Tests that combiners only look for wakers once a task registered one, and
+    // that a task registering while another thread combines wakes itself to try
+    // again.
+    #[test]
+    fn test_replica_async_waiters() {
+        use core::future::Future;
+        use core::task::{Context, Poll};
+
+        let slog = Arc::new(Log::<<Data as Dispatch>::WriteOperation>::default());
+        let repl = Replica::<Data>::new(&slog);
+        let idx1 = repl.register().unwrap();
+        let idx2 = repl.register().unwrap();
+        assert_eq!(repl.execute_mut(0, idx1), Ok(107));
+        assert!(!repl.async_waiters.load(Ordering::SeqCst));
+
+        let count = Arc::new(WakeCount::default());
+        let waker = futures::task::waker(count.clone());
+        let mut cx = Context::from_waker(&waker);
+
+        repl.combiner.store(idx2.id(), Ordering::SeqCst);
+        let mut fut = std::boxed::Box::pin(repl.execute_mut_future(0, idx1));
+        assert_eq!(fut.as_mut().poll(&mut cx), Poll::Pending);
+        assert!(!repl.async_waiters.load(Ordering::SeqCst));
+        assert_eq!(count.0.load(Ordering::SeqCst), 1);
+
+        repl.combiner.store(0, Ordering::SeqCst);
+        assert_eq!(fut.as_mut().poll(&mut cx), Poll::Ready(Ok(107)));
+        assert!(repl.async_waiters.load(Ordering::SeqCst));
+    }
+
+    // Tests that a read future yields to the executor while the replica is behind
+    // the log and can't catch up itself.
+    #[test]
+    fn test_replica_execute_future() {
+        use core::future::Future;
+        use core::task::{Context, Poll};
+
+        let slog = Arc::new(Log::<<Data as Dispatch>::WriteOperation>::default());
+        let one = Replica::<Data>::new(&slog);
+        let two = Replica::<Data>::new(&slog);
+        let idx1 = one.register().unwrap();
+        let idx2 = two.register().unwrap();
+        assert_eq!(two.execute_mut(0, idx2), Ok(107));
+
+        let count = Arc::new(WakeCount::default());
+        let waker = futures::task::waker(count.clone());
+        let mut cx = Context::from_waker(&waker);
+
+        one.combiner.store(2, Ordering::SeqCst);
+        let mut fut = std::boxed::Box::pin(one.execute_future(0, idx1));
+        assert_eq!(fut.as_mut().poll(&mut cx), Poll::Pending);
+        assert_eq!(count.0.load(Ordering::SeqCst), 1);
+
+        one.combiner.store(0, Ordering::SeqCst);
+        assert_eq!(fut.as_mut().poll(&mut cx), Poll::Ready(Ok(1)));
+    }
+
     #[tokio::test]
     async fn test_box_reuse() {
         use futures::executor::block_on;
@@ -928,4 +3259,145 @@ mod test {
         let res = block_on(resp).unwrap();
         assert_eq!(res, 1);
     }
+
+    // A counter that coalesces consecutive additions, and records the thread
+    // of every operation it executes.
+    #[derive(Default)]
+    struct Counter {
+        value: u64,
+        threads: Vec<usize>,
+    }
+
+    #[derive(Clone, Debug, PartialEq)]
+    enum CounterOp {
+        Add(u64),
+        Set(u64),
+    }
+
+    impl Dispatch for Counter {
+        type ReadOperation = ();
+        type WriteOperation = CounterOp;
+        type Response = u64;
+
+        fn dispatch(&self, _op: Self::ReadOperation) -> Self::Response {
+            self.value
+        }
+
+        fn dispatch_mut(&mut self, op: Self::WriteOperation) -> Self::Response {
+            match op {
+                CounterOp::Add(n) => self.value += n,
+                CounterOp::Set(n) => self.value = n,
+            }
+            self.value
+        }
+
+        fn dispatch_mut_with(
+            &mut self,
+            op: Self::WriteOperation,
+            ctx: &WriteContext,
+        ) -> Self::Response {
+            self.threads.push(ctx.thread);
+            self.dispatch_mut(op)
+        }
+
+        fn coalesce(
+            op: &Self::WriteOperation,
+            next: &Self::WriteOperation,
+        ) -> Option<Self::WriteOperation> {
+            match (op, next) {
+                (CounterOp::Add(a), CounterOp::Add(b)) => Some(CounterOp::Add(a + b)),
+                _ => None,
+            }
+        }
+
+        // An addition returns the value after it, i.e., without the additions
+        // that were merged in after it.
+        fn split_response(
+            ops: &[Self::WriteOperation],
+            i: usize,
+            resp: &Self::Response,
+        ) -> Self::Response {
+            let later: u64 = ops[i + 1..]
+                .iter()
+                .map(|op| match op {
+                    CounterOp::Add(n) => *n,
+                    CounterOp::Set(_) => unreachable!("only additions are merged"),
+                })
+                .sum();
+            resp - later
+        }
+    }
+
+    // Tests that operations are only rearranged once a pair of them merges, and
+    // that the runs before the first merged pair stay on their own.
+    #[test]
+    fn test_coalescing_runs() {
+        let mut coalescing = Coalescing::<Counter>::new();
+        let mut ops = vec![CounterOp::Set(1), CounterOp::Add(2), CounterOp::Set(3)];
+        assert!(!coalescing.coalesce(&mut ops, &[3]));
+        assert!(coalescing.runs.is_empty());
+
+        let mut ops = vec![
+            CounterOp::Set(1),
+            CounterOp::Add(2),
+            CounterOp::Add(3),
+            CounterOp::Add(4),
+            CounterOp::Set(5),
+            CounterOp::Add(6),
+        ];
+        assert!(coalescing.coalesce(&mut ops, &[2, 4]));
+        assert_eq!(
+            ops,
+            vec![
+                CounterOp::Set(1),
+                CounterOp::Add(9),
+                CounterOp::Set(5),
+                CounterOp::Add(6)
+            ]
+        );
+        assert_eq!(coalescing.runs, vec![1, 3, 1, 1]);
+        assert_eq!(coalescing.threads, vec![2, 2]);
+    }
+
+    // Tests that the combiner merges adjacent operations, also across threads,
+    // and that every issuer gets the response of its own operation.
+    #[test]
+    fn test_replica_coalesce() {
+        let slog = Arc::new(Log::<<Counter as Dispatch>::WriteOperation>::default());
+        let repl = Replica::<Counter>::new(&slog);
+        let other = Replica::<Counter>::new(&slog);
+        let idx1 = repl.register().unwrap();
+        let idx2 = repl.register().unwrap();
+
+        for op in [
+            CounterOp::Add(1),
+            CounterOp::Add(2),
+            CounterOp::Set(10),
+            CounterOp::Add(3),
+        ]
+        .iter()
+        {
+            assert!(repl.make_pending(op.clone(), idx1.0));
+        }
+        assert!(repl.make_pending(CounterOp::Add(4), idx2.0));
+        repl.try_combine(idx1.0).unwrap();
+
+        let resps: Vec<_> = (0..4).map(|_i| repl.contexts[0].res().unwrap()).collect();
+        assert_eq!(resps, vec![1, 3, 10, 13]);
+        assert_eq!(repl.contexts[1].res_at(), Some((17, 2)));
+
+        // Three entries made it to the log; the last one is attributed to the
+        // first thread, which issued the first operation of its run.
+        assert_eq!(slog.get_ctail(), 3);
+        let oidx = other.register().unwrap();
+        assert_eq!(other.execute((), oidx), 17);
+        assert_eq!(other.read_with(oidx, |c| c.threads.clone()), vec![1, 1, 1]);
+
+        // Batches without anything to merge are appended as they are.
+        assert_eq!(
+            repl.execute_mut_batch(&[CounterOp::Set(0), CounterOp::Add(5)], idx2),
+            vec![0, 5]
+        );
+        assert_eq!(slog.get_ctail(), 5);
+    }
 }
@@ -22,8 +22,10 @@ use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 
 use crossbeam_utils::CachePadded;
 
-/// Maximum number of reader threads that this lock supports.
-const MAX_READER_THREADS: usize = 192;
+use crate::wait::{Spin, WaitStrategy};
+
+/// Default maximum number of reader threads that this lock supports.
+pub const MAX_READER_THREADS: usize = 192;
 const_assert!(MAX_READER_THREADS > 0);
 
 #[allow(clippy::declare_interior_mutable_const)]
@@ -34,10 +36,11 @@ const RLOCK_DEFAULT: CachePadded<AtomicUsize> = CachePadded::new(AtomicUsize::ne
 /// This lock favours reader performance over writers. Each reader thread gets
 /// its own "lock" while writers share a single lock.
 ///
-/// `T` represents the underlying type protected by the lock.
+/// `T` represents the underlying type protected by the lock and `MAX_READERS`
+/// the number of reader threads that can use the lock.
 /// Calling `read()` returns a read-guard that can be used to safely read `T`.
 /// Calling `write()` returns a write-guard that can be used to safely mutate `T`.
-pub struct RwLock<T>
+pub struct RwLock<T, const MAX_READERS: usize = MAX_READER_THREADS>
 where
     T: Sized + Sync,
 {
@@ -45,7 +48,7 @@ where
     wlock: CachePadded<AtomicBool>,
 
     /// Each reader use an individual lock to access the underlying data-structure.
-    rlock: [CachePadded<AtomicUsize>; MAX_READER_THREADS],
+    rlock: [CachePadded<AtomicUsize>; MAX_READERS],
 
     /// The underlying data-structure.
     data: UnsafeCell<T>,
@@ -53,47 +56,47 @@ where
 
 /// A read-guard that can be used to read the underlying data structure. Writes on
 /// the data structure will be blocked as long as one of these is lying around.
-pub struct ReadGuard<'a, T: Sized + Sync + 'a> {
+pub struct ReadGuard<'a, T: Sized + Sync + 'a, const MAX_READERS: usize = MAX_READER_THREADS> {
     /// Id of the thread that acquired this guard. Required at drop time so that
     /// we can release the appropriate read lock.
     tid: usize,
 
     /// A reference to the Rwlock wrapping the data-structure.
-    lock: &'a RwLock<T>,
+    lock: &'a RwLock<T, MAX_READERS>,
 }
 
 /// A write-guard that can be used to write to the underlying data structure. All
 /// reads will be blocked until this is dropped.
-pub struct WriteGuard<'a, T: Sized + Sync + 'a> {
+pub struct WriteGuard<'a, T: Sized + Sync + 'a, const MAX_READERS: usize = MAX_READER_THREADS> {
     /// A reference to the Rwlock wrapping the data-structure.
-    lock: &'a RwLock<T>,
+    lock: &'a RwLock<T, MAX_READERS>,
 }
 
-impl<T> Default for RwLock<T>
+impl<T, const MAX_READERS: usize> Default for RwLock<T, MAX_READERS>
 where
     T: Sized + Default + Sync,
 {
     /// Returns a new instance of a RwLock. Default constructs the
     /// underlying data structure.
-    fn default() -> RwLock<T> {
-        RwLock {
-            wlock: CachePadded::new(AtomicBool::new(false)),
-            rlock: [RLOCK_DEFAULT; MAX_READER_THREADS],
-            data: UnsafeCell::new(T::default()),
-        }
+    fn default() -> RwLock<T, MAX_READERS> {
+        RwLock::new(T::default())
     }
 }
 
-impl<T> RwLock<T>
+impl<T, const MAX_READERS: usize> RwLock<T, MAX_READERS>
 where
     T: Sized + Sync,
 {
     /// Returns a new instance of a RwLock. Default constructs the
     /// underlying data structure.
     pub fn new(t: T) -> Self {
+        assert!(
+            MAX_READERS > 0,
+            "The lock must support at least one reader."
+        );
         Self {
             wlock: CachePadded::new(AtomicBool::new(false)),
-            rlock: [RLOCK_DEFAULT; MAX_READER_THREADS],
+            rlock: [RLOCK_DEFAULT; MAX_READERS],
             data: UnsafeCell::new(t),
         }
     }
@@ -118,8 +121,15 @@ where
     ///     let mut w_guard = lock.write(N_CONCURRENT_READERS);
     ///     *w_guard = 777;
     /// ```
-    pub fn write(&self, n: usize) -> WriteGuard<T> {
+    pub fn write(&self, n: usize) -> WriteGuard<T, MAX_READERS> {
+        self.write_with(n, &Spin)
+    }
+
+    /// Same as `write`, but waits for the writer lock and for readers according
+    /// to `wait` instead of busy spinning.
+    pub fn write_with(&self, n: usize, wait: &dyn WaitStrategy) -> WriteGuard<T, MAX_READERS> {
         // First, wait until we can acquire the writer lock.
+        let mut iteration = 1;
         loop {
             match self.wlock.compare_exchange_weak(
                 false,
@@ -128,19 +138,24 @@ where
                 Ordering::Acquire,
             ) {
                 Ok(_) => break,
-                Err(_) => continue,
+                Err(_) => {
+                    wait.relax(iteration);
+                    iteration += 1;
+                }
             }
         }
 
         // Next, wait until all readers have released their locks. This condition
         // evaluates to true if each reader lock is free (i.e equal to zero).
+        let mut iteration = 1;
         while !self
             .rlock
             .iter()
             .take(n)
             .all(|item| item.load(Ordering::Relaxed) == 0)
         {
-            spin_loop();
+            wait.relax(iteration);
+            iteration += 1;
         }
 
         unsafe { WriteGuard::new(self) }
@@ -163,7 +178,7 @@ where
     ///     const MY_THREAD_ID: usize = 16;
     ///     let r_guard = lock.read(MY_THREAD_ID);
     ///     assert_eq!(0, *r_guard);
-    pub fn read(&self, tid: usize) -> ReadGuard<T> {
+    pub fn read(&self, tid: usize) -> ReadGuard<T, MAX_READERS> {
         // We perform a small optimization. Before attempting to acquire a read lock, we issue
         // naked reads to the write lock and wait until it is free. For that, we retrieve a
         // raw pointer to the write lock over here.
@@ -215,16 +230,19 @@ where
     }
 }
 
-impl<'rwlock, T: Sized + Sync> ReadGuard<'rwlock, T> {
+impl<'rwlock, T: Sized + Sync, const MAX_READERS: usize> ReadGuard<'rwlock, T, MAX_READERS> {
     /// Returns a read guard over a passed in reader-writer lock.
-    unsafe fn new(lock: &'rwlock RwLock<T>, tid: usize) -> ReadGuard<'rwlock, T> {
+    unsafe fn new(
+        lock: &'rwlock RwLock<T, MAX_READERS>,
+        tid: usize,
+    ) -> ReadGuard<'rwlock, T, MAX_READERS> {
         ReadGuard { tid, lock }
     }
 }
 
-impl<'rwlock, T: Sized + Sync> WriteGuard<'rwlock, T> {
+impl<'rwlock, T: Sized + Sync, const MAX_READERS: usize> WriteGuard<'rwlock, T, MAX_READERS> {
     /// Returns a write guard over a passed in reader-writer lock.
-    unsafe fn new(lock: &'rwlock RwLock<T>) -> WriteGuard<'rwlock, T> {
+    unsafe fn new(lock: &'rwlock RwLock<T, MAX_READERS>) -> WriteGuard<'rwlock, T, MAX_READERS> {
         WriteGuard { lock }
     }
 }
@@ -232,11 +250,11 @@ impl<'rwlock, T: Sized + Sync> WriteGuard<'rwlock, T> {
 /// `Sync` trait allows `RwLock` to be shared between threads. The `read()` and
 /// `write()` logic ensures that we will never have threads writing to and
 /// reading from the underlying data structure simultaneously.
-unsafe impl<T: Sized + Sync> Sync for RwLock<T> {}
+unsafe impl<T: Sized + Sync, const MAX_READERS: usize> Sync for RwLock<T, MAX_READERS> {}
 
 /// This `Deref` trait allows a thread to use T from a ReadGuard.
 /// ReadGuard can only be dereferenced into an immutable reference.
-impl<T: Sized + Sync> Deref for ReadGuard<'_, T> {
+impl<T: Sized + Sync, const MAX_READERS: usize> Deref for ReadGuard<'_, T, MAX_READERS> {
     type Target = T;
 
     fn deref(&self) -> &T {
@@ -246,7 +264,7 @@ impl<T: Sized + Sync> Deref for ReadGuard<'_, T> {
 
 /// This `Deref` trait allows a thread to use T from a WriteGuard.
 /// This allows us to dereference an immutable reference.
-impl<T: Sized + Sync> Deref for WriteGuard<'_, T> {
+impl<T: Sized + Sync, const MAX_READERS: usize> Deref for WriteGuard<'_, T, MAX_READERS> {
     type Target = T;
 
     fn deref(&self) -> &T {
@@ -256,7 +274,7 @@ impl<T: Sized + Sync> Deref for WriteGuard<'_, T> {
 
 /// This `DerefMut` trait allow a thread to use T from a WriteGuard.
 /// This allows us to dereference a mutable reference.
-impl<T: Sized + Sync> DerefMut for WriteGuard<'_, T> {
+impl<T: Sized + Sync, const MAX_READERS: usize> DerefMut for WriteGuard<'_, T, MAX_READERS> {
     fn deref_mut(&mut self) -> &mut T {
         unsafe { &mut *self.lock.data.get() }
     }
@@ -264,7 +282,7 @@ impl<T: Sized + Sync> DerefMut for WriteGuard<'_, T> {
 
 /// This `Drop` trait implements the unlock logic for a reader lock. Once the `ReadGuard`
 /// goes out of scope, the corresponding read lock is marked as released.
-impl<T: Sized + Sync> Drop for ReadGuard<'_, T> {
+impl<T: Sized + Sync, const MAX_READERS: usize> Drop for ReadGuard<'_, T, MAX_READERS> {
     fn drop(&mut self) {
         unsafe {
             let tid = self.tid;
@@ -275,7 +293,7 @@ impl<T: Sized + Sync> Drop for ReadGuard<'_, T> {
 
 /// This `Drop` trait implements the unlock logic for a writer lock. Once the `WriteGuard`
 /// goes out of scope, the corresponding write lock is marked as released.
-impl<T: Sized + Sync> Drop for WriteGuard<'_, T> {
+impl<T: Sized + Sync, const MAX_READERS: usize> Drop for WriteGuard<'_, T, MAX_READERS> {
     fn drop(&mut self) {
         unsafe {
             self.lock.write_unlock();
@@ -303,6 +321,21 @@ mod tests {
         assert_eq!(unsafe { *lock.data.get() }, usize::default());
     }
 
+    // Tests if a lock can be created for a number of readers other than the default.
+    #[test]
+    fn test_rwlock_max_readers() {
+        let lock = RwLock::<usize, 256>::default();
+        assert_eq!(lock.rlock.len(), 256);
+
+        {
+            let _r = lock.read(255);
+            assert_eq!(lock.rlock[255].load(Ordering::Relaxed), 1);
+        }
+
+        *lock.write(256) = 7;
+        assert_eq!(*lock.read(0), 7);
+    }
+
     // Tests if the mutable reference returned on acquiring a write lock
     // can be used to write to the underlying data structure.
     #[test]
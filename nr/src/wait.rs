@@ -0,0 +1,383 @@
+// Copyright © 2019-2020 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Strategies for threads that wait on the shared log or a replica.
+//!
+//! By default, a thread that waits (for its response, for garbage collection
+//! on the log, for a replica to catch up with the log, or for the replica's
+//! writer lock) busy spins. That's the fastest option with one thread per core,
+//! but wastes CPU time when cores are oversubscribed or the process runs under
+//! a CPU quota. A replica constructed with
+//! [`Replica::with_wait_strategy`](../struct.Replica.html#method.with_wait_strategy)
+//! waits according to the given [WaitStrategy](trait.WaitStrategy.html) instead.
+//!
+//! With the `std` feature, [SpinThenYield](struct.SpinThenYield.html) and
+//! [SpinThenPark](struct.SpinThenPark.html) give up the CPU after spinning for a
+//! while. A thread waiting for the response to one of its operations can be
+//! parked, in which case the combiner unparks it once the response is ready.
+
+#[cfg(feature = "std")]
+use core::time::Duration;
+
+use core::cell::UnsafeCell;
+use core::hint::spin_loop;
+#[cfg(not(loom))]
+use core::sync::atomic::{fence, AtomicBool, AtomicUsize, Ordering};
+use core::task::Waker;
+#[cfg(loom)]
+use loom::sync::atomic::{fence, AtomicBool, AtomicUsize, Ordering};
+
+#[cfg(feature = "std")]
+use std::sync::Mutex;
+#[cfg(feature = "std")]
+use std::thread::{self, Thread};
+
+/// Decides what a thread does while it waits for a condition to become true.
+///
+/// Callers check the condition, and invoke one of the methods every time they
+/// find that it doesn't hold yet. `iteration` is the number of times the
+/// condition was checked so far (starting at one).
+pub trait WaitStrategy: Send + Sync {
+    /// Waits once for a condition no other thread signals; for example, for
+    /// another replica to make progress on the shared log.
+    fn relax(&self, iteration: usize);
+
+    /// Waits once for a response to be enqueued into a thread's context. The
+    /// combiner calls `unpark` on `parker` after it enqueued the response.
+    ///
+    /// Returns true if the thread gave up the CPU. The caller then tries to
+    /// become the combiner itself, in case no other thread picked up its
+    /// operation in the meantime.
+    fn block(&self, iteration: usize, _parker: &Parker) -> bool {
+        self.relax(iteration);
+        false
+    }
+}
+
+/// Busy spins; the default.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Spin;
+
+impl WaitStrategy for Spin {
+    #[inline(always)]
+    fn relax(&self, _iteration: usize) {
+        spin_loop();
+    }
+
+    /// Polls for the response as fast as possible.
+    #[inline(always)]
+    fn block(&self, _iteration: usize, _parker: &Parker) -> bool {
+        false
+    }
+}
+
+/// Busy spins for a number of iterations, then yields the CPU to other threads
+/// every time it waits.
+#[cfg(feature = "std")]
+#[derive(Clone, Copy, Debug)]
+pub struct SpinThenYield {
+    /// Number of iterations to spin before yielding.
+    spins: usize,
+}
+
+#[cfg(feature = "std")]
+impl SpinThenYield {
+    /// Creates a strategy that starts yielding after `spins` iterations.
+    pub fn new(spins: usize) -> SpinThenYield {
+        SpinThenYield { spins }
+    }
+}
+
+#[cfg(feature = "std")]
+impl Default for SpinThenYield {
+    fn default() -> SpinThenYield {
+        SpinThenYield::new(1 << 10)
+    }
+}
+
+#[cfg(feature = "std")]
+impl WaitStrategy for SpinThenYield {
+    fn relax(&self, iteration: usize) {
+        if iteration < self.spins {
+            spin_loop();
+        } else {
+            thread::yield_now();
+        }
+    }
+}
+
+/// Busy spins for a number of iterations and then parks a thread waiting for a
+/// response until the combiner wakes it up, or until `timeout` expires. Waits
+/// that can't be woken up yield instead.
+#[cfg(feature = "std")]
+#[derive(Clone, Copy, Debug)]
+pub struct SpinThenPark {
+    /// Number of iterations to spin before yielding or parking.
+    spins: usize,
+
+    /// Maximum time a thread stays parked before it checks for its response
+    /// (and tries to become the combiner) again.
+    timeout: Duration,
+}
+
+#[cfg(feature = "std")]
+impl SpinThenPark {
+    /// Creates a strategy that parks after `spins` iterations, for at most
+    /// `timeout` at a time.
+    pub fn new(spins: usize, timeout: Duration) -> SpinThenPark {
+        SpinThenPark { spins, timeout }
+    }
+}
+
+#[cfg(feature = "std")]
+impl Default for SpinThenPark {
+    fn default() -> SpinThenPark {
+        SpinThenPark::new(1 << 10, Duration::from_millis(1))
+    }
+}
+
+#[cfg(feature = "std")]
+impl WaitStrategy for SpinThenPark {
+    fn relax(&self, iteration: usize) {
+        if iteration < self.spins {
+            spin_loop();
+        } else {
+            thread::yield_now();
+        }
+    }
+
+    fn block(&self, iteration: usize, parker: &Parker) -> bool {
+        if iteration < self.spins {
+            spin_loop();
+            false
+        } else {
+            parker.park_timeout(self.timeout);
+            true
+        }
+    }
+}
+
+/// The owning thread is running, and there is no notification pending.
+#[cfg(feature = "std")]
+const EMPTY: usize = 0;
+/// The owning thread is (about to be) parked.
+const PARKED: usize = 1;
+/// `unpark` was called while the owning thread wasn't parked.
+const NOTIFIED: usize = 2;
+
+/// Lets the combiner wake up the thread owning a context.
+///
+/// Like `std::thread::park`, a call to `unpark` that happens while the thread is
+/// not parked is not lost: the next `park_timeout` returns immediately. So a
+/// thread that checks for its response and then parks can't miss the combiner
+/// enqueueing the response in between.
+///
+/// Instead of parking, an asynchronous task can `register` its waker, which the
+/// next `unpark` wakes. A task must check for its response after registering.
+#[derive(Debug, Default)]
+pub struct Parker {
+    /// One of `EMPTY`, `PARKED` or `NOTIFIED`.
+    state: AtomicUsize,
+
+    /// The thread that parks on this parker.
+    #[cfg(feature = "std")]
+    thread: Mutex<Option<Thread>>,
+
+    /// Whether `waker` holds a waker, so that `unpark` doesn't have to take
+    /// `waker_lock` otherwise.
+    has_waker: AtomicBool,
+
+    /// Spin lock protecting `waker`; held only to swap the waker in or out.
+    waker_lock: AtomicBool,
+
+    /// The waker of the task that waits on this parker, if any.
+    waker: UnsafeCell<Option<Waker>>,
+}
+
+/// `waker` is only accessed with `waker_lock` held.
+unsafe impl Sync for Parker {}
+
+impl Parker {
+    /// Wakes up the owning thread if it is parked, or makes sure its next
+    /// attempt to park returns immediately.
+    #[inline(always)]
+    pub fn unpark(&self) {
+        self.unpark_thread();
+        self.wake_task();
+    }
+
+    /// Like `unpark`, but leaves the waker registered with `register` alone.
+    #[inline(always)]
+    pub(crate) fn unpark_thread(&self) {
+        // Threads that never park leave the notification in place, so this is
+        // usually just a load.
+        if self.state.load(Ordering::SeqCst) != NOTIFIED
+            && self.state.swap(NOTIFIED, Ordering::SeqCst) == PARKED
+        {
+            #[cfg(feature = "std")]
+            if let Some(t) = self.thread.lock().unwrap().as_ref() {
+                t.unpark();
+            }
+        }
+    }
+
+    /// Wakes the waker registered with `register`, if any. Unlike threads that
+    /// park, tasks aren't woken by `unpark_thread`.
+    #[inline(always)]
+    pub(crate) fn wake_task(&self) {
+        // Pairs with the fence in `register`: either the task sees what was
+        // published before this call, or we see its waker.
+        fence(Ordering::SeqCst);
+        if self.has_waker.load(Ordering::Relaxed) {
+            if let Some(waker) = self.with_waker(|w| w.take()) {
+                waker.wake();
+            }
+        }
+    }
+
+    /// Makes the next call to `unpark` wake `waker`, replacing the waker that
+    /// was registered before (if any).
+    pub fn register(&self, waker: &Waker) {
+        self.with_waker(|w| match w {
+            Some(old) if old.will_wake(waker) => {}
+            _ => *w = Some(waker.clone()),
+        });
+        fence(Ordering::SeqCst);
+    }
+
+    /// Returns true if a waker is registered that no `unpark` woke yet.
+    pub fn has_waker(&self) -> bool {
+        self.has_waker.load(Ordering::SeqCst)
+    }
+
+    /// Invokes `f` on the registered waker with `waker_lock` held.
+    fn with_waker<R, F: FnOnce(&mut Option<Waker>) -> R>(&self, f: F) -> R {
+        while self
+            .waker_lock
+            .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            spin_loop();
+        }
+
+        // Safety: `waker_lock` is held, so nobody else accesses `waker`.
+        let waker = unsafe { &mut *self.waker.get() };
+        let r = f(waker);
+        self.has_waker.store(waker.is_some(), Ordering::Relaxed);
+
+        self.waker_lock.store(false, Ordering::Release);
+        r
+    }
+
+    /// Parks the calling thread until `unpark` is called or `timeout` expires.
+    /// May also return spuriously.
+    #[cfg(feature = "std")]
+    pub fn park_timeout(&self, timeout: Duration) {
+        if self
+            .state
+            .compare_exchange(NOTIFIED, EMPTY, Ordering::SeqCst, Ordering::SeqCst)
+            .is_ok()
+        {
+            return;
+        }
+
+        {
+            let mut t = self.thread.lock().unwrap();
+            if t.is_none() {
+                *t = Some(thread::current());
+            }
+        }
+
+        if self
+            .state
+            .compare_exchange(EMPTY, PARKED, Ordering::SeqCst, Ordering::SeqCst)
+            .is_ok()
+        {
+            thread::park_timeout(timeout);
+        }
+        self.state.store(EMPTY, Ordering::SeqCst);
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+
+    // Tests that a parked thread is woken up before its timeout expires.
+    #[test]
+    fn test_parker_unpark() {
+        use std::sync::Arc;
+        use std::time::Instant;
+
+        let parker = Arc::new(Parker::default());
+        let p = parker.clone();
+        let start = Instant::now();
+        let waiter = thread::spawn(move || p.park_timeout(Duration::from_secs(60)));
+
+        while parker.state.load(Ordering::SeqCst) != PARKED {
+            thread::yield_now();
+        }
+        parker.unpark();
+        waiter.join().unwrap();
+
+        assert!(start.elapsed() < Duration::from_secs(60));
+        assert_eq!(parker.state.load(Ordering::SeqCst), EMPTY);
+    }
+
+    // Tests that parking gives up after the timeout if nobody unparks, and that
+    // an unpark before parking isn't lost.
+    #[test]
+    fn test_parker_timeout() {
+        use std::time::Instant;
+
+        let parker = Parker::default();
+        parker.park_timeout(Duration::from_millis(1));
+        assert_eq!(parker.state.load(Ordering::SeqCst), EMPTY);
+
+        parker.unpark();
+        let start = Instant::now();
+        parker.park_timeout(Duration::from_secs(60));
+        assert!(start.elapsed() < Duration::from_secs(60));
+        assert_eq!(parker.state.load(Ordering::SeqCst), EMPTY);
+    }
+
+    // Tests that unpark wakes a registered waker exactly once.
+    #[test]
+    fn test_parker_waker() {
+        use futures::task::{waker, ArcWake};
+        use std::sync::atomic::AtomicUsize;
+        use std::sync::Arc;
+
+        #[derive(Default)]
+        struct Count(AtomicUsize);
+
+        impl ArcWake for Count {
+            fn wake_by_ref(arc_self: &Arc<Self>) {
+                arc_self.0.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+
+        let count = Arc::new(Count::default());
+        let parker = Parker::default();
+        parker.unpark();
+        assert!(!parker.has_waker());
+
+        parker.register(&waker(count.clone()));
+        parker.register(&waker(count.clone()));
+        assert!(parker.has_waker());
+        parker.unpark();
+        parker.unpark();
+        assert!(!parker.has_waker());
+        assert_eq!(count.0.load(Ordering::SeqCst), 1);
+    }
+
+    // Tests that the strategies only give up the CPU after spinning.
+    #[test]
+    fn test_spin_then_park_block() {
+        let parker = Parker::default();
+        let w = SpinThenPark::new(2, Duration::from_millis(1));
+        assert!(!w.block(1, &parker));
+        assert!(w.block(2, &parker));
+        assert!(!Spin.block(1 << 20, &parker));
+    }
+}
@@ -0,0 +1,149 @@
+// Copyright © 2019-2020 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Tests that a persisted log can be recovered into replicas after a restart.
+
+extern crate std;
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Barrier};
+use std::thread;
+
+use node_replication::persist::{self, FsyncPolicy, Persistent};
+use node_replication::Dispatch;
+use node_replication::Log;
+use node_replication::Replica;
+
+#[derive(Eq, PartialEq, Clone, Copy, Debug)]
+enum OpWr {
+    Push(u32),
+    Pop,
+}
+
+impl Persistent for OpWr {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        match self {
+            OpWr::Push(v) => {
+                buf.push(0);
+                v.encode(buf);
+            }
+            OpWr::Pop => buf.push(1),
+        }
+    }
+
+    fn decode(buf: &[u8]) -> Option<Self> {
+        match buf.split_first()? {
+            (0, v) => Some(OpWr::Push(u32::decode(v)?)),
+            (1, []) => Some(OpWr::Pop),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Default)]
+struct Stack {
+    storage: Vec<u32>,
+}
+
+impl Dispatch for Stack {
+    type ReadOperation = ();
+    type WriteOperation = OpWr;
+    type Response = Option<u32>;
+
+    fn dispatch(&self, _op: Self::ReadOperation) -> Self::Response {
+        self.storage.last().cloned()
+    }
+
+    fn dispatch_mut(&mut self, op: Self::WriteOperation) -> Self::Response {
+        match op {
+            OpWr::Push(v) => {
+                self.storage.push(v);
+                None
+            }
+            OpWr::Pop => self.storage.pop(),
+        }
+    }
+}
+
+/// Runs `nthreads` threads on each replica that push and pop on the stack.
+fn run(replicas: &[Arc<Replica<'static, Stack>>], nthreads: usize, nop: usize) {
+    let total = replicas.len() * nthreads;
+    let barrier = Arc::new(Barrier::new(total));
+    let finished = Arc::new(AtomicUsize::new(0));
+    let mut threads = Vec::new();
+
+    for replica in replicas {
+        for t in 0..nthreads {
+            let r = replica.clone();
+            let b = barrier.clone();
+            let f = finished.clone();
+            threads.push(thread::spawn(move || {
+                let idx = r.register().expect("Failed to register with replica.");
+                b.wait();
+                for i in 0..nop {
+                    if i % 3 == 2 {
+                        r.execute_mut(OpWr::Pop, idx);
+                    } else {
+                        r.execute_mut(OpWr::Push((t * nop + i) as u32), idx);
+                    }
+                }
+
+                // Keep consuming the log until every thread is done, otherwise
+                // the other replicas can't garbage collect the log.
+                f.fetch_add(1, Ordering::SeqCst);
+                while f.load(Ordering::SeqCst) < total {
+                    r.sync(idx);
+                }
+            }));
+        }
+    }
+
+    for t in threads {
+        t.join().unwrap();
+    }
+}
+
+/// Returns the contents of the stack of each replica.
+fn contents(replicas: &[Arc<Replica<'static, Stack>>]) -> Vec<Vec<u32>> {
+    let mut stacks = Vec::new();
+    for r in replicas {
+        r.verify(|s| stacks.push(s.storage.clone()));
+    }
+    stacks
+}
+
+/// Tests that replicas recovered from a segment end up with the same state as the
+/// replicas that were writing to it, even when the log wrapped around in between.
+#[test]
+fn recover_concurrent_appends() {
+    let path = std::env::temp_dir().join(format!("nr-persistence-{}", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+
+    let before = {
+        let log = Arc::new(
+            Log::<OpWr>::with_segment(1024 * 1024, &path, FsyncPolicy::Every(4096)).unwrap(),
+        );
+        let replicas = vec![Replica::<Stack>::new(&log), Replica::<Stack>::new(&log)];
+        run(&replicas, 2, 5_000);
+        contents(&replicas)
+    };
+    assert_eq!(before[0], before[1]);
+
+    let (_log, replicas) =
+        persist::recover::<Stack, _>(1024 * 1024, &path, FsyncPolicy::Never, 3).unwrap();
+    let after = contents(&replicas);
+    for stack in after.iter() {
+        assert_eq!(stack, &before[0]);
+    }
+
+    // The recovered log keeps appending to the same segment.
+    run(&replicas, 2, 1_000);
+    let before = contents(&replicas);
+    drop(replicas);
+
+    let (_log, replicas) =
+        persist::recover::<Stack, _>(1024 * 1024, &path, FsyncPolicy::Never, 1).unwrap();
+    assert_eq!(contents(&replicas)[0], before[0]);
+
+    std::fs::remove_file(&path).unwrap();
+}